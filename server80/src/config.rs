@@ -0,0 +1,314 @@
+//! Command line and config file.
+//!
+//! Config file is TOML, every key is optional:
+//!
+//! ```toml
+//! listen = ["[::]:80", "127.0.0.1:8080"]
+//! root = "/srv/www"
+//! workers = 4
+//! # more clients wait until some connection closes
+//! max_connections = 1024
+//! listings = true
+//! # enables PUT and DELETE with `Authorization: Bearer <token>`
+//! write_token = "secret"
+//! # bytes of PUT body, it goes straight to disk
+//! max_body = 16777216
+//!
+//! [timeouts]  # seconds
+//! idle = 15   # waiting for the next request on kept-alive connection
+//! read = 30   # waiting for the rest of started request
+//! write = 30  # waiting for client to take more of the reply
+//! drain = 30  # waiting for connections to finish on shutdown
+//!
+//! [mime]
+//! gmi = "text/gemini"
+//!
+//! [[vhost]]
+//! hosts = ["example.org", "www.example.org"]
+//! root = "/srv/example"
+//! ```
+//!
+//! Command line options take precedence over the file.
+
+use clap::Parser;
+use serde::Deserialize;
+use snafu::{ResultExt, Whatever, whatever};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::{Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+#[derive(Debug, Parser)]
+#[command(about = "Static file server")]
+pub(crate) struct Args {
+    /// Config file, TOML
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, like `[::]:8080`, may be repeated
+    #[arg(short, long)]
+    pub listen: Vec<SocketAddr>,
+    /// Directory with files to serve, unless Host has its own
+    #[arg(short, long)]
+    pub root: Option<PathBuf>,
+    /// Number of threads serving connections
+    #[arg(short, long)]
+    pub workers: Option<usize>,
+    /// Number of connections served at once
+    #[arg(short, long)]
+    pub max_connections: Option<usize>,
+    /// Check config and exit
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub root: PathBuf,
+    pub workers: usize,
+    pub max_connections: usize,
+    /// List directories without index.html, instead of replying 403
+    pub listings: bool,
+    /// Bearer token for PUT and DELETE, which are disabled without it
+    pub write_token: Option<String>,
+    /// Largest request body accepted, in bytes
+    pub max_body: usize,
+    pub timeouts: Timeouts,
+    /// Extension to media type, over builtin ones
+    pub mime: BTreeMap<String, String>,
+    #[serde(rename = "vhost")]
+    pub vhosts: Vec<Vhost>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::from(([0u16; 8], 8080))],
+            root: PathBuf::from("./"),
+            workers: std::thread::available_parallelism().map_or(1, usize::from),
+            max_connections: 1024,
+            listings: true,
+            write_token: None,
+            max_body: 16 << 20,
+            timeouts: Timeouts::default(),
+            mime: BTreeMap::new(),
+            vhosts: Vec::new(),
+        }
+    }
+}
+
+/// Timeouts in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Timeouts {
+    pub idle: u64,
+    pub read: u64,
+    pub write: u64,
+    pub drain: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            idle: 15,
+            read: 30,
+            write: 30,
+            drain: 30,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle)
+    }
+
+    pub fn read(&self) -> Duration {
+        Duration::from_secs(self.read)
+    }
+
+    pub fn write(&self) -> Duration {
+        Duration::from_secs(self.write)
+    }
+
+    pub fn drain(&self) -> Duration {
+        Duration::from_secs(self.drain)
+    }
+}
+
+/// Virtual host, serving its own root to requests with matching Host
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Vhost {
+    /// Host names, without port
+    pub hosts: Vec<String>,
+    pub root: PathBuf,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, Whatever> {
+        toml::from_str(text).whatever_context("parsing config")
+    }
+
+    /// Reads config file if there is one, applies command line over it and validates it
+    pub fn from_args(args: &Args) -> Result<Self, Whatever> {
+        let mut config = match args.config {
+            Some(ref path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_whatever_context(|_| format!("reading {}", path.display()))?;
+                Self::parse(&text)
+                    .with_whatever_context(|_| format!("loading {}", path.display()))?
+            }
+            None => Self::default(),
+        };
+        if !args.listen.is_empty() {
+            config.listen = args.listen.clone();
+        }
+        if let Some(ref root) = args.root {
+            config.root = root.clone();
+        }
+        if let Some(workers) = args.workers {
+            config.workers = workers;
+        }
+        if let Some(max) = args.max_connections {
+            config.max_connections = max;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), Whatever> {
+        if self.listen.is_empty() {
+            whatever!("nothing to listen on");
+        }
+        if self.workers == 0 {
+            whatever!("at least one worker is needed");
+        }
+        if self.max_connections == 0 {
+            whatever!("at least one connection has to be allowed");
+        }
+        // no drain timeout means connections are cut off right away
+        let Timeouts {
+            idle, read, write, ..
+        } = self.timeouts;
+        if idle == 0 || read == 0 || write == 0 {
+            whatever!("timeouts have to be at least a second");
+        }
+        for (ext, t) in &self.mime {
+            if !t.contains('/') {
+                whatever!("media type {t:?} of {ext:?} is not like type/subtype");
+            }
+        }
+
+        for root in std::iter::once(&self.root).chain(self.vhosts.iter().map(|v| &v.root)) {
+            if !root.is_dir() {
+                whatever!("root {} is not a directory", root.display());
+            }
+        }
+        let mut hosts = HashSet::new();
+        for host in self.vhosts.iter().flat_map(|v| &v.hosts) {
+            // IPv6 address goes in brackets, like in Host header
+            let valid = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+                Some(addr) => addr.parse::<Ipv6Addr>().is_ok(),
+                None => !host_key(host).is_empty() && !host.contains(['/', ':', '[', ']']),
+            };
+            if !valid {
+                whatever!("bad host name {host:?}, it has to be without port");
+            }
+            if !hosts.insert(host_key(host)) {
+                whatever!("host {host} has several roots");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Host name as virtual hosts are looked up by, lowercase
+/// and without the trailing dot of fully qualified name
+pub(crate) fn host_key(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configuring() {
+        let root = std::env::temp_dir();
+        let text = format!(
+            "listen = [\"127.0.0.1:8080\", \"[::1]:80\"]\n\
+             workers = 2\n\
+             max_body = 1024\n\
+             [timeouts]\n\
+             idle = 5\n\
+             [mime]\n\
+             gmi = \"text/gemini\"\n\
+             [[vhost]]\n\
+             hosts = [\"example.org\"]\n\
+             root = {:?}\n",
+            root
+        );
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(
+            config.listen[1],
+            SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 80))
+        );
+        assert_eq!((config.workers, config.max_body), (2, 1024));
+        assert_eq!(
+            config.timeouts,
+            Timeouts {
+                idle: 5,
+                ..Timeouts::default()
+            }
+        );
+        assert_eq!(config.vhosts[0].root, root);
+        assert!(config.listings);
+        assert!(config.validate().is_ok());
+
+        let args = Args::parse_from(["server80", "-l", "0.0.0.0:81", "--workers", "3"]);
+        let config = Config::from_args(&args).unwrap();
+        assert_eq!(config.listen, [SocketAddr::from(([0, 0, 0, 0], 81))]);
+        assert_eq!((config.workers, config.root), (3, PathBuf::from("./")));
+
+        let e = Config::parse("listen = [\"localhost\"]").unwrap_err();
+        assert_eq!(e.to_string(), "parsing config");
+        assert!(Config::parse("port = 80").is_err());
+        let invalid = |text: &str| {
+            Config::parse(text)
+                .unwrap()
+                .validate()
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(invalid("workers = 0"), "at least one worker is needed");
+        assert_eq!(
+            invalid("max_connections = 0"),
+            "at least one connection has to be allowed"
+        );
+        assert_eq!(
+            invalid("[timeouts]\nread = 0"),
+            "timeouts have to be at least a second"
+        );
+        assert_eq!(
+            invalid("root = \"/nonexistent\""),
+            "root /nonexistent is not a directory"
+        );
+        let dup =
+            "[[vhost]]\nhosts = [\"a\"]\nroot = \"/\"\n[[vhost]]\nhosts = [\"A\"]\nroot = \"/\"";
+        assert_eq!(invalid(dup), "host A has several roots");
+        let host = |h: &str| format!("[[vhost]]\nhosts = [{h:?}]\nroot = \"/\"\n");
+        for bad in ["a:80", "[::1]:80", "[a]", "[::1", "", "."] {
+            assert!(invalid(&host(bad)).starts_with("bad host name"), "{bad}");
+        }
+        let hosts = [host("[::1]"), host("example.org.")];
+        let config = Config::parse(&hosts.concat()).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(host_key("Example.ORG."), "example.org");
+        let dup = [host("example.org."), host("EXAMPLE.org")].concat();
+        assert_eq!(invalid(&dup), "host EXAMPLE.org has several roots");
+    }
+}
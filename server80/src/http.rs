@@ -1,31 +1,101 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum Method {
     Get(Box<str>),
+    Head(Box<str>),
+    Options(Box<str>),
+    Put(Box<str>),
+    Delete(Box<str>),
+    /// Any other method, with its name and target
+    Other(Box<str>, Box<str>),
+}
+
+impl Method {
+    pub fn target(&self) -> &str {
+        match self {
+            Method::Get(t)
+            | Method::Head(t)
+            | Method::Options(t)
+            | Method::Put(t)
+            | Method::Delete(t)
+            | Method::Other(_, t) => t,
+        }
+    }
+}
+
+/// Formats method as in request line, like `GET /index.html`
+impl Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Method::Get(_) => "GET",
+            Method::Head(_) => "HEAD",
+            Method::Options(_) => "OPTIONS",
+            Method::Put(_) => "PUT",
+            Method::Delete(_) => "DELETE",
+            Method::Other(name, _) => name,
+        };
+        write!(f, "{name} {}", self.target())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Version {
+    Http10,
+    Http11,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum Code {
     Unknown = 0,
     Ok = 200,
+    Created = 201,
+    NoContent = 204,
+    PartialContent = 206,
+    MovedPermanently = 301,
+    NotModified = 304,
     BadRequest = 400,
+    Unauthorized = 401,
     Forbidden = 403,
     NotFound = 404,
+    MethodNotAllowed = 405,
+    RequestTimeout = 408,
+    Conflict = 409,
+    ContentTooLarge = 413,
+    UriTooLong = 414,
+    RangeNotSatisfiable = 416,
+    RequestHeaderFieldsTooLarge = 431,
     NotImplemented = 501,
     InternalServerError = 500,
     ServiceUnavailable = 503,
+    HttpVersionNotSupported = 505,
 }
 
 impl From<i32> for Code {
     fn from(value: i32) -> Self {
         match value {
             200 => Self::Ok,
+            201 => Self::Created,
+            204 => Self::NoContent,
+            206 => Self::PartialContent,
+            301 => Self::MovedPermanently,
+            304 => Self::NotModified,
+            400 => Self::BadRequest,
+            401 => Self::Unauthorized,
             403 => Self::Forbidden,
             404 => Self::NotFound,
+            405 => Self::MethodNotAllowed,
+            408 => Self::RequestTimeout,
+            409 => Self::Conflict,
+            413 => Self::ContentTooLarge,
+            414 => Self::UriTooLong,
+            416 => Self::RangeNotSatisfiable,
+            431 => Self::RequestHeaderFieldsTooLarge,
             500 => Self::InternalServerError,
             501 => Self::NotImplemented,
             503 => Self::ServiceUnavailable,
+            505 => Self::HttpVersionNotSupported,
             _ => Self::Unknown,
         }
     }
@@ -35,7 +105,11 @@ impl From<std::io::Error> for Code {
     fn from(e: std::io::Error) -> Self {
         match e.kind() {
             std::io::ErrorKind::NotFound => Self::NotFound,
+            std::io::ErrorKind::NotADirectory => Self::NotFound,
             std::io::ErrorKind::PermissionDenied => Self::Forbidden,
+            std::io::ErrorKind::IsADirectory | std::io::ErrorKind::DirectoryNotEmpty => {
+                Self::Conflict
+            }
             _ => Self::InternalServerError,
         }
     }
@@ -50,13 +124,629 @@ impl Display for Code {
             match self {
                 Code::Unknown => "Unknown",
                 Code::Ok => "Ok",
+                Code::Created => "Created",
+                Code::NoContent => "No Content",
+                Code::PartialContent => "Partial Content",
+                Code::MovedPermanently => "Moved Permanently",
+                Code::NotModified => "Not Modified",
                 Code::BadRequest => "Bad Request",
+                Code::Unauthorized => "Unauthorized",
                 Code::Forbidden => "Forbidden",
                 Code::NotFound => "Not Found",
+                Code::MethodNotAllowed => "Method Not Allowed",
+                Code::RequestTimeout => "Request Timeout",
+                Code::Conflict => "Conflict",
+                Code::ContentTooLarge => "Content Too Large",
+                Code::UriTooLong => "URI Too Long",
+                Code::RangeNotSatisfiable => "Range Not Satisfiable",
+                Code::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
                 Code::NotImplemented => "Not Implemented",
                 Code::InternalServerError => "Internal Server Error",
                 Code::ServiceUnavailable => "Service Unavailable",
+                Code::HttpVersionNotSupported => "HTTP Version Not Supported",
             }
         )
     }
 }
+
+/// Request as parsed from the wire, see https://datatracker.ietf.org/doc/html/rfc9112
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub method: Method,
+    pub version: Version,
+    /// Lowercase names, repeated fields are joined with ", "
+    pub headers: HashMap<Box<str>, Box<str>>,
+}
+
+impl Request {
+    /// Whether connection stays open after reply, HTTP/1.0 closes it by default
+    pub fn keep_alive(&self) -> bool {
+        let has = |option: &str| {
+            self.headers
+                .get("connection")
+                .is_some_and(|c| c.split(',').any(|x| x.trim().eq_ignore_ascii_case(option)))
+        };
+        match self.version {
+            Version::Http11 => !has("close"),
+            Version::Http10 => has("keep-alive"),
+        }
+    }
+
+    /// Whether client waits for `100 Continue` before sending body,
+    /// see RFC 9110 section 10.1.1. HTTP/1.0 clients can't ask for it.
+    pub fn expects_continue(&self) -> bool {
+        self.version == Version::Http11
+            && self
+                .headers
+                .get("expect")
+                .is_some_and(|e| e.eq_ignore_ascii_case("100-continue"))
+    }
+}
+
+/// Limits of what clients may send, larger requests are rejected
+#[derive(Debug, Clone)]
+pub(crate) struct Limits {
+    /// Request line and every field line
+    pub max_line: usize,
+    /// Number of header fields
+    pub max_headers: usize,
+    /// Request line and all the fields together
+    pub max_head: usize,
+    pub max_body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_line: 8 << 10,
+            max_headers: 100,
+            max_head: 64 << 10,
+            max_body: 16 << 20,
+        }
+    }
+}
+
+/// Parses whole request from the start of `buf`, like `parse_head` does,
+/// but returns it only once its body is complete too, along with the body
+#[cfg(test)]
+pub(crate) fn parse_request(
+    buf: &[u8],
+    limits: &Limits,
+) -> Result<Option<(Request, Vec<u8>, usize)>, Code> {
+    let Some((request, mut body, pos)) = parse_head(buf, limits)? else {
+        return Ok(None);
+    };
+    let mut data = Vec::new();
+    let used = body.decode(&buf[pos..], &mut data)?;
+    if !body.done() {
+        return Ok(None);
+    }
+    Ok(Some((request, data, pos + used)))
+}
+
+/// Parses request line and header fields from the start of `buf`, which holds
+/// whatever was read from connection so far. Returns the request, decoder of
+/// its body, and number of bytes the head took, `None` if the head
+/// is not complete yet, or error code to reply with before closing the
+/// connection, as it can't be told where the next request starts.
+pub(crate) fn parse_head(
+    buf: &[u8],
+    limits: &Limits,
+) -> Result<Option<(Request, Body, usize)>, Code> {
+    // empty lines before request line are allowed, see RFC 9112 section 2.2
+    let mut pos = 0;
+    let start = loop {
+        let Some((line, next)) = read_line(buf, pos, limits.max_line, Code::UriTooLong)? else {
+            return Ok(None);
+        };
+        pos = next;
+        if pos > limits.max_head {
+            return Err(Code::RequestHeaderFieldsTooLarge);
+        }
+        if !line.is_empty() {
+            break line;
+        }
+    };
+    let start = std::str::from_utf8(start).map_err(|_| Code::BadRequest)?;
+    let mut parts = start.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(Code::BadRequest);
+    };
+    if !is_token(method) || target.is_empty() || target.chars().any(|c| c.is_control()) {
+        return Err(Code::BadRequest);
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        v if v.starts_with("HTTP/") => return Err(Code::HttpVersionNotSupported),
+        _ => return Err(Code::BadRequest),
+    };
+
+    let mut fields: Vec<(String, String)> = Vec::new();
+    loop {
+        let Some((line, next)) =
+            read_line(buf, pos, limits.max_line, Code::RequestHeaderFieldsTooLarge)?
+        else {
+            return Ok(None);
+        };
+        pos = next;
+        if pos > limits.max_head {
+            return Err(Code::RequestHeaderFieldsTooLarge);
+        }
+        if line.is_empty() {
+            break;
+        }
+        let line = String::from_utf8_lossy(line);
+        if line.starts_with([' ', '\t']) {
+            // obsolete line folding, continues value of the previous field
+            let (_, value) = fields.last_mut().ok_or(Code::BadRequest)?;
+            value.push(' ');
+            value.push_str(line.trim_matches([' ', '\t']));
+            continue;
+        }
+        // split on the first colon only, values like host:port have more
+        let (name, value) = line.split_once(':').ok_or(Code::BadRequest)?;
+        if !is_token(name) {
+            return Err(Code::BadRequest);
+        }
+        if fields.len() == limits.max_headers {
+            return Err(Code::RequestHeaderFieldsTooLarge);
+        }
+        fields.push((
+            name.to_ascii_lowercase(),
+            String::from(value.trim_matches([' ', '\t'])),
+        ));
+    }
+
+    let mut headers: HashMap<Box<str>, Box<str>> = HashMap::new();
+    for (name, value) in fields {
+        match headers.get_mut(name.as_str()) {
+            Some(_) if name == "host" => return Err(Code::BadRequest),
+            Some(v) => *v = format!("{v}, {value}").into(),
+            None => {
+                headers.insert(name.into(), value.into());
+            }
+        }
+    }
+    if version == Version::Http11 && !headers.contains_key("host") {
+        return Err(Code::BadRequest);
+    }
+
+    let body = match (
+        headers.get("transfer-encoding"),
+        headers.get("content-length"),
+    ) {
+        // could be an attempt to smuggle a request past a proxy
+        (Some(_), Some(_)) => return Err(Code::BadRequest),
+        (Some(coding), None) => {
+            if !coding.eq_ignore_ascii_case("chunked") {
+                return Err(Code::NotImplemented);
+            }
+            Body::new(State::ChunkSize, limits)
+        }
+        (None, Some(length)) => {
+            let length = content_length(length)?;
+            if length > limits.max_body {
+                return Err(Code::ContentTooLarge);
+            }
+            Body::new(State::Length(length), limits)
+        }
+        (None, None) => Body::new(State::Done, limits),
+    };
+
+    let target: Box<str> = target.into();
+    let method = match method {
+        "GET" => Method::Get(target),
+        "HEAD" => Method::Head(target),
+        "OPTIONS" => Method::Options(target),
+        "PUT" => Method::Put(target),
+        "DELETE" => Method::Delete(target),
+        m => Method::Other(m.into(), target),
+    };
+    let request = Request {
+        method,
+        version,
+        headers,
+    };
+    Ok(Some((request, body, pos)))
+}
+
+/// Reads line starting at `pos`, without its CRLF (or bare LF).
+/// Returns the line and position after it, `None` if it is not complete yet.
+fn read_line(
+    buf: &[u8],
+    pos: usize,
+    max: usize,
+    too_long: Code,
+) -> Result<Option<(&[u8], usize)>, Code> {
+    let rest = &buf[pos..];
+    match rest.iter().position(|b| *b == b'\n') {
+        Some(n) if n > max => Err(too_long),
+        Some(n) => {
+            let line = &rest[..n];
+            Ok(Some((
+                line.strip_suffix(b"\r").unwrap_or(line),
+                pos + n + 1,
+            )))
+        }
+        None if rest.len() > max => Err(too_long),
+        None => Ok(None),
+    }
+}
+
+/// Decoder of request body, fed with data as it arrives, so none of it is
+/// looked at twice however small the pieces are
+#[derive(Debug, Clone)]
+pub(crate) struct Body {
+    state: State,
+    /// Bytes of body decoded so far
+    size: usize,
+    max_line: usize,
+    max_head: usize,
+    max_body: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Bytes left of body with Content-Length
+    Length(usize),
+    ChunkSize,
+    /// Bytes left of chunk data
+    Chunk(usize),
+    /// CRLF after chunk data
+    ChunkEnd,
+    /// Bytes of trailer fields so far, they are dropped
+    Trailer(usize),
+    Done,
+}
+
+impl Body {
+    fn new(state: State, limits: &Limits) -> Self {
+        let state = match state {
+            State::Length(0) => State::Done,
+            state => state,
+        };
+        Self {
+            state,
+            size: 0,
+            max_line: limits.max_line,
+            max_head: limits.max_head,
+            max_body: limits.max_body,
+        }
+    }
+
+    /// Whether the whole body was decoded
+    pub fn done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Decodes as much of `buf` as it can, appending data to `out`.
+    /// Returns number of bytes taken, the rest has to wait for more data.
+    pub fn decode(&mut self, buf: &[u8], out: &mut Vec<u8>) -> Result<usize, Code> {
+        let mut pos = 0;
+        loop {
+            match self.state {
+                State::Done => return Ok(pos),
+                State::Length(left) | State::Chunk(left) => {
+                    let n = left.min(buf.len() - pos);
+                    out.extend_from_slice(&buf[pos..pos + n]);
+                    pos += n;
+                    self.state = match (self.state, left - n) {
+                        (State::Length(_), 0) => State::Done,
+                        (_, 0) => State::ChunkEnd,
+                        (State::Length(_), left) => State::Length(left),
+                        (_, left) => State::Chunk(left),
+                    };
+                    if n < left {
+                        return Ok(pos);
+                    }
+                }
+                State::ChunkSize => {
+                    let Some((line, next)) = read_line(buf, pos, self.max_line, Code::BadRequest)?
+                    else {
+                        return Ok(pos);
+                    };
+                    pos = next;
+                    // chunk extensions are ignored
+                    let size = line.split(|b| *b == b';').next().unwrap_or_default();
+                    let size = std::str::from_utf8(size).map_err(|_| Code::BadRequest)?;
+                    let size = size.trim_matches([' ', '\t']);
+                    if size.is_empty() || !size.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return Err(Code::BadRequest);
+                    }
+                    let size =
+                        usize::from_str_radix(size, 16).map_err(|_| Code::ContentTooLarge)?;
+                    if size > self.max_body - self.size {
+                        return Err(Code::ContentTooLarge);
+                    }
+                    self.size += size;
+                    self.state = match size {
+                        0 => State::Trailer(0),
+                        size => State::Chunk(size),
+                    };
+                }
+                State::ChunkEnd => {
+                    match &buf[pos..] {
+                        [b'\r', b'\n', ..] => pos += 2,
+                        [b'\n', ..] => pos += 1,
+                        [] | [b'\r'] => return Ok(pos),
+                        _ => return Err(Code::BadRequest),
+                    }
+                    self.state = State::ChunkSize;
+                }
+                State::Trailer(seen) => {
+                    let Some((line, next)) =
+                        read_line(buf, pos, self.max_line, Code::RequestHeaderFieldsTooLarge)?
+                    else {
+                        return Ok(pos);
+                    };
+                    let seen = seen + next - pos;
+                    pos = next;
+                    if seen > self.max_head {
+                        return Err(Code::RequestHeaderFieldsTooLarge);
+                    }
+                    self.state = match line.is_empty() {
+                        true => State::Done,
+                        false => State::Trailer(seen),
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Content-Length value, repeated fields have to agree
+fn content_length(value: &str) -> Result<usize, Code> {
+    let mut lengths = value.split(',').map(|x| {
+        let x = x.trim();
+        match !x.is_empty() && x.bytes().all(|b| b.is_ascii_digit()) {
+            true => x.parse::<usize>().map_err(|_| Code::ContentTooLarge),
+            false => Err(Code::BadRequest),
+        }
+    });
+    let first = lengths.next().ok_or(Code::BadRequest)??;
+    for length in lengths {
+        if length? != first {
+            return Err(Code::BadRequest);
+        }
+    }
+    Ok(first)
+}
+
+/// Splits request target into percent-decoded path and raw query,
+/// `None` if path is not valid UTF-8 once decoded, or has NUL in it
+pub(crate) fn split_target(target: &str) -> Option<(String, &str)> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        let hex = rest.get(..2).and_then(|h| std::str::from_utf8(h).ok())?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &rest[2..];
+    }
+    let path = String::from_utf8(bytes).ok()?;
+    (!path.contains('\0')).then_some((path, query))
+}
+
+/// Percent-encodes everything but unreserved characters, as for a path segment
+pub(crate) fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                String::from(b as char)
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Token of RFC 9110 section 5.6.2, as in method and field names
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(buf: &[u8]) -> Result<Option<(Request, Vec<u8>, usize)>, Code> {
+        parse_request(buf, &Limits::default())
+    }
+
+    #[test]
+    fn parsing_requests() {
+        let buf = b"\r\nGET /a HTTP/1.1\r\nHost: example.com:8080\r\nAccept: text/html\r\n\
+                    X-Folded: one\r\n  two\r\naccept: */*\r\n\r\n\
+                    POST /b HTTP/1.1\nHost: x\nContent-Length: 3\n\nabc\
+                    PUT /c HTTP/1.0\r\nTransfer-Encoding: chunked\r\nConnection: keep-alive\r\n\r\n\
+                    4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        let (req, _, used) = parse(buf).unwrap().unwrap();
+        // every shorter prefix is just incomplete
+        for i in 0..used {
+            assert!(parse(&buf[..i]).unwrap().is_none(), "{i}");
+        }
+        assert_eq!(req.method, Method::Get("/a".into()));
+        assert_eq!(req.headers["host"].as_ref(), "example.com:8080");
+        assert_eq!(req.headers["accept"].as_ref(), "text/html, */*");
+        assert_eq!(req.headers["x-folded"].as_ref(), "one two");
+        assert!(req.keep_alive());
+
+        // pipelined requests follow right after
+        let (req, body, n) = parse(&buf[used..]).unwrap().unwrap();
+        assert_eq!(req.method, Method::Other("POST".into(), "/b".into()));
+        assert_eq!(req.method.target(), "/b");
+        assert_eq!(body, b"abc");
+        let used = used + n;
+        let (req, body, n) = parse(&buf[used..]).unwrap().unwrap();
+        assert_eq!(req.version, Version::Http10);
+        assert_eq!(req.method, Method::Put("/c".into()));
+        assert_eq!(body, b"Wikipedia");
+        assert!(req.keep_alive());
+        assert_eq!(used + n, buf.len());
+        for i in used..buf.len() {
+            assert!(parse(&buf[used..i]).unwrap().is_none(), "{i}");
+        }
+        // body fed byte by byte decodes the same, taking each byte once
+        let (_, mut body, head) = parse_head(&buf[used..], &Limits::default())
+            .unwrap()
+            .unwrap();
+        let (mut data, mut pending) = (Vec::new(), Vec::new());
+        for b in &buf[used + head..] {
+            assert!(!body.done());
+            pending.push(*b);
+            let taken = body.decode(&pending, &mut data).unwrap();
+            pending.drain(..taken);
+        }
+        assert!(body.done() && pending.is_empty());
+        assert_eq!(data, b"Wikipedia");
+
+        let closing = parse(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: Close\r\n\r\n");
+        assert!(!closing.unwrap().unwrap().0.keep_alive());
+        let old = parse(b"GET / HTTP/1.0\r\n\r\n").unwrap().unwrap().0;
+        assert!(!old.keep_alive());
+    }
+
+    #[test]
+    fn rejecting_requests() {
+        let error = |buf: &str| parse(buf.as_bytes()).unwrap_err();
+        assert_eq!(error("GET /\r\n\r\n"), Code::BadRequest);
+        assert_eq!(error("GET / HTTP/1.1\r\n\r\n"), Code::BadRequest);
+        assert_eq!(
+            error("GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n"),
+            Code::BadRequest
+        );
+        assert_eq!(
+            error("GET / HTTP/2.0\r\n\r\n"),
+            Code::HttpVersionNotSupported
+        );
+        assert_eq!(error("GET  / HTTP/1.1\r\n\r\n"), Code::BadRequest);
+        assert_eq!(error("GET / HTTP/1.0\r\nBad : x\r\n\r\n"), Code::BadRequest);
+        assert_eq!(error("GET / HTTP/1.0\r\n folded\r\n\r\n"), Code::BadRequest);
+        assert_eq!(
+            error("POST / HTTP/1.0\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            Code::BadRequest
+        );
+        assert_eq!(
+            error("POST / HTTP/1.0\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"),
+            Code::BadRequest
+        );
+        assert_eq!(
+            error("POST / HTTP/1.0\r\nContent-Length: -1\r\n\r\n"),
+            Code::BadRequest
+        );
+        assert_eq!(
+            error("POST / HTTP/1.0\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            Code::NotImplemented
+        );
+        assert_eq!(
+            error("POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"),
+            Code::BadRequest
+        );
+        assert_eq!(
+            error("POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nab\r\n"),
+            Code::BadRequest
+        );
+
+        let limits = Limits {
+            max_line: 32,
+            max_headers: 2,
+            max_head: 64,
+            max_body: 4,
+        };
+        let error = |buf: String| parse_request(buf.as_bytes(), &limits).unwrap_err();
+        assert_eq!(
+            error(format!("GET /{} HTTP/1.0\r\n", "a".repeat(40))),
+            Code::UriTooLong
+        );
+        // too long line is rejected before it ends
+        assert_eq!(error("a".repeat(40)), Code::UriTooLong);
+        assert_eq!(
+            error(String::from(
+                "GET / HTTP/1.0\r\na: 1\r\nb: 2\r\nc: 3\r\n\r\n"
+            )),
+            Code::RequestHeaderFieldsTooLarge
+        );
+        assert_eq!(
+            error(format!("GET / HTTP/1.0\r\n{}", "a: 1\r\n".repeat(10))),
+            Code::RequestHeaderFieldsTooLarge
+        );
+        assert_eq!(
+            error(String::from("PUT / HTTP/1.0\r\nContent-Length: 5\r\n\r\n")),
+            Code::ContentTooLarge
+        );
+        assert_eq!(
+            error(String::from(
+                "PUT / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\n"
+            )),
+            Code::ContentTooLarge
+        );
+    }
+
+    #[test]
+    fn decoding_targets() {
+        assert_eq!(
+            split_target("/a%20b/%D1%84?sort=size"),
+            Some((String::from("/a b/ф"), "sort=size"))
+        );
+        assert_eq!(split_target("/%2e%2E/x"), Some((String::from("/../x"), "")));
+        assert_eq!(split_target("/%zz"), None);
+        assert_eq!(split_target("/%2"), None);
+        assert_eq!(split_target("/%00"), None);
+        assert_eq!(split_target("/%ff"), None);
+        assert_eq!(percent_encode("a b/ф~"), "a%20b%2F%D1%84~");
+    }
+
+    /// Feeds mutated requests to the parser, which must neither panic nor
+    /// claim more bytes than it got
+    #[test]
+    fn fuzzing() {
+        let seeds: [&[u8]; 3] = [
+            b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+            b"POST /form HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello",
+            b"PUT /f HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n0\r\nTrailer: x\r\n\r\n",
+        ];
+        let alphabet = b"\r\n :;\t0123456789abcdefABCDEF-/HTTP\x00\xff";
+        let limits = Limits {
+            max_line: 64,
+            max_headers: 4,
+            max_head: 256,
+            max_body: 64,
+        };
+        // xorshift, so failures are reproducible
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut random = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        for _ in 0..20_000 {
+            let mut buf = seeds[random(seeds.len())].to_vec();
+            for _ in 0..1 + random(4) {
+                let i = random(buf.len() + 1);
+                let b = alphabet[random(alphabet.len())];
+                match random(3) {
+                    0 if i < buf.len() => buf[i] = b,
+                    1 if i < buf.len() => {
+                        buf.remove(i);
+                    }
+                    _ => buf.insert(i, b),
+                }
+            }
+            buf.extend_from_within(..random(buf.len()));
+            if let Ok(Some((_, _, used))) = parse_request(&buf, &limits) {
+                assert!(used > 0 && used <= buf.len(), "{buf:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use crate::http::percent_encode;
+use crate::time::{Civil, unix};
+use std::{io, path::Path};
+
+use futures_lite::StreamExt;
+
+/// Directory entry, as shown in listings
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub name: String,
+    pub dir: bool,
+    /// Bytes, 0 for directories
+    pub size: u64,
+    /// Seconds since Unix epoch
+    pub mtime: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Sort {
+    Name,
+    Size,
+    Mtime,
+}
+
+/// Listing order, taken from `sort` and `order` query parameters,
+/// like `?sort=size&order=desc`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Order {
+    pub sort: Sort,
+    pub descending: bool,
+}
+
+impl Order {
+    pub fn from_query(query: &str) -> Self {
+        let mut order = Self {
+            sort: Sort::Name,
+            descending: false,
+        };
+        for (k, v) in query.split('&').filter_map(|p| p.split_once('=')) {
+            match (k, v) {
+                ("sort", "name") => order.sort = Sort::Name,
+                ("sort", "size") => order.sort = Sort::Size,
+                ("sort", "mtime") => order.sort = Sort::Mtime,
+                ("order", "asc") => order.descending = false,
+                ("order", "desc") => order.descending = true,
+                _ => {}
+            }
+        }
+        order
+    }
+
+    /// Directories go first, whatever the order
+    pub fn sort(&self, entries: &mut [Entry]) {
+        entries.sort_by(|a, b| {
+            let by_key = match self.sort {
+                Sort::Name => a.name.cmp(&b.name),
+                Sort::Size => a.size.cmp(&b.size).then(a.name.cmp(&b.name)),
+                Sort::Mtime => a.mtime.cmp(&b.mtime).then(a.name.cmp(&b.name)),
+            };
+            let by_key = if self.descending {
+                by_key.reverse()
+            } else {
+                by_key
+            };
+            b.dir.cmp(&a.dir).then(by_key)
+        });
+    }
+
+    /// Query of column header link, which reverses order of current column
+    fn link(&self, sort: Sort) -> String {
+        let name = match sort {
+            Sort::Name => "name",
+            Sort::Size => "size",
+            Sort::Mtime => "mtime",
+        };
+        let desc = sort == self.sort && !self.descending;
+        format!(
+            "?sort={name}&amp;order={}",
+            if desc { "desc" } else { "asc" }
+        )
+    }
+}
+
+/// Reads directory, skipping hidden files, like temporary files of uploads
+pub(crate) async fn read(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut dir = async_fs::read_dir(dir).await?;
+    while let Some(entry) = dir.next().await {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        // entries removed since directory was read are skipped
+        let Ok(meta) = entry.metadata().await else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            dir: meta.is_dir(),
+            size: if meta.is_dir() { 0 } else { meta.len() },
+            mtime: meta.modified().map_or(0, unix),
+        });
+    }
+    Ok(entries)
+}
+
+/// Whether Accept header prefers JSON to HTML
+pub(crate) fn wants_json(accept: Option<&str>) -> bool {
+    let Some(accept) = accept else {
+        return false;
+    };
+    let quality = |media: &str| {
+        accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let range = params.next()?.trim();
+                let q = params
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                range.eq_ignore_ascii_case(media).then_some(q)
+            })
+            .fold(None, |max: Option<f32>, q| {
+                Some(max.map_or(q, |m| m.max(q)))
+            })
+    };
+    match (quality("application/json"), quality("text/html")) {
+        (Some(json), Some(html)) => json > 0.0 && json >= html,
+        (Some(json), None) => json > 0.0,
+        _ => false,
+    }
+}
+
+/// Listing page of directory at `path`, which is decoded request path
+pub(crate) fn html(path: &str, entries: &[Entry], order: Order) -> String {
+    let title = format!("Index of {}", escape(path));
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+         <body><h1>{title}</h1>\n<table>\n\
+         <tr><th><a href=\"{}\">Name</a></th><th><a href=\"{}\">Size</a></th>\
+         <th><a href=\"{}\">Modified</a></th></tr>\n",
+        order.link(Sort::Name),
+        order.link(Sort::Size),
+        order.link(Sort::Mtime),
+    );
+    if path != "/" {
+        page.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for e in entries {
+        let slash = if e.dir { "/" } else { "" };
+        let size = match e.dir {
+            true => String::from("-"),
+            false => e.size.to_string(),
+        };
+        let t = Civil::from_unix(e.mtime);
+        page.push_str(&format!(
+            "<tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td>{size}</td>\
+             <td>{:04}-{:02}-{:02} {:02}:{:02}</td></tr>\n",
+            percent_encode(&e.name),
+            escape(&e.name),
+            t.year,
+            t.month,
+            t.day,
+            t.hour,
+            t.minute,
+        ));
+    }
+    page.push_str("</table></body></html>\n");
+    page
+}
+
+/// Listing as JSON array of `{"name", "dir", "size", "mtime"}` objects
+pub(crate) fn json(entries: &[Entry]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|e| {
+            format!(
+                "{{\"name\":{},\"dir\":{},\"size\":{},\"mtime\":{}}}",
+                json_string(&e.name),
+                e.dir,
+                e.size,
+                e.mtime
+            )
+        })
+        .collect();
+    format!("[{}]\n", entries.join(","))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing() {
+        let entry = |name: &str, dir, size, mtime| Entry {
+            name: String::from(name),
+            dir,
+            size,
+            mtime,
+        };
+        let mut entries = vec![
+            entry("b.txt", false, 10, 300),
+            entry("a <b>.txt", false, 20, 100),
+            entry("docs", true, 0, 200),
+        ];
+        let names = |entries: &[Entry]| entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+        Order::from_query("").sort(&mut entries);
+        assert_eq!(names(&entries), ["docs", "a <b>.txt", "b.txt"]);
+        let order = Order::from_query("sort=size&order=desc");
+        order.sort(&mut entries);
+        assert_eq!(names(&entries), ["docs", "a <b>.txt", "b.txt"]);
+        Order::from_query("order=asc&sort=mtime").sort(&mut entries);
+        assert_eq!(names(&entries), ["docs", "a <b>.txt", "b.txt"]);
+        Order::from_query("sort=mtime&order=desc").sort(&mut entries);
+        assert_eq!(names(&entries), ["docs", "b.txt", "a <b>.txt"]);
+
+        let page = html("/files/", &entries, order);
+        assert!(page.contains("<title>Index of /files/</title>"));
+        assert!(page.contains("<a href=\"../\">"));
+        assert!(page.contains("<a href=\"docs/\">docs/</a></td><td>-</td>"));
+        assert!(page.contains(
+            "<a href=\"a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a></td><td>20</td>\
+             <td>1970-01-01 00:01</td>"
+        ));
+        // size column is sorted descending, so its link sorts ascending
+        assert!(page.contains("<a href=\"?sort=size&amp;order=asc\">Size</a>"));
+        assert!(page.contains("<a href=\"?sort=name&amp;order=asc\">Name</a>"));
+        assert!(!html("/", &entries, order).contains("../"));
+
+        assert_eq!(
+            json(&entries[..2]),
+            "[{\"name\":\"docs\",\"dir\":true,\"size\":0,\"mtime\":200},\
+             {\"name\":\"b.txt\",\"dir\":false,\"size\":10,\"mtime\":300}]\n"
+        );
+        assert_eq!(json_string("a\"\\\n"), "\"a\\\"\\\\\\u000a\"");
+
+        assert!(wants_json(Some("application/json")));
+        assert!(wants_json(Some("text/html;q=0.5, application/json")));
+        assert!(!wants_json(Some("text/html,application/json;q=0.9")));
+        assert!(!wants_json(Some("*/*")));
+        assert!(!wants_json(None));
+    }
+}
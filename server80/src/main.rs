@@ -1,32 +1,67 @@
+mod config;
 mod http;
+mod listing;
+mod mime;
+mod range;
+mod time;
+mod timeout;
+mod validators;
 
 use async_fs::File;
 use async_net::{TcpListener, TcpStream};
-use futures_lite::io;
+use clap::Parser;
+use easy_parallel::Parallel;
+use futures_lite::{FutureExt, future, io};
 use smol::{
-    LocalExecutor,
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufWriter},
+    Executor, Timer,
+    channel::{self, Receiver, Sender},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+    lock::Semaphore,
 };
-use snafu::{ResultExt, Whatever};
+use snafu::{ResultExt, Whatever, whatever};
 use std::{
     collections::HashMap,
+    io::SeekFrom,
     net::SocketAddr,
+    ops::RangeInclusive,
     path::{Path, PathBuf},
-    rc::Rc,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::http::{Code, Method};
+use crate::config::{Args, Config, Timeouts, host_key};
+use crate::http::{Body, Code, Limits, Method, Request, parse_head, split_target};
+use crate::listing::Order;
+use crate::mime::{SNIFF_LEN, Types};
+use crate::range::Ranges;
+use crate::timeout::{Timed, timeout};
+use crate::validators::Validators;
+
+/// Reply goes to client through it, giving up on clients which don't read
+type Writer = BufWriter<Timed<TcpStream>>;
 
 #[derive(Clone)]
 struct Server {
-    root: Rc<str>,
-}
-
-struct Request {
-    method: Method,
-    client: SocketAddr,
-    headers: HashMap<Box<str>, Box<str>>,
-    body: Option<Box<[u8]>>,
+    root: Arc<Path>,
+    /// Roots of virtual hosts, by lowercase host name
+    vhosts: Arc<HashMap<Box<str>, Arc<Path>>>,
+    limits: Arc<Limits>,
+    timeouts: Timeouts,
+    /// Bearer token for PUT and DELETE, which are disabled without it
+    write_token: Option<Arc<str>>,
+    /// List directories without index.html, instead of replying 403
+    listings: bool,
+    types: Arc<Types>,
+    /// Permit per open connection, once they're out, listeners stop accepting
+    permits: Arc<Semaphore>,
+    max_connections: usize,
+    /// Nothing is sent over it, it is closed on shutdown to wake whatever waits for it
+    stop: Sender<()>,
+    stopped: Receiver<()>,
 }
 
 struct Reply {
@@ -34,89 +69,191 @@ struct Reply {
     headers: Option<Vec<(Box<str>, Box<str>)>>,
 }
 
+impl Reply {
+    /// Whether connection has to be closed after this reply
+    fn closes(&self) -> bool {
+        self.headers
+            .iter()
+            .flatten()
+            .any(|(k, v)| k.eq_ignore_ascii_case("connection") && v.eq_ignore_ascii_case("close"))
+    }
+}
+
 impl Server {
+    fn new(config: &Config) -> Self {
+        let (stop, stopped) = channel::bounded(1);
+        let vhosts = config
+            .vhosts
+            .iter()
+            .flat_map(|v| {
+                let root = Arc::from(v.root.as_path());
+                v.hosts
+                    .iter()
+                    .map(move |h| (Box::from(host_key(h)), Arc::clone(&root)))
+            })
+            .collect();
+        Self {
+            root: Arc::from(config.root.as_path()),
+            vhosts: Arc::new(vhosts),
+            limits: Arc::new(Limits {
+                max_body: config.max_body,
+                ..Limits::default()
+            }),
+            timeouts: config.timeouts,
+            write_token: config.write_token.as_deref().map(Arc::from),
+            listings: config.listings,
+            types: Arc::new(Types::new(config.mime.clone())),
+            permits: Arc::new(Semaphore::new(config.max_connections)),
+            max_connections: config.max_connections,
+            stop,
+            stopped,
+        }
+    }
+
+    /// Stops accepting connections, and closes kept-alive ones once they're idle
+    fn shut_down(&self) {
+        self.stop.close();
+    }
+
+    fn stopping(&self) -> bool {
+        self.stop.is_closed()
+    }
+
+    /// Resolves once shutdown begins
+    async fn wait_stop(&self) {
+        // nothing is ever sent, so it returns only when channel is closed
+        let _ = self.stopped.recv().await;
+    }
+
+    /// Waits for all connections to finish, which is when all permits are back
+    async fn drain(&self) {
+        let mut permits = Vec::with_capacity(self.max_connections);
+        for _ in 0..self.max_connections {
+            permits.push(self.permits.acquire_arc().await);
+        }
+    }
+
     async fn handle_connection(
         &self,
         stream: TcpStream,
         client: SocketAddr,
     ) -> Result<(), Whatever> {
-        let mut r = io::BufReader::new(stream.clone());
-        let mut w = io::BufWriter::new(stream);
-        let mut start = String::new();
-        let mut headers = HashMap::new();
-        r.read_line(&mut start)
-            .await
-            .whatever_context("reading request")?;
+        let mut r = stream.clone();
+        let mut w = io::BufWriter::new(Timed::new(stream, self.timeouts.write()));
+        let mut buf = Vec::new();
 
-        // ** Reading HTTP message from client ** //
+        // ** Reading HTTP messages from client ** //
         // see https://datatracker.ietf.org/doc/html/rfc9112#message.format
         //
-        let start = start.trim_end_matches("\r\n"); // read start-line
+        // Requests are parsed from whatever was read so far, so pipelined
+        // ones are answered in order without waiting for more data.
         loop {
-            // read field lines (aka headers)
-            let mut line = String::new();
-            r.read_line(&mut line)
-                .await
-                .whatever_context("reading headers")?;
-            if line == "\r\n" || line == "\n" {
-                break;
-            }
-            let mut m = line.trim_end_matches("\r\n").split(':');
-            if let (Some(k), Some(v)) = (m.next(), m.next()) {
-                headers.insert(k.to_lowercase().trim().into(), v.trim().into());
-            } else {
-                w.write(b"HTTP/1.1 400 Bad Request\r\n")
-                    .await
-                    .whatever_context("writing response")?;
-                return Ok(());
-            }
-        }
-
-        // read body
-        let mut body = None;
-        if let Some(size) = headers
-            .get("content-length")
-            .and_then(|x: &Box<str>| x.parse::<usize>().ok())
-        {
-            let mut b = vec![0; size];
-            r.read_exact(&mut b)
-                .await
-                .whatever_context("reading request body")?;
-            body = Some(b.into_boxed_slice());
-        }
+            let mut fresh = buf.len();
+            // whole request has to arrive in time, however it's trickled
+            let mut deadline = None;
+            let (req, body, used) = loop {
+                if deadline.is_none() && !buf.is_empty() {
+                    deadline = Some(Instant::now() + self.timeouts.read());
+                }
+                // head can't be complete until its final line ends,
+                // but a line that never ends is rejected once it's too long
+                let ended = fresh > 0 && buf[buf.len() - fresh..].contains(&b'\n');
+                if ended || buf.len() > self.limits.max_line {
+                    match parse_head(&buf, &self.limits) {
+                        Ok(Some(parsed)) => break parsed,
+                        Ok(None) => {}
+                        Err(code) => return self.reject(&mut w, client, code).await,
+                    }
+                }
+                fresh = match self.read_more(&mut r, &mut w, &mut buf, deadline).await? {
+                    0 => return Ok(()),
+                    n => n,
+                };
+            };
+            buf.drain(..used);
 
-        let mut m = start.split(' ');
-        let req = match (m.next(), m.next(), m.next()) {
-            (Some("GET"), Some(target), Some(_)) => Request {
-                method: Method::Get(target.into()),
-                client,
-                headers,
+            let mut body = Incoming {
+                r: &mut r,
+                buf: &mut buf,
+                waiting: req.expects_continue() && !body.done(),
                 body,
-            },
-            _ => {
-                w.write(b"HTTP/1.1 400 Bad Request\r\n")
-                    .await
-                    .whatever_context("writing response")?;
+                deadline: deadline.unwrap_or_else(|| Instant::now() + self.timeouts.read()),
+                size: 0,
+                broken: false,
+            };
+            // only PUT takes its body, others have it skipped beforehand,
+            // so they aren't handled at all if it's malformed
+            if !matches!(req.method, Method::Put(_))
+                && !body.waiting
+                && let Err(code) = body.skip().await
+            {
+                return self.reject(&mut w, client, code).await;
+            }
+
+            let method = req.method.clone();
+            let reply = match self.handle_request(req, &mut body, &mut w).await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    eprintln!("Error handling request from {}: {e:?}", client);
+                    return Ok(());
+                }
+            };
+            eprintln!("{} {} {} {}", method, reply.code, client, body.size);
+            // refused PUT leaves its body behind, or never gets it sent
+            if reply.closes() || body.waiting || body.skip().await.is_err() {
                 return Ok(());
             }
-        };
+        }
+    }
 
-        let method = req.method.clone();
-        match self.handle_request(req, w).await {
-            Ok(reply) => {
-                eprintln!("{:?} {} {}", method, reply.code, client);
+    /// Reads more of request into `buf`. Returns number of bytes read,
+    /// 0 once connection is to be closed, which is also after replying 408
+    /// to request started but not finished by its `deadline`
+    async fn read_more(
+        &self,
+        r: &mut TcpStream,
+        w: &mut Writer,
+        buf: &mut Vec<u8>,
+        deadline: Option<Instant>,
+    ) -> Result<usize, Whatever> {
+        // idle connections are dropped sooner than ones sending request
+        let started = deadline.is_some();
+        let limit = match deadline {
+            Some(at) => at.saturating_duration_since(Instant::now()),
+            None => self.timeouts.idle(),
+        };
+        // shutdown closes connection between requests, but lets started one finish
+        let stop = async {
+            match started {
+                true => future::pending().await,
+                false => self.wait_stop().await,
             }
-            Err(e) => {
-                eprintln!("Error handling request from {}: {e:?}", client);
+            Ok(0)
+        };
+        match read_into(r, buf, limit).or(stop).await {
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                if started {
+                    let code = Code::RequestTimeout;
+                    self.reply(w, code, Some(close()), None).await?;
+                }
+                Ok(0)
             }
+            Err(e) => Err(e).whatever_context("reading request"),
         }
+    }
 
+    /// Replies to malformed request, closing connection as it can't be told
+    /// where the next request starts
+    async fn reject(&self, w: &mut Writer, client: SocketAddr, code: Code) -> Result<(), Whatever> {
+        eprintln!("Bad request from {client}: {code}");
+        self.reply(w, code, Some(close()), None).await?;
         Ok(())
     }
 
     async fn reply(
         &self,
-        w: &mut BufWriter<TcpStream>,
+        w: &mut Writer,
         code: Code,
         headers: Option<Vec<(Box<str>, Box<str>)>>,
         body: Option<&[u8]>,
@@ -132,8 +269,15 @@ impl Server {
                     .whatever_context("writing header")?;
             }
         }
-        if let Some(ref b) = body {
-            w.write(format!("Content-Length: {}\r\n", b.len()).as_bytes())
+        // connection is reused, so client has to know where reply ends
+        let has_length = headers
+            .iter()
+            .flatten()
+            .any(|(k, _)| k.eq_ignore_ascii_case("content-length"));
+        // 304 would tell length of the file, which it doesn't send,
+        // and 204 must not have it at all, see RFC 9110 section 8.6
+        if !has_length && !matches!(code, Code::NoContent | Code::NotModified) {
+            w.write(format!("Content-Length: {}\r\n", body.map_or(0, <[u8]>::len)).as_bytes())
                 .await
                 .whatever_context("writing content length")?;
         }
@@ -148,8 +292,179 @@ impl Server {
         Ok(Reply { code, headers })
     }
 
-    fn get_file_path(&self, path: &str) -> Option<impl AsRef<Path>> {
-        let mut p = PathBuf::from(self.root.as_ref());
+    /// Sends file, or part of it for Range requests,
+    /// just its headers for HEAD, or 304 if client has it already
+    async fn send_file(
+        &self,
+        w: &mut Writer,
+        path: &Path,
+        req: &Request,
+        headers: Option<Vec<(Box<str>, Box<str>)>>,
+    ) -> Result<Reply, Whatever> {
+        let mut f = match File::open(path).await {
+            Ok(f) => f,
+            Err(e) => return self.reply(w, Code::from(e), headers, None).await,
+        };
+        let meta = match f.metadata().await {
+            Ok(meta) => meta,
+            Err(e) => return self.reply(w, Code::from(e), headers, None).await,
+        };
+        let len = meta.len();
+        let validators = Validators::new(&meta);
+        let mut headers = headers.unwrap_or_default();
+        headers.extend(validators.headers());
+        if validators.not_modified(&req.headers) {
+            return self.reply(w, Code::NotModified, Some(headers), None).await;
+        }
+        headers.push((Box::from("Accept-Ranges"), Box::from("bytes")));
+
+        // ranges of HEAD are ignored, and so are ranges of changed file
+        let head = matches!(req.method, Method::Head(_));
+        let ranges = match req.headers.get("range") {
+            Some(r)
+                if !head
+                    && req
+                        .headers
+                        .get("if-range")
+                        .is_none_or(|v| validators.if_range(v)) =>
+            {
+                range::parse(r, len)
+            }
+            _ => Ranges::Whole,
+        };
+        if ranges == Ranges::Unsatisfiable {
+            headers.push((
+                Box::from("Content-Range"),
+                Box::from(format!("bytes */{len}")),
+            ));
+            return self
+                .reply(w, Code::RangeNotSatisfiable, Some(headers), None)
+                .await;
+        }
+
+        // contents are read only if extension tells nothing
+        let mut start = Vec::new();
+        if self.types.lookup(path).is_none()
+            && let Err(e) = (&mut f)
+                .take(SNIFF_LEN as u64)
+                .read_to_end(&mut start)
+                .await
+        {
+            return self.reply(w, Code::from(e), Some(headers), None).await;
+        }
+        let content_type = self.types.content_type(path, &start);
+
+        let ranges = match ranges {
+            Ranges::Partial(ranges) => ranges,
+            _ => {
+                headers.push((Box::from("Content-Type"), Box::from(content_type)));
+                headers.push((Box::from("Content-Length"), Box::from(format!("{len}"))));
+                let reply = self.reply(w, Code::Ok, Some(headers), None).await?;
+                if head {
+                    return Ok(reply);
+                }
+                w.write_all(&start).await.whatever_context("writing file")?;
+                // exactly as many bytes as promised, or the next reply is garbled
+                let rest = len.saturating_sub(start.len() as u64);
+                io::copy((&mut f).take(rest), &mut *w)
+                    .await
+                    .whatever_context("writing file")?;
+                w.flush().await.whatever_context("flushing buffer")?;
+                return Ok(reply);
+            }
+        };
+
+        if let [range] = ranges.as_slice() {
+            headers.push((Box::from("Content-Type"), Box::from(content_type)));
+            headers.push((
+                Box::from("Content-Range"),
+                Box::from(range::content_range(range, len)),
+            ));
+            let n = range.end() - range.start() + 1;
+            headers.push((Box::from("Content-Length"), Box::from(format!("{n}"))));
+            let reply = self
+                .reply(w, Code::PartialContent, Some(headers), None)
+                .await?;
+            self.copy_range(w, &mut f, range).await?;
+            w.flush().await.whatever_context("flushing buffer")?;
+            return Ok(reply);
+        }
+
+        let boundary = format!(
+            "{:x}{:x}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos())
+        );
+        let parts: Vec<String> = ranges
+            .iter()
+            .map(|r| range::part_head(&boundary, &content_type, &range::content_range(r, len)))
+            .collect();
+        let closing = range::closing(&boundary);
+        let n = parts.iter().map(|p| p.len() as u64).sum::<u64>()
+            + ranges.iter().map(|r| r.end() - r.start() + 1).sum::<u64>()
+            + closing.len() as u64;
+        headers.push((
+            Box::from("Content-Type"),
+            Box::from(format!("multipart/byteranges; boundary={boundary}")),
+        ));
+        headers.push((Box::from("Content-Length"), Box::from(format!("{n}"))));
+        let reply = self
+            .reply(w, Code::PartialContent, Some(headers), None)
+            .await?;
+        for (part, range) in parts.iter().zip(&ranges) {
+            w.write_all(part.as_bytes())
+                .await
+                .whatever_context("writing part")?;
+            self.copy_range(w, &mut f, range).await?;
+        }
+        w.write_all(closing.as_bytes())
+            .await
+            .whatever_context("writing part")?;
+        w.flush().await.whatever_context("flushing buffer")?;
+        Ok(reply)
+    }
+
+    /// Copies range of file, which has to be within it
+    async fn copy_range(
+        &self,
+        w: &mut Writer,
+        f: &mut File,
+        range: &RangeInclusive<u64>,
+    ) -> Result<(), Whatever> {
+        f.seek(SeekFrom::Start(*range.start()))
+            .await
+            .whatever_context("seeking file")?;
+        let n = range.end() - range.start() + 1;
+        let copied = io::copy(f.take(n), &mut *w)
+            .await
+            .whatever_context("writing file")?;
+        // file shrank, reply can't be finished
+        if copied != n {
+            whatever!("file ended {} bytes early", n - copied);
+        }
+        Ok(())
+    }
+
+    /// Root of virtual host named by Host, or the default one
+    fn root(&self, req: &Request) -> &Path {
+        let Some(host) = req.headers.get("host") else {
+            return &self.root;
+        };
+        // port goes after IPv6 address in brackets
+        let host = match host.strip_prefix('[') {
+            Some(_) => host.split_inclusive(']').next().unwrap_or_default(),
+            None => host.split(':').next().unwrap_or_default(),
+        };
+        self.vhosts
+            .get(host_key(host).as_str())
+            .unwrap_or(&self.root)
+    }
+
+    /// Maps decoded request path to file under root of the host
+    fn get_file_path(&self, req: &Request, path: &str) -> Option<PathBuf> {
+        let mut p = self.root(req).to_path_buf();
         for part in path.split('/') {
             match part {
                 ".." => return None,
@@ -164,78 +479,884 @@ impl Server {
     async fn handle_request(
         &self,
         req: Request,
-        mut w: BufWriter<TcpStream>,
+        body: &mut Incoming<'_>,
+        w: &mut Writer,
     ) -> Result<Reply, Whatever> {
-        if req.headers.get("host").is_none() {
-            self.reply(&mut w, Code::BadRequest, None, None).await?;
-        }
+        let headers = |mut headers: Vec<(Box<str>, Box<str>)>| {
+            if !req.keep_alive() || self.stopping() {
+                headers.extend(close());
+            }
+            Some(headers)
+        };
 
+        let allow = match self.write_token {
+            Some(_) => "GET, HEAD, OPTIONS, PUT, DELETE",
+            None => "GET, HEAD, OPTIONS",
+        };
         match req.method {
-            Method::Get(target) => {
-                let path = match self.get_file_path(&target) {
+            Method::Get(ref target) | Method::Head(ref target) => {
+                let head = matches!(req.method, Method::Head(_));
+                let Some((path, query)) = split_target(target) else {
+                    return self.reply(w, Code::BadRequest, headers(vec![]), None).await;
+                };
+                let file = match self.get_file_path(&req, &path) {
                     Some(p) => p,
                     None => {
                         return self
                             .reply(
-                                &mut w,
+                                w,
                                 Code::NotFound,
-                                None,
-                                Some("file not found".as_bytes()),
+                                headers(vec![]),
+                                Some("file not found".as_bytes()).filter(|_| !head),
                             )
                             .await;
                     }
                 };
+                let meta = match async_fs::metadata(&file).await {
+                    Ok(meta) => meta,
+                    Err(e) => return self.reply(w, Code::from(e), headers(vec![]), None).await,
+                };
+                if !meta.is_dir() {
+                    return self.send_file(w, &file, &req, headers(vec![])).await;
+                }
 
-                match File::open(path).await {
-                    Ok(f) => {
-                        let meta = match f.metadata().await {
-                            Ok(meta) => meta,
-                            Err(e) => {
-                                return self.reply(&mut w, Code::from(e), None, None).await;
-                            }
-                        };
-
-                        let reply = self
-                            .reply(
-                                &mut w,
-                                Code::Ok,
-                                Some(vec![(
-                                    Box::from("Content-Length"),
-                                    Box::from(format!("{}", meta.len())),
-                                )]),
-                                None,
-                            )
-                            .await?;
-                        io::copy(f, w).await.whatever_context("writing file")?;
-                        Ok(reply)
-                    }
-                    Err(e) => {
-                        return self.reply(&mut w, Code::from(e), None, None).await;
+                // relative links of index page resolve against the directory itself
+                if !path.ends_with('/') {
+                    let (raw, _) = target.split_once('?').unwrap_or((target, ""));
+                    let location = match query {
+                        "" => format!("{raw}/"),
+                        q => format!("{raw}/?{q}"),
+                    };
+                    let location = vec![(Box::from("Location"), Box::from(location))];
+                    return self
+                        .reply(w, Code::MovedPermanently, headers(location), None)
+                        .await;
+                }
+                let index = file.join("index.html");
+                if async_fs::metadata(&index).await.is_ok_and(|m| m.is_file()) {
+                    return self.send_file(w, &index, &req, headers(vec![])).await;
+                }
+                if !self.listings {
+                    return self.reply(w, Code::Forbidden, headers(vec![]), None).await;
+                }
+                let mut entries = match listing::read(&file).await {
+                    Ok(entries) => entries,
+                    Err(e) => return self.reply(w, Code::from(e), headers(vec![]), None).await,
+                };
+                let order = Order::from_query(query);
+                order.sort(&mut entries);
+                let (body, content_type) =
+                    match listing::wants_json(req.headers.get("accept").map(AsRef::as_ref)) {
+                        true => (listing::json(&entries), "application/json"),
+                        false => (
+                            listing::html(&path, &entries, order),
+                            "text/html; charset=utf-8",
+                        ),
+                    };
+                let mut extra = vec![(Box::from("Content-Type"), Box::from(content_type))];
+                if head {
+                    extra.push((
+                        Box::from("Content-Length"),
+                        Box::from(body.len().to_string()),
+                    ));
+                }
+                self.reply(
+                    w,
+                    Code::Ok,
+                    headers(extra),
+                    Some(body.as_bytes()).filter(|_| !head),
+                )
+                .await
+            }
+            Method::Options(_) => {
+                let allow = vec![(Box::from("Allow"), Box::from(allow))];
+                self.reply(w, Code::NoContent, headers(allow), None).await
+            }
+            Method::Put(_) | Method::Delete(_) if self.write_token.is_none() => {
+                let allow = vec![(Box::from("Allow"), Box::from(allow))];
+                self.reply(w, Code::MethodNotAllowed, headers(allow), None)
+                    .await
+            }
+            Method::Put(_) | Method::Delete(_) if !self.authorized(&req) => {
+                let challenge = vec![(Box::from("WWW-Authenticate"), Box::from("Bearer"))];
+                self.reply(w, Code::Unauthorized, headers(challenge), None)
+                    .await
+            }
+            Method::Put(ref target) => {
+                let file = split_target(target).and_then(|(p, _)| self.get_file_path(&req, &p));
+                let code = match file {
+                    Some(path) if !target.ends_with('/') => {
+                        // otherwise curl holds large uploads back for a second
+                        if body.waiting {
+                            w.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                                .await
+                                .whatever_context("writing 100 Continue")?;
+                            w.flush().await.whatever_context("writing 100 Continue")?;
+                            body.waiting = false;
+                        }
+                        match self.put_file(path.as_ref(), body).await {
+                            Ok(true) => Code::Created,
+                            Ok(false) => Code::NoContent,
+                            Err(code) => code,
+                        }
                     }
+                    _ => Code::Conflict,
+                };
+                // with body cut short, it can't be told where the next request starts
+                let extra = match body.broken {
+                    true => close(),
+                    false => vec![],
+                };
+                self.reply(w, code, headers(extra), None).await
+            }
+            Method::Delete(ref target) => {
+                let file = split_target(target).and_then(|(p, _)| self.get_file_path(&req, &p));
+                let code = match file {
+                    Some(path) => match async_fs::metadata(&path).await {
+                        Ok(meta) if meta.is_dir() => Code::Conflict,
+                        Ok(_) => async_fs::remove_file(&path)
+                            .await
+                            .map_or_else(Code::from, |_| Code::NoContent),
+                        Err(e) => Code::from(e),
+                    },
+                    None => Code::NotFound,
+                };
+                self.reply(w, code, headers(vec![]), None).await
+            }
+            Method::Other(..) => {
+                let allow = vec![(Box::from("Allow"), Box::from(allow))];
+                self.reply(w, Code::NotImplemented, headers(allow), None)
+                    .await
+            }
+        }
+    }
+
+    /// Accepts connections until shutdown, serving each in its own task
+    async fn serve(&self, ex: &Executor<'_>, listener: TcpListener) {
+        loop {
+            // with no permits left, new connections wait in listen backlog
+            let next = async {
+                let permit = self.permits.acquire_arc().await;
+                Some((permit, listener.accept().await))
+            };
+            let stop = async {
+                self.wait_stop().await;
+                None
+            };
+            let Some((permit, accepted)) = next.or(stop).await else {
+                return;
+            };
+            let (stream, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // errors like running out of file descriptors last, retrying at once would spin
+                    eprintln!("Error accepting connection: {e}");
+                    drop(permit);
+                    let pause = async {
+                        Timer::after(Duration::from_millis(100)).await;
+                    };
+                    pause.or(self.wait_stop()).await;
+                    continue;
                 }
+            };
+            let s = self.clone();
+            // connections are kept alive, so they are served side by side
+            ex.spawn(async move {
+                let _permit = permit;
+                if let Err(e) = s.handle_connection(stream, peer_addr).await {
+                    eprintln!("handling connection: {e}");
+                }
+            })
+            .detach();
+        }
+    }
+
+    /// Serves all listeners on the executor until shutdown
+    async fn serve_all(&self, ex: &Executor<'_>, listeners: &[TcpListener]) {
+        listeners
+            .iter()
+            .map(|l| Box::pin(self.serve(ex, l.clone())) as Pin<Box<dyn Future<Output = ()>>>)
+            .reduce(|a, b| {
+                Box::pin(async {
+                    future::zip(a, b).await;
+                })
+            })
+            .unwrap_or(Box::pin(future::ready(())))
+            .await
+    }
+
+    /// Checks bearer token of request modifying files, in constant time
+    fn authorized(&self, req: &Request) -> bool {
+        let (Some(token), Some(given)) = (
+            self.write_token.as_deref(),
+            req.headers
+                .get("authorization")
+                .and_then(|v| v.strip_prefix("Bearer ")),
+        ) else {
+            return false;
+        };
+        token.len() == given.len()
+            && token
+                .bytes()
+                .zip(given.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Writes file atomically, so readers never see it half written:
+    /// body goes to a temporary file next to it as it arrives, which then
+    /// replaces it. Returns whether the file was created.
+    async fn put_file(&self, path: &Path, body: &mut Incoming<'_>) -> Result<bool, Code> {
+        static UPLOADS: AtomicU64 = AtomicU64::new(0);
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(Code::Conflict);
+        };
+        async_fs::create_dir_all(dir).await?;
+        let created = match async_fs::metadata(path).await {
+            Ok(meta) if meta.is_dir() => return Err(Code::Conflict),
+            Ok(_) => false,
+            Err(_) => true,
+        };
+
+        let tmp = dir.join(format!(
+            ".{}.{}.{}.tmp",
+            name.to_string_lossy(),
+            std::process::id(),
+            UPLOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = async {
+            let mut f = File::create(&tmp).await?;
+            let mut data = Vec::new();
+            while body.next(&mut data).await? {
+                f.write_all(&data).await?;
+                data.clear();
             }
+            f.sync_all().await?;
+            async_fs::rename(&tmp, path).await?;
+            Ok(())
+        };
+        if let Err(e) = written.await {
+            let _ = async_fs::remove_file(&tmp).await;
+            return Err(e);
         }
+        Ok(created)
     }
 }
 
-fn main() {
-    let ex = LocalExecutor::new();
-    smol::block_on(ex.run(async {
-        let addr = ("::0", 8080);
-        let listener = TcpListener::bind(addr).await.expect("binding to the port");
-        let server = Server {
-            root: Rc::from("./"),
-        };
+/// Body of request being handled, taken from connection as handler reads it,
+/// so uploads go to disk without being held in memory
+struct Incoming<'a> {
+    r: &'a mut TcpStream,
+    /// Read from connection but not decoded yet
+    buf: &'a mut Vec<u8>,
+    body: Body,
+    /// When the whole request has to be in
+    deadline: Instant,
+    /// Bytes of body decoded so far
+    size: usize,
+    /// Reading body failed, so the connection is unusable
+    broken: bool,
+    /// Client sends body only after `100 Continue`
+    waiting: bool,
+}
+
+impl Incoming<'_> {
+    /// Decodes next piece of body into `out`, `false` once it's all taken.
+    /// Errors are codes to reply with before closing the connection
+    async fn next(&mut self, out: &mut Vec<u8>) -> Result<bool, Code> {
+        let next = self.decode(out).await;
+        self.broken |= next.is_err();
+        next
+    }
+
+    async fn decode(&mut self, out: &mut Vec<u8>) -> Result<bool, Code> {
         loop {
-            if let Ok((stream, peer_addr)) = listener.accept().await {
-                let s = server.clone();
-                if let Err(e) = ex
-                    .spawn(async move { s.handle_connection(stream, peer_addr).await })
-                    .await
-                {
-                    eprintln!("handling connection: {e}");
+            let len = out.len();
+            let used = self.body.decode(self.buf, out)?;
+            self.buf.drain(..used);
+            self.size += out.len() - len;
+            if out.len() > len {
+                return Ok(true);
+            }
+            if self.body.done() {
+                return Ok(false);
+            }
+            let limit = self.deadline.saturating_duration_since(Instant::now());
+            match read_into(self.r, self.buf, limit).await {
+                // client is gone before sending it all
+                Ok(0) => return Err(Code::BadRequest),
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    return Err(Code::RequestTimeout);
                 }
+                Err(e) => return Err(Code::from(e)),
             }
         }
-    }));
+    }
+
+    /// Reads the rest of body, so the next request can be found after it
+    async fn skip(&mut self) -> Result<(), Code> {
+        let mut data = Vec::new();
+        while self.next(&mut data).await? {
+            data.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Reads whatever comes next to the end of `buf`, giving up after `limit`
+async fn read_into(r: &mut TcpStream, buf: &mut Vec<u8>, limit: Duration) -> io::Result<usize> {
+    let len = buf.len();
+    buf.resize(len + (8 << 10), 0);
+    let read = timeout(limit, r.read(&mut buf[len..])).await;
+    buf.truncate(len + *read.as_ref().unwrap_or(&0));
+    read
+}
+
+/// Header asking client to close the connection
+fn close() -> Vec<(Box<str>, Box<str>)> {
+    vec![(Box::from("Connection"), Box::from("close"))]
+}
+
+fn main() {
+    let args = Args::parse();
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", snafu::Report::from_error(e));
+            std::process::exit(2);
+        }
+    };
+    if args.check_config {
+        println!("config is ok");
+        return;
+    }
+
+    let listeners: Result<Vec<TcpListener>, Whatever> = config
+        .listen
+        .iter()
+        .map(|addr| {
+            smol::block_on(TcpListener::bind(addr))
+                .with_whatever_context(|_| format!("binding to {addr}"))
+        })
+        .collect();
+    let listeners = match listeners {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            eprintln!("{}", snafu::Report::from_error(e));
+            std::process::exit(2);
+        }
+    };
+    let server = Server::new(&config);
+    let s = server.clone();
+    ctrlc::set_handler(move || {
+        if s.stopping() {
+            eprintln!("Interrupted again, exiting right away");
+            std::process::exit(130);
+        }
+        eprintln!("Shutting down, waiting for connections to finish");
+        s.shut_down();
+    })
+    .expect("setting signal handler");
+
+    // workers run connection tasks, main thread accepts them
+    let ex = Executor::new();
+    let (done, finished) = channel::unbounded::<()>();
+    Parallel::new()
+        .each(0..config.workers, |_| {
+            smol::block_on(ex.run(finished.recv()))
+        })
+        .finish(|| {
+            smol::block_on(async {
+                server.serve_all(&ex, &listeners).await;
+                let drained = async {
+                    server.drain().await;
+                    true
+                };
+                let deadline = async {
+                    Timer::after(config.timeouts.drain()).await;
+                    false
+                };
+                if !drained.or(deadline).await {
+                    eprintln!("Connections didn't finish in time, closing them");
+                }
+                drop(done);
+            })
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Sends raw requests over single connection and reads replies until server closes it
+    fn exchange(server: &Server, requests: &str) -> String {
+        let ex = Executor::new();
+        smol::block_on(ex.run(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = async {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream.write_all(requests.as_bytes()).await.unwrap();
+                let mut reply = String::new();
+                stream.read_to_string(&mut reply).await.unwrap();
+                reply
+            };
+            future::or(client, async {
+                server.serve(&ex, listener).await;
+                unreachable!()
+            })
+            .await
+        }))
+    }
+
+    /// Value of the first header named so in the reply
+    fn header_value(reply: &str, name: &str) -> String {
+        let start = reply.find(&format!("\r\n{name}: ")).unwrap() + name.len() + 4;
+        let end = reply[start..].find("\r\n").unwrap();
+        String::from(&reply[start..][..end])
+    }
+
+    #[test]
+    fn writing_files() {
+        let root = std::env::temp_dir().join(format!("server80-put-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut server = Server::new(&Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let reply = exchange(
+            &server,
+            "OPTIONS * HTTP/1.1\r\nHost: x\r\n\r\n\
+             PUT /a.txt HTTP/1.0\r\nContent-Length: 1\r\n\r\na",
+        );
+        assert!(reply.starts_with("HTTP/1.1 204 No Content\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+        assert!(reply.contains("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        server.write_token = Some(Arc::from("secret"));
+        let auth = "Authorization: Bearer secret\r\n";
+        let put =
+            format!("PUT /up/a.txt HTTP/1.1\r\nHost: x\r\n{auth}Content-Length: 4\r\n\r\ndata");
+        let requests = [
+            "PUT /up/a.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\nnope",
+            "PUT /up/a.txt HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer secreT\r\n\r\n",
+            &put,
+            &put,
+            "HEAD /up/a.txt HTTP/1.1\r\nHost: x\r\n\r\n",
+            "GET /up/a.txt HTTP/1.1\r\nHost: x\r\n\r\n",
+            &format!("DELETE /up HTTP/1.1\r\nHost: x\r\n{auth}\r\n"),
+            &format!("DELETE /up/a.txt HTTP/1.1\r\nHost: x\r\n{auth}\r\n"),
+            "GET /up/a.txt HTTP/1.1\r\nHost: x\r\n\r\n",
+            "PATCH /up/a.txt HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        ];
+        let reply = exchange(&server, &requests.concat());
+        let codes: Vec<&str> = reply
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|r| r.split("\r\n").next().unwrap())
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                "401 Unauthorized",
+                "401 Unauthorized",
+                "201 Created",
+                "204 No Content",
+                "200 Ok",
+                "200 Ok",
+                "409 Conflict",
+                "204 No Content",
+                "404 Not Found",
+                "501 Not Implemented",
+            ]
+        );
+        assert!(reply.contains("WWW-Authenticate: Bearer\r\n"));
+        // HEAD has length of GET, but no body
+        assert!(reply.contains(
+            "Content-Type: text/plain; charset=utf-8\r\nContent-Length: 4\r\n\r\nHTTP/1.1 200 Ok"
+        ));
+        assert!(reply.contains("\r\n\r\ndataHTTP/1.1 409"));
+        assert!(reply.contains("Allow: GET, HEAD, OPTIONS, PUT, DELETE\r\n"));
+        for r in reply.split("HTTP/1.1 ").filter(|r| r.starts_with("204")) {
+            assert!(!r.contains("Content-Length"), "{r}");
+        }
+        // no temporary files are left behind
+        assert_eq!(std::fs::read_dir(root.join("up")).unwrap().count(), 0);
+
+        // big body arrives in many reads, refused one is skipped
+        let chunk = "x".repeat(1000);
+        let chunked = format!("3e8\r\n{chunk}\r\n").repeat(100) + "0\r\n\r\n";
+        let put = |name: &str, auth: &str, framing: &str, body: &str| {
+            format!("PUT /{name} HTTP/1.1\r\nHost: x\r\n{auth}{framing}\r\n\r\n{body}")
+        };
+        let te = "Transfer-Encoding: chunked";
+        let requests = [
+            put("big.txt", auth, te, &chunked),
+            put("big.txt", "", te, &chunked),
+            put(
+                "small.txt",
+                auth,
+                "Content-Length: 1\r\nConnection: close",
+                "a",
+            ),
+        ];
+        let reply = exchange(&server, &requests.concat());
+        assert!(reply.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(reply.contains("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(
+            reply.ends_with(
+                "HTTP/1.1 201 Created\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+            )
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("big.txt")).unwrap(),
+            chunk.repeat(100)
+        );
+
+        // client waiting for 100 Continue gets it once it may send, or a final reply
+        let expect = "Content-Length: 4\r\nExpect: 100-continue";
+        let framing = format!("{expect}\r\nConnection: close");
+        let reply = exchange(&server, &put("sent.txt", auth, &framing, "data"));
+        assert!(reply.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\n"));
+        let reply = exchange(&server, &put("sent.txt", "", expect, ""));
+        assert!(reply.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(!reply.contains("100 Continue"));
+
+        // body over the limit is refused when it's announced, or once it's over
+        server.limits = Arc::new(Limits {
+            max_body: 1500,
+            ..Limits::default()
+        });
+        let reply = exchange(&server, &put("huge.txt", auth, "Content-Length: 1501", ""));
+        assert!(reply.starts_with("HTTP/1.1 413 Content Too Large\r\nConnection: close\r\n"));
+        // ends right where it's refused, so server has nothing left unread
+        let over = format!("3e8\r\n{chunk}\r\n3e8\r\n");
+        let reply = exchange(&server, &put("huge.txt", auth, te, &over));
+        assert!(reply.starts_with("HTTP/1.1 413 Content Too Large\r\nConnection: close\r\n"));
+        assert!(!root.join("huge.txt").exists());
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 4);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn serving_directories() {
+        let root = std::env::temp_dir().join(format!("server80-dir-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("site")).unwrap();
+        std::fs::create_dir_all(root.join("a b")).unwrap();
+        std::fs::write(root.join("site/index.html"), "home").unwrap();
+        std::fs::write(root.join("a b/big.txt"), "0123456789").unwrap();
+        std::fs::write(root.join("a b/small.txt"), "0").unwrap();
+        std::fs::write(root.join("a b/.hidden"), "").unwrap();
+        let mut server = Server::new(&Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let reply = exchange(
+            &server,
+            "GET /a%20b?sort=size HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /site/ HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /a%20b/?sort=size&order=desc HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /a%20b/ HTTP/1.1\r\nHost: x\r\nAccept: application/json\r\n\r\n\
+             GET /%zz HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+        let replies: Vec<&str> = reply.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(replies.len(), 5);
+        assert!(replies[0].starts_with("301 Moved Permanently\r\n"));
+        assert!(replies[0].contains("Location: /a%20b/?sort=size\r\n"));
+        assert!(replies[1].ends_with("\r\n\r\nhome"));
+
+        assert!(replies[2].contains("Content-Type: text/html; charset=utf-8\r\n"));
+        let big = replies[2].find("big.txt").unwrap();
+        let small = replies[2].find("small.txt").unwrap();
+        assert!(big < small);
+        assert!(replies[2].contains("<a href=\"../\">"));
+        assert!(!replies[2].contains(".hidden"));
+
+        assert!(replies[3].contains("Content-Type: application/json\r\n"));
+        let json = replies[3].split("\r\n\r\n").nth(1).unwrap();
+        assert!(json.starts_with(r#"[{"name":"big.txt","dir":false,"size":10,"mtime":"#));
+        assert!(replies[4].starts_with("400 Bad Request\r\n"));
+
+        server.listings = false;
+        let reply = exchange(
+            &server,
+            "GET /a%20b/ HTTP/1.1\r\nHost: x\r\n\r\n\
+             HEAD /site/ HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+        assert!(reply.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(reply.contains("HTTP/1.1 200 Ok\r\nConnection: close\r\n"));
+        assert!(
+            reply.ends_with("Content-Type: text/html; charset=utf-8\r\nContent-Length: 4\r\n\r\n")
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn content_types() {
+        let root = std::env::temp_dir().join(format!("server80-mime-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        // longer than sniffed part, which is sent separately
+        let notes = "line\n".repeat(200);
+        std::fs::write(root.join("notes"), &notes).unwrap();
+        std::fs::write(root.join("logo"), b"\x89PNG\r\n\x1a\n").unwrap();
+        std::fs::write(root.join("x.gmi"), "# hi").unwrap();
+        let server = Server::new(&Config {
+            root: root.clone(),
+            mime: [(String::from("gmi"), String::from("text/gemini"))].into(),
+            ..Config::default()
+        });
+        let reply = exchange(
+            &server,
+            "GET /notes HTTP/1.1\r\nHost: x\r\n\r\n\
+             HEAD /logo HTTP/1.1\r\nHost: x\r\n\r\n\
+             GET /x.gmi HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+        let replies: Vec<&str> = reply.split("HTTP/1.1 ").skip(1).collect();
+        assert!(replies[0].ends_with(&format!(
+            "\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 1000\r\n\r\n{notes}"
+        )));
+        assert!(replies[1].ends_with("\r\nContent-Type: image/png\r\nContent-Length: 8\r\n\r\n"));
+        assert!(replies[2].contains("Content-Type: text/gemini; charset=utf-8\r\n"));
+        assert!(replies[2].ends_with("\r\n\r\n# hi"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn partial_requests() {
+        let root = std::env::temp_dir().join(format!("server80-range-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "0123456789").unwrap();
+        let server = Server::new(&Config {
+            root: root.clone(),
+            ..Config::default()
+        });
+        let reply = exchange(
+            &server,
+            "GET /a.txt HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+        );
+        let etag = header_value(&reply, "ETag");
+        let modified = header_value(&reply, "Last-Modified");
+        assert!(reply.contains("Accept-Ranges: bytes\r\n"));
+
+        let requests = [
+            format!("GET /a.txt HTTP/1.1\r\nHost: x\r\nIf-None-Match: \"x\", W/{etag}\r\n\r\n"),
+            format!("HEAD /a.txt HTTP/1.1\r\nHost: x\r\nIf-Modified-Since: {modified}\r\n\r\n"),
+            String::from("GET /a.txt HTTP/1.1\r\nHost: x\r\nIf-None-Match: \"x\"\r\n\r\n"),
+            String::from("GET /a.txt HTTP/1.1\r\nHost: x\r\nRange: bytes=-3\r\n\r\n"),
+            format!(
+                "GET /a.txt HTTP/1.1\r\nHost: x\r\nRange: bytes=2-3\r\nIf-Range: {etag}\r\n\r\n"
+            ),
+            String::from(
+                "GET /a.txt HTTP/1.1\r\nHost: x\r\nRange: bytes=2-3\r\nIf-Range: \"old\"\r\n\r\n",
+            ),
+            String::from("GET /a.txt HTTP/1.1\r\nHost: x\r\nRange: bytes=10-\r\n\r\n"),
+            String::from("HEAD /a.txt HTTP/1.1\r\nHost: x\r\nRange: bytes=0-0\r\n\r\n"),
+            String::from(
+                "GET /a.txt HTTP/1.1\r\nHost: x\r\nRange: bytes=0-0,8-\r\nConnection: close\r\n\r\n",
+            ),
+        ];
+        let reply = exchange(&server, &requests.concat());
+        let replies: Vec<&str> = reply.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(replies.len(), 9);
+        assert_eq!(
+            replies[0],
+            format!("304 Not Modified\r\nETag: {etag}\r\nLast-Modified: {modified}\r\n\r\n")
+        );
+        assert!(replies[1].starts_with("304 Not Modified\r\n"));
+        assert!(replies[2].starts_with("200 Ok\r\n"));
+        assert!(replies[2].ends_with("\r\n\r\n0123456789"));
+
+        assert!(replies[3].starts_with("206 Partial Content\r\n"));
+        assert!(replies[3].contains("Content-Range: bytes 7-9/10\r\nContent-Length: 3\r\n"));
+        assert!(replies[3].ends_with("\r\n\r\n789"));
+        assert!(replies[4].starts_with("206 Partial Content\r\n"));
+        assert!(replies[4].ends_with("\r\n\r\n23"));
+        // file changed since client got the tag, so it gets whole new file
+        assert!(replies[5].starts_with("200 Ok\r\n"));
+        assert!(replies[5].ends_with("\r\n\r\n0123456789"));
+        assert!(replies[6].starts_with("416 Range Not Satisfiable\r\n"));
+        assert!(replies[6].contains("Content-Range: bytes */10\r\n"));
+        assert!(replies[7].starts_with("200 Ok\r\n"));
+        assert!(replies[7].contains("Content-Length: 10\r\n"));
+
+        let multipart = replies[8];
+        let boundary = header_value(multipart, "Content-Type")
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_owned();
+        let body = multipart.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(
+            body,
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                 Content-Range: bytes 0-0/10\r\n\r\n0\
+                 \r\n--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                 Content-Range: bytes 8-9/10\r\n\r\n89\
+                 \r\n--{boundary}--\r\n"
+            )
+        );
+        assert_eq!(
+            header_value(multipart, "Content-Length"),
+            body.len().to_string()
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn virtual_hosts() {
+        let root = std::env::temp_dir().join(format!("server80-vhost-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("default")).unwrap();
+        std::fs::create_dir_all(root.join("example")).unwrap();
+        std::fs::write(root.join("default/a.txt"), "default").unwrap();
+        std::fs::write(root.join("example/a.txt"), "example").unwrap();
+        let config = Config::parse(&format!(
+            "root = {:?}\n\
+             [timeouts]\n\
+             read = 1\n\
+             [[vhost]]\n\
+             hosts = [\"example.org.\", \"[::1]\"]\n\
+             root = {:?}\n",
+            root.join("default"),
+            root.join("example")
+        ))
+        .unwrap();
+        config.validate().unwrap();
+        let server = Server::new(&config);
+        let reply = exchange(
+            &server,
+            "GET /a.txt HTTP/1.1\r\nHost: Example.ORG.:8080\r\n\r\n\
+             GET /a.txt HTTP/1.1\r\nHost: [::1]:80\r\n\r\n\
+             GET /a.txt HTTP/1.1\r\nHost: other.org\r\n\r\n\
+             GET /a.txt HTTP/1.0\r\n\r\n",
+        );
+        let bodies: Vec<&str> = reply
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|r| r.split("\r\n\r\n").nth(1).unwrap())
+            .collect();
+        assert_eq!(bodies, ["example", "example", "default", "default"]);
+
+        // request which never ends
+        let reply = exchange(&server, "GET /a.txt HTTP/1.1\r\nHost: x\r\n");
+        assert!(reply.starts_with("HTTP/1.1 408 Request Timeout\r\nConnection: close\r\n"));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn limiting_and_draining() {
+        let root = std::env::temp_dir().join(format!("server80-drain-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "data").unwrap();
+        let server = Server::new(&Config {
+            root: root.clone(),
+            max_connections: 1,
+            ..Config::default()
+        });
+        let request = "GET /a.txt HTTP/1.1\r\nHost: x\r\n\r\n";
+        let ex = Executor::new();
+        smol::block_on(ex.run(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = async {
+                let mut first = TcpStream::connect(addr).await.unwrap();
+                first.write_all(request.as_bytes()).await.unwrap();
+                let mut reply = Vec::new();
+                let mut buf = vec![0; 4096];
+                while !reply.ends_with(b"\r\n\r\ndata") {
+                    let n = first.read(&mut buf).await.unwrap();
+                    assert_ne!(n, 0);
+                    reply.extend_from_slice(&buf[..n]);
+                }
+
+                // second client waits while the first is connected
+                let mut second = TcpStream::connect(addr).await.unwrap();
+                second.write_all(request.as_bytes()).await.unwrap();
+                let waited = second
+                    .read(&mut buf)
+                    .or(async {
+                        Timer::after(Duration::from_millis(200)).await;
+                        Ok(0)
+                    })
+                    .await
+                    .unwrap();
+                assert_eq!(waited, 0);
+
+                // started request is finished, then idle connections close
+                first.write_all(b"GET /a.txt HTTP/1.1\r\n").await.unwrap();
+                Timer::after(Duration::from_millis(100)).await;
+                server.shut_down();
+                first.write_all(b"Host: x\r\n\r\n").await.unwrap();
+                let mut reply = String::new();
+                first.read_to_string(&mut reply).await.unwrap();
+                assert!(reply.starts_with("HTTP/1.1 200 Ok\r\nConnection: close\r\n"));
+                assert!(reply.ends_with("\r\n\r\ndata"));
+            };
+            // serving ends with shutdown
+            future::zip(server.serve(&ex, listener), client).await;
+            server.drain().await;
+        }));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn timing_out_requests() {
+        let server = Server::new(&Config {
+            timeouts: Timeouts {
+                read: 1,
+                ..Timeouts::default()
+            },
+            ..Config::default()
+        });
+        let ex = Executor::new();
+        smol::block_on(ex.run(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = async {
+                let mut w = TcpStream::connect(addr).await.unwrap();
+                let mut r = w.clone();
+                let started = Instant::now();
+                // each byte comes well within read timeout, but the request never ends
+                let trickle = async {
+                    for b in b"GET / HTTP/1.1\r\nX: ".iter().cycle().take(20) {
+                        if w.write_all(&[*b]).await.is_err() {
+                            break;
+                        }
+                        Timer::after(Duration::from_millis(200)).await;
+                    }
+                };
+                let mut reply = String::new();
+                let read = r.read_to_string(&mut reply);
+                future::zip(trickle, read).await.1.unwrap();
+                assert!(reply.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+                assert!(started.elapsed() < Duration::from_secs(2));
+
+                // line with no end is rejected without waiting for the deadline
+                let mut w = TcpStream::connect(addr).await.unwrap();
+                let mut r = w.clone();
+                let started = Instant::now();
+                let flood = async {
+                    let _ = w.write_all("a".repeat(100_000).as_bytes()).await;
+                };
+                let read = async {
+                    let mut reply = Vec::new();
+                    let mut chunk = [0; 1024];
+                    // server closes with the rest unread, which may reset connection
+                    while let Ok(n @ 1..) = r.read(&mut chunk).await {
+                        reply.extend_from_slice(&chunk[..n]);
+                    }
+                    reply
+                };
+                let reply = future::zip(flood, read).await.1;
+                assert!(reply.starts_with(b"HTTP/1.1 414 URI Too Long\r\n"));
+                assert!(started.elapsed() < Duration::from_millis(500));
+            };
+            future::or(client, async {
+                server.serve(&ex, listener).await;
+                unreachable!()
+            })
+            .await
+        }));
+    }
 }
@@ -0,0 +1,185 @@
+use std::{collections::HashMap, path::Path};
+
+/// Types of common extensions, lowercase
+const TABLE: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("avif", "image/avif"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// Magic numbers of formats worth recognizing without extension
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\0asm", "application/wasm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OggS", "audio/ogg"),
+    (b"ID3", "audio/mpeg"),
+];
+
+/// Fallback for binary data nothing is known about
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// How many leading bytes of a file `sniff` needs
+pub(crate) const SNIFF_LEN: usize = 512;
+
+/// Maps file names to media types, by extension first,
+/// and by contents when extension is unknown
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Types {
+    /// Extension (lowercase, without dot) to type, taking precedence over builtin table
+    overrides: HashMap<Box<str>, Box<str>>,
+}
+
+impl Types {
+    pub fn new(overrides: impl IntoIterator<Item = (String, String)>) -> Self {
+        let overrides = overrides
+            .into_iter()
+            .map(|(ext, t)| {
+                let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+                (Box::from(ext), Box::from(t))
+            })
+            .collect();
+        Self { overrides }
+    }
+
+    /// Type by extension, `None` if it's unknown
+    pub fn lookup(&self, path: &Path) -> Option<&str> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        if let Some(t) = self.overrides.get(ext.as_str()) {
+            return Some(t);
+        }
+        TABLE.iter().find(|(e, _)| *e == ext).map(|(_, t)| *t)
+    }
+
+    /// Value of `Content-Type` for the file, `head` is its first `SNIFF_LEN` bytes,
+    /// needed only if `lookup` fails
+    pub fn content_type(&self, path: &Path, head: &[u8]) -> String {
+        with_charset(self.lookup(path).unwrap_or_else(|| sniff(head)))
+    }
+}
+
+/// Guesses type from leading bytes of contents
+pub(crate) fn sniff(head: &[u8]) -> &'static str {
+    if let Some((_, t)) = MAGIC.iter().find(|(m, _)| head.starts_with(m)) {
+        return t;
+    }
+    if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // last character may be cut in half
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).expect("prefix is valid up to the error")
+        }
+        Err(_) => return OCTET_STREAM,
+    };
+    if text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c'))
+    {
+        return OCTET_STREAM;
+    }
+    let start = text.trim_start().as_bytes();
+    let starts = |prefix: &str| {
+        start
+            .get(..prefix.len())
+            .is_some_and(|s| s.eq_ignore_ascii_case(prefix.as_bytes()))
+    };
+    if starts("<!doctype html") || starts("<html") {
+        return "text/html";
+    }
+    if starts("<?xml") {
+        return "application/xml";
+    }
+    "text/plain"
+}
+
+/// Adds UTF-8 charset to textual types which don't name one
+pub(crate) fn with_charset(t: &str) -> String {
+    let textual = t.starts_with("text/")
+        || matches!(
+            t,
+            "application/json" | "application/xml" | "application/javascript" | "image/svg+xml"
+        );
+    match textual && !t.contains("charset=") {
+        true => format!("{t}; charset=utf-8"),
+        false => String::from(t),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detecting_types() {
+        let types = Types::new([
+            (String::from(".GMI"), String::from("text/gemini")),
+            (
+                String::from("txt"),
+                String::from("text/plain; charset=latin1"),
+            ),
+        ]);
+        let t = |name: &str, head: &[u8]| types.content_type(Path::new(name), head);
+        assert_eq!(t("index.HTML", b""), "text/html; charset=utf-8");
+        assert_eq!(t("a.png", b"not really"), "image/png");
+        assert_eq!(t("capsule.gmi", b""), "text/gemini; charset=utf-8");
+        assert_eq!(t("old.txt", b""), "text/plain; charset=latin1");
+        assert_eq!(t("logo", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(t("x.unknown", b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(
+            t("page", b"\n  <!DOCTYPE html><p>"),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            t("README", "plain ф".as_bytes()),
+            "text/plain; charset=utf-8"
+        );
+        // multibyte character cut at the end of sniffed bytes
+        assert_eq!(
+            t("README", &"ф".as_bytes()[..1]),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(t("blob", b"\0\x01\x02"), OCTET_STREAM);
+        assert_eq!(t("blob", b"\xff\xfe"), OCTET_STREAM);
+        assert_eq!(t(".hidden", b""), "text/plain; charset=utf-8");
+    }
+}
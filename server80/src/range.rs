@@ -0,0 +1,131 @@
+use std::ops::RangeInclusive;
+
+/// More ranges than that are served as the whole file, they're rather
+/// an attempt to make server do lots of seeking than a real download
+const MAX_RANGES: usize = 32;
+
+/// What Range header asks for
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Ranges {
+    /// Header is malformed, or in other units, and is ignored
+    Whole,
+    /// Nonempty, sorted, with overlapping and adjacent ranges merged
+    Partial(Vec<RangeInclusive<u64>>),
+    /// None of the ranges overlaps the file
+    Unsatisfiable,
+}
+
+/// Parses Range header like `bytes=0-99, 200-, -50` for file of `len` bytes
+pub(crate) fn parse(header: &str, len: u64) -> Ranges {
+    let Some((unit, specs)) = header.split_once('=') else {
+        return Ranges::Whole;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Ranges::Whole;
+    }
+
+    let number = |s: &str| match !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+        true => s.parse::<u64>().ok(),
+        false => None,
+    };
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let Some((first, last)) = spec.split_once('-') else {
+            return Ranges::Whole;
+        };
+        let range = match (number(first), number(last)) {
+            // last n bytes
+            (None, Some(n)) if first.is_empty() => match n {
+                0 => None,
+                n => Some(len.saturating_sub(n)..=len.saturating_sub(1)),
+            },
+            (Some(first), None) if last.is_empty() => Some(first..=len.saturating_sub(1)),
+            (Some(first), Some(last)) if first <= last => {
+                Some(first..=last.min(len.saturating_sub(1)))
+            }
+            _ => return Ranges::Whole,
+        };
+        count += 1;
+        ranges.extend(range.filter(|r| *r.start() < len));
+    }
+    if count == 0 || count > MAX_RANGES {
+        return Ranges::Whole;
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    ranges.sort_by_key(|r| *r.start());
+    let mut merged: Vec<RangeInclusive<u64>> = Vec::with_capacity(ranges.len());
+    for r in ranges {
+        match merged.last_mut() {
+            Some(prev) if *r.start() <= prev.end().saturating_add(1) => {
+                *prev = *prev.start()..=*r.end().max(prev.end());
+            }
+            _ => merged.push(r),
+        }
+    }
+    Ranges::Partial(merged)
+}
+
+/// Value of Content-Range for a range of file of `len` bytes
+pub(crate) fn content_range(r: &RangeInclusive<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{len}", r.start(), r.end())
+}
+
+/// Headers of a part of multipart/byteranges body, with boundary before them
+pub(crate) fn part_head(boundary: &str, content_type: &str, range: &str) -> String {
+    format!("\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {range}\r\n\r\n")
+}
+
+/// Final boundary of multipart/byteranges body
+pub(crate) fn closing(boundary: &str) -> String {
+    format!("\r\n--{boundary}--\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse("bytes=0-499", 1000), Ranges::Partial(vec![0..=499]));
+        assert_eq!(parse("Bytes=500-", 1000), Ranges::Partial(vec![500..=999]));
+        assert_eq!(parse("bytes=-200", 1000), Ranges::Partial(vec![800..=999]));
+        assert_eq!(parse("bytes=-2000", 1000), Ranges::Partial(vec![0..=999]));
+        assert_eq!(
+            parse("bytes=900-2000", 1000),
+            Ranges::Partial(vec![900..=999])
+        );
+        assert_eq!(
+            parse("bytes=500-599, ,0-9, 5-20, 21-30", 1000),
+            Ranges::Partial(vec![0..=30, 500..=599])
+        );
+        // unsatisfiable ones are dropped, unless all are
+        assert_eq!(
+            parse("bytes=0-0,2000-3000", 1000),
+            Ranges::Partial(vec![0..=0])
+        );
+        assert_eq!(parse("bytes=1000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-1", 0), Ranges::Unsatisfiable);
+
+        assert_eq!(parse("bytes=5-1", 1000), Ranges::Whole);
+        assert_eq!(parse("bytes=-", 1000), Ranges::Whole);
+        assert_eq!(parse("bytes=+1-2", 1000), Ranges::Whole);
+        assert_eq!(parse("bytes=", 1000), Ranges::Whole);
+        assert_eq!(parse("items=0-1", 1000), Ranges::Whole);
+        assert_eq!(parse("0-1", 1000), Ranges::Whole);
+        assert_eq!(
+            parse(&format!("bytes={}", "0-0,".repeat(33)), 1000),
+            Ranges::Whole
+        );
+
+        assert_eq!(content_range(&(0..=0), 1), "bytes 0-0/1");
+        assert_eq!(
+            part_head("b", "text/plain", "bytes 0-0/1") + &closing("b"),
+            "\r\n--b\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-0/1\r\n\r\n\r\n--b--\r\n"
+        );
+    }
+}
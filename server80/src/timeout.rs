@@ -0,0 +1,129 @@
+use futures_lite::{AsyncWrite, FutureExt};
+use smol::Timer;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Fails with `TimedOut` if `f` takes longer than `limit`
+pub(crate) async fn timeout<T>(
+    limit: Duration,
+    f: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    f.or(async {
+        Timer::after(limit).await;
+        Err(io::ErrorKind::TimedOut.into())
+    })
+    .await
+}
+
+/// Writer failing with `TimedOut` once it makes no progress for `limit`,
+/// so client which stopped reading doesn't hold connection forever.
+/// Unlike `timeout` around whole reply, it lets big files go to slow clients
+pub(crate) struct Timed<W> {
+    inner: W,
+    limit: Duration,
+    /// Armed while inner writer is pending
+    timer: Option<Timer>,
+}
+
+impl<W> Timed<W> {
+    pub fn new(inner: W, limit: Duration) -> Self {
+        Self {
+            inner,
+            limit,
+            timer: None,
+        }
+    }
+
+    fn pending<T>(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<T>> {
+        let limit = self.limit;
+        let timer = self.timer.get_or_insert_with(|| Timer::after(limit));
+        match timer.poll(cx) {
+            Poll::Ready(_) => {
+                self.timer = None;
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn ready<T>(&mut self, r: io::Result<T>) -> Poll<io::Result<T>> {
+        self.timer = None;
+        Poll::Ready(r)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Timed<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(r) => self.ready(r),
+            Poll::Pending => self.pending(cx),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.inner).poll_flush(cx) {
+            Poll::Ready(r) => self.ready(r),
+            Poll::Pending => self.pending(cx),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.inner).poll_close(cx) {
+            Poll::Ready(r) => self.ready(r),
+            Poll::Pending => self.pending(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::{AsyncWriteExt, future};
+
+    /// Writer which never takes anything
+    struct Stuck;
+
+    impl AsyncWrite for Stuck {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn timing_out() {
+        let limit = Duration::from_millis(20);
+        let mut ok = Timed::new(Vec::new(), limit);
+        future::block_on(ok.write_all(b"data")).unwrap();
+        assert_eq!(ok.inner, b"data");
+
+        let mut stuck = Timed::new(Stuck, limit);
+        let e = future::block_on(stuck.write_all(b"data")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        let e = future::block_on(timeout(limit, future::pending::<io::Result<()>>())).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            future::block_on(timeout(limit, async { Ok(1) })).unwrap(),
+            1
+        );
+    }
+}
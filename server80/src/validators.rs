@@ -0,0 +1,128 @@
+use crate::time::{http_date, parse_http_date, unix};
+use std::{collections::HashMap, fs::Metadata, time::UNIX_EPOCH};
+
+/// ETag and Last-Modified of a file, both derived from its metadata,
+/// so they change whenever file is rewritten
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Validators {
+    /// Strong entity tag, with quotes
+    pub etag: String,
+    /// Seconds since Unix epoch
+    pub modified: Option<u64>,
+}
+
+impl Validators {
+    pub fn new(meta: &Metadata) -> Self {
+        let modified = meta.modified().ok();
+        let nanos = modified
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+        Self {
+            etag: format!("\"{:x}-{:x}\"", meta.len(), nanos),
+            modified: modified.map(unix),
+        }
+    }
+
+    pub fn headers(&self) -> Vec<(Box<str>, Box<str>)> {
+        let mut headers = vec![(Box::from("ETag"), Box::from(self.etag.as_str()))];
+        if let Some(t) = self.modified {
+            headers.push((Box::from("Last-Modified"), Box::from(http_date(t))));
+        }
+        headers
+    }
+
+    /// Whether client's copy is fresh and 304 does instead of the file.
+    /// If-Modified-Since is looked at only without If-None-Match
+    pub fn not_modified(&self, headers: &HashMap<Box<str>, Box<str>>) -> bool {
+        if let Some(tags) = headers.get("if-none-match") {
+            // weak comparison, W/ prefix doesn't matter
+            return tags.trim() == "*"
+                || entity_tags(tags).any(|(_, tag)| tag == self.etag.as_str());
+        }
+        let since = headers.get("if-modified-since");
+        match (since.and_then(|s| parse_http_date(s)), self.modified) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    /// Whether range of If-Range request still applies, which needs
+    /// strong match of the tag or exactly the same date
+    pub fn if_range(&self, value: &str) -> bool {
+        let value = value.trim();
+        if value.starts_with('"') || value.starts_with("W/") {
+            let mut tags = entity_tags(value);
+            return matches!(tags.next(), Some((false, tag)) if tag == self.etag)
+                && tags.next().is_none();
+        }
+        parse_http_date(value).is_some_and(|t| Some(t) == self.modified)
+    }
+}
+
+/// Entity tags of comma separated list, with flag for weak ones.
+/// Stops at the first malformed one
+fn entity_tags(list: &str) -> impl Iterator<Item = (bool, &str)> {
+    let mut rest = list;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        let weak = rest.starts_with("W/");
+        let tag = rest.strip_prefix("W/").unwrap_or(rest);
+        // tag can have commas, but never quotes inside
+        let end = tag.strip_prefix('"')?.find('"')? + 2;
+        rest = &tag[end..];
+        Some((weak, &tag[..end]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validating() {
+        let v = Validators {
+            etag: String::from("\"4-1a\""),
+            modified: Some(784111777),
+        };
+        let headers = |k: &str, val: &str| HashMap::from([(Box::from(k), Box::from(val))]);
+        let not_modified = |k, val| v.not_modified(&headers(k, val));
+        assert!(not_modified("if-none-match", "*"));
+        assert!(not_modified("if-none-match", "\"x,y\", W/\"4-1a\""));
+        assert!(!not_modified("if-none-match", "\"4-1b\""));
+        // dates don't matter when tags are there
+        let both = HashMap::from([
+            (Box::from("if-none-match"), Box::from("\"old\"")),
+            (
+                Box::from("if-modified-since"),
+                Box::from("Sun, 06 Nov 1994 08:49:37 GMT"),
+            ),
+        ]);
+        assert!(!v.not_modified(&both));
+        assert!(not_modified(
+            "if-modified-since",
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        ));
+        assert!(not_modified(
+            "if-modified-since",
+            "Mon, 07 Nov 1994 00:00:00 GMT"
+        ));
+        assert!(!not_modified(
+            "if-modified-since",
+            "Sun, 06 Nov 1994 08:49:36 GMT"
+        ));
+        assert!(!not_modified("if-modified-since", "yesterday"));
+
+        assert!(v.if_range("\"4-1a\""));
+        assert!(!v.if_range("W/\"4-1a\""));
+        assert!(!v.if_range("\"4-1a\", \"x\""));
+        assert!(v.if_range("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!v.if_range("Mon, 07 Nov 1994 00:00:00 GMT"));
+        assert_eq!(
+            v.headers()[1],
+            (
+                Box::from("Last-Modified"),
+                Box::from("Sun, 06 Nov 1994 08:49:37 GMT")
+            )
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use crate::charset;
+use crate::cso;
+use crate::frontier::now;
+use crate::gopher::{fetch_url, DirEntry, ExternalLink, GopherItem, GopherURL, Menu};
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection};
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
+
+const HELP: &str = "\
+  N          open item N
+  b, f       go back, forward
+  r          reload
+  g URL      go to URL
+  a [LABEL]  bookmark current page
+  m          list bookmarks
+  q          quit";
+
+/// Creates bookmarks table
+pub fn init(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS bookmarks(
+            url TEXT PRIMARY KEY, label TEXT NOT NULL, added INTEGER NOT NULL)",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Place in browsing history, search results are identified by query
+#[derive(Debug, Clone, PartialEq)]
+struct Location {
+    url: GopherURL,
+    query: Option<String>,
+}
+
+/// Line-oriented gopher browser, menu items are opened by their numbers
+pub struct Browser<'a, R: BufRead, W: Write> {
+    conn: &'a Connection,
+    input: R,
+    output: W,
+    /// Lines per page of text viewer
+    pub page_size: usize,
+    /// Where binary items are saved
+    pub download_dir: PathBuf,
+    history: Vec<Location>,
+    position: usize,
+    /// Menu whose items are opened by number, either current page or bookmarks
+    menu: Option<Menu>,
+}
+
+impl<'a, R: BufRead, W: Write> Browser<'a, R, W> {
+    pub fn new(conn: &'a Connection, input: R, output: W) -> Self {
+        Self {
+            conn,
+            input,
+            output,
+            page_size: 20,
+            download_dir: PathBuf::from("."),
+            history: Vec::new(),
+            position: 0,
+            menu: None,
+        }
+    }
+
+    /// Runs until `q` or end of input
+    pub fn run(&mut self, start: Option<GopherURL>) -> Result<()> {
+        if let Some(url) = start {
+            self.go(Location { url, query: None })?;
+        }
+        while let Some(line) = self.prompt("> ")? {
+            let (cmd, arg) = match line.split_once(' ') {
+                Some((cmd, arg)) => (cmd, Some(arg.trim())),
+                None => (line.as_str(), None),
+            };
+            let result = match (cmd, arg, cmd.parse::<usize>()) {
+                ("", _, _) => Ok(()),
+                ("q", _, _) => break,
+                ("b", _, _) => self.step_back(),
+                ("f", _, _) => self.step_forward(),
+                ("r", _, _) => self.reload(),
+                ("g", Some(url), _) => {
+                    GopherURL::try_from(url).and_then(|url| self.go(Location { url, query: None }))
+                }
+                ("a", label, _) => self.bookmark(label),
+                ("m", _, _) => self.bookmarks(),
+                (_, None, Ok(n)) => self.open(n),
+                _ => writeln!(self.output, "{HELP}").map_err(Into::into),
+            };
+            if let Err(e) = result {
+                writeln!(self.output, "error: {e:#}")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn prompt(&mut self, prompt: &str) -> Result<Option<String>> {
+        write!(self.output, "{prompt}")?;
+        self.output.flush()?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(String::from(line.trim())))
+    }
+
+    fn go(&mut self, location: Location) -> Result<()> {
+        self.show(&location)?;
+        self.history.truncate(self.position + 1);
+        if self.history.last() != Some(&location) {
+            self.history.push(location);
+        }
+        self.position = self.history.len() - 1;
+        Ok(())
+    }
+
+    fn step_back(&mut self) -> Result<()> {
+        if self.position == 0 {
+            return Err(anyhow!("no previous page"));
+        }
+        self.position -= 1;
+        self.reload()
+    }
+
+    fn step_forward(&mut self) -> Result<()> {
+        if self.position + 1 >= self.history.len() {
+            return Err(anyhow!("no next page"));
+        }
+        self.position += 1;
+        self.reload()
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        let location = self
+            .history
+            .get(self.position)
+            .cloned()
+            .ok_or(anyhow!("nothing to reload"))?;
+        self.show(&location)
+    }
+
+    fn open(&mut self, n: usize) -> Result<()> {
+        let (url, external) = self
+            .menu
+            .as_ref()
+            .and_then(|m| numbered(m).nth(n.wrapping_sub(1)))
+            .and_then(|e| Some((e.url.clone()?, e.external.clone())))
+            .ok_or(anyhow!("no item {n}"))?;
+        match external {
+            Some(ExternalLink::Cso { host, port }) => return self.phonebook(&host, port),
+            Some(link) => {
+                writeln!(self.output, "external link, open it elsewhere: {link}")?;
+                return Ok(());
+            }
+            None => {}
+        }
+        match url.gopher_type {
+            GopherItem::Submenu
+            | GopherItem::TextFile
+            | GopherItem::HtmlFile
+            | GopherItem::XmlFile => self.go(Location { url, query: None }),
+            GopherItem::FullTextSearch => match self.prompt("query: ")? {
+                Some(query) if !query.is_empty() => self.go(Location {
+                    url,
+                    query: Some(query),
+                }),
+                _ => Ok(()),
+            },
+            _ => self.save(&url),
+        }
+    }
+
+    /// Queries CSO phonebook until empty query
+    fn phonebook(&mut self, host: &str, port: u16) -> Result<()> {
+        while let Some(query) = self.prompt("phonebook query: ")? {
+            if query.is_empty() {
+                break;
+            }
+            let entries = match cso::query(host, port, &query) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    writeln!(self.output, "error: {e:#}")?;
+                    continue;
+                }
+            };
+            writeln!(self.output, "{} entries", entries.len())?;
+            for entry in entries {
+                for (name, value) in &entry.fields {
+                    let value = value.replace('\n', "\n             ");
+                    writeln!(self.output, "{name:>12}: {value}")?;
+                }
+                writeln!(self.output)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn show(&mut self, location: &Location) -> Result<()> {
+        let mut reply = Vec::new();
+        fetch_url(&location.url, location.query.clone())?
+            .read_to_end(&mut reply)
+            .context(format!("reading {}", location.url))?;
+        let text = charset::decode(&reply).text;
+        writeln!(self.output, "{}", location.url)?;
+        match location.url.gopher_type {
+            GopherItem::Submenu | GopherItem::FullTextSearch => {
+                let menu = Menu::parse(&text);
+                self.print_menu(&menu)?;
+                self.menu = Some(menu);
+                Ok(())
+            }
+            _ => self.page(&text),
+        }
+    }
+
+    fn print_menu(&mut self, menu: &Menu) -> Result<()> {
+        let mut n = 0;
+        for item in &menu.items {
+            match &item.url {
+                Some(_) if item.item_type != GopherItem::Error => {
+                    n += 1;
+                    writeln!(self.output, "{n:>4} {} {}", tag(item.item_type), item.label)?;
+                }
+                _ => {
+                    for line in item.label.lines() {
+                        writeln!(self.output, "         {line}")?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Shows text page by page, stops early on `q`
+    fn page(&mut self, text: &str) -> Result<()> {
+        let lines: Vec<&str> = text.lines().collect();
+        let pages = lines.chunks(self.page_size.max(1)).collect::<Vec<_>>();
+        for (i, page) in pages.iter().enumerate() {
+            for line in *page {
+                writeln!(self.output, "{line}")?;
+            }
+            if i + 1 < pages.len() {
+                let more = format!("-- {}/{} Enter for more, q to stop -- ", i + 1, pages.len());
+                if matches!(self.prompt(&more)?.as_deref(), Some("q") | None) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, url: &GopherURL) -> Result<()> {
+        let mut data = Vec::new();
+        fetch_url(url, None)?
+            .read_to_end(&mut data)
+            .context(format!("reading {url}"))?;
+        let name = url
+            .selector
+            .rsplit('/')
+            .find(|x| !x.is_empty() && *x != "..")
+            .unwrap_or("download");
+        let mut path = self.download_dir.join(name);
+        let mut i = 1;
+        while path.exists() {
+            path = self.download_dir.join(format!("{name}.{i}"));
+            i += 1;
+        }
+        std::fs::write(&path, &data).context(format!("saving {}", path.display()))?;
+        writeln!(
+            self.output,
+            "saved {} bytes to {}",
+            data.len(),
+            path.display()
+        )?;
+        Ok(())
+    }
+
+    fn bookmark(&mut self, label: Option<&str>) -> Result<()> {
+        let location = self
+            .history
+            .get(self.position)
+            .ok_or(anyhow!("nothing to bookmark"))?;
+        let url = location.url.to_string();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO bookmarks (url, label, added) VALUES (?1, ?2, ?3)",
+            params![url, label.unwrap_or(&url), now()],
+        )?;
+        writeln!(self.output, "bookmarked {url}")?;
+        Ok(())
+    }
+
+    fn bookmarks(&mut self) -> Result<()> {
+        let items = self
+            .conn
+            .prepare("SELECT url, label FROM bookmarks ORDER BY added, rowid")?
+            .query_map((), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .filter_map(|row| {
+                let (url, label) = row.ok()?;
+                let url = GopherURL::try_from(url.as_str()).ok()?;
+                Some(DirEntry {
+                    item_type: url.gopher_type,
+                    label,
+                    external: url.external(),
+                    url: Some(url),
+                    gopher_plus: false,
+                })
+            })
+            .collect();
+        let menu = Menu { items };
+        writeln!(self.output, "bookmarks")?;
+        self.print_menu(&menu)?;
+        self.menu = Some(menu);
+        Ok(())
+    }
+}
+
+/// Items that get numbers in menu listing
+fn numbered(menu: &Menu) -> impl Iterator<Item = &DirEntry> {
+    menu.items
+        .iter()
+        .filter(|e| e.url.is_some() && e.item_type != GopherItem::Error)
+}
+
+fn tag(item: GopherItem) -> &'static str {
+    match item {
+        GopherItem::Submenu => "DIR",
+        GopherItem::TextFile => "TXT",
+        GopherItem::FullTextSearch => "ASK",
+        GopherItem::HtmlFile => "HTM",
+        GopherItem::XmlFile => "XML",
+        GopherItem::GifFile
+        | GopherItem::ImageFile
+        | GopherItem::PngFile
+        | GopherItem::BitmapFile => "IMG",
+        GopherItem::SoundFile | GopherItem::WavFile => "SND",
+        GopherItem::MovieFile => "MOV",
+        GopherItem::Telnet | GopherItem::Telnet3270 => "TEL",
+        GopherItem::Nameserver => "CSO",
+        _ => "BIN",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::init_db;
+    use crate::testing::{MockGopherServer, Response};
+
+    #[test]
+    fn browsing() {
+        let server = MockGopherServer::new().unwrap();
+        server.route(
+            "",
+            server.menu(&[
+                (GopherItem::Info, "Welcome", ""),
+                (GopherItem::TextFile, "Long read", "/long.txt"),
+                (GopherItem::FullTextSearch, "Search", "/search"),
+                (GopherItem::BinaryFile, "Archive", "/files/hole.zip"),
+                (GopherItem::HtmlFile, "Web", "URL:https://example.org/"),
+            ]),
+        );
+        let long: Vec<String> = (1..=5).map(|i| format!("line {i}")).collect();
+        server.route("/long.txt", Response::text(&long.join("\r\n")));
+        server.route(
+            "/search",
+            server.menu(&[(GopherItem::TextFile, "Found it", "/long.txt")]),
+        );
+        server.route("/files/hole.zip", Response::Raw(vec![b'P', b'K', 3, 4]));
+
+        let dir = std::env::temp_dir().join(format!("snitch-browse-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let conn = init_db(":memory:").unwrap();
+        let input = "a home\n1\n\nq\nb\n2\ngopher\nb\nf\nb\n3\n4\nm\n1\nq\n";
+        let mut output = Vec::new();
+        let mut browser = Browser::new(&conn, input.as_bytes(), &mut output);
+        browser.page_size = 2;
+        browser.download_dir = dir.clone();
+        browser
+            .run(Some(server.url(GopherItem::Submenu, "")))
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains("   1 TXT Long read\n"));
+        assert!(output.contains("         Welcome\n"));
+        // two pages shown, third one skipped
+        assert!(output.contains("line 4\n-- 2/3"));
+        assert!(!output.contains("line 5"));
+        assert!(server.requests().contains(&String::from("/search\tgopher")));
+        assert!(output.contains("   1 TXT Found it\n"));
+        assert_eq!(std::fs::read(dir.join("hole.zip")).unwrap(), b"PK\x03\x04");
+        assert!(output.contains("open it elsewhere: https://example.org/\n"));
+        assert!(!server.requests().iter().any(|r| r.starts_with("URL:")));
+        assert!(output.contains("   1 DIR home\n"));
+        assert_eq!(
+            server.requests().iter().filter(|r| r.is_empty()).count(),
+            5,
+            "{output}"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use encoding::all::{IBM866, ISO_8859_1, KOI8_R, UTF_8, WINDOWS_1251};
+use encoding::codec::singlebyte::SingleByteEncoding;
+use encoding::label::encoding_from_whatwg_label;
+use encoding::{DecoderTrap, EncodingRef};
+
+/// IBM PC code page, used by DOS-era ANSI art and text files.
+/// `encoding` crate knows CP866 but not CP437, so its table is here.
+pub const CP437: &SingleByteEncoding = &SingleByteEncoding {
+    name: "cp437",
+    whatwg_name: None,
+    index_forward: cp437_forward,
+    index_backward: cp437_backward,
+};
+
+#[rustfmt::skip]
+const CP437_TABLE: [u16; 128] = [
+    0x00c7, 0x00fc, 0x00e9, 0x00e2, 0x00e4, 0x00e0, 0x00e5, 0x00e7,
+    0x00ea, 0x00eb, 0x00e8, 0x00ef, 0x00ee, 0x00ec, 0x00c4, 0x00c5,
+    0x00c9, 0x00e6, 0x00c6, 0x00f4, 0x00f6, 0x00f2, 0x00fb, 0x00f9,
+    0x00ff, 0x00d6, 0x00dc, 0x00a2, 0x00a3, 0x00a5, 0x20a7, 0x0192,
+    0x00e1, 0x00ed, 0x00f3, 0x00fa, 0x00f1, 0x00d1, 0x00aa, 0x00ba,
+    0x00bf, 0x2310, 0x00ac, 0x00bd, 0x00bc, 0x00a1, 0x00ab, 0x00bb,
+    0x2591, 0x2592, 0x2593, 0x2502, 0x2524, 0x2561, 0x2562, 0x2556,
+    0x2555, 0x2563, 0x2551, 0x2557, 0x255d, 0x255c, 0x255b, 0x2510,
+    0x2514, 0x2534, 0x252c, 0x251c, 0x2500, 0x253c, 0x255e, 0x255f,
+    0x255a, 0x2554, 0x2569, 0x2566, 0x2560, 0x2550, 0x256c, 0x2567,
+    0x2568, 0x2564, 0x2565, 0x2559, 0x2558, 0x2552, 0x2553, 0x256b,
+    0x256a, 0x2518, 0x250c, 0x2588, 0x2584, 0x258c, 0x2590, 0x2580,
+    0x03b1, 0x00df, 0x0393, 0x03c0, 0x03a3, 0x03c3, 0x00b5, 0x03c4,
+    0x03a6, 0x0398, 0x03a9, 0x03b4, 0x221e, 0x03c6, 0x03b5, 0x2229,
+    0x2261, 0x00b1, 0x2265, 0x2264, 0x2320, 0x2321, 0x00f7, 0x2248,
+    0x00b0, 0x2219, 0x00b7, 0x221a, 0x207f, 0x00b2, 0x25a0, 0x00a0,
+];
+
+fn cp437_forward(code: u8) -> u16 {
+    CP437_TABLE[(code - 0x80) as usize]
+}
+
+fn cp437_backward(code: u32) -> u8 {
+    CP437_TABLE
+        .iter()
+        .position(|&c| c as u32 == code)
+        .map_or(0, |i| i as u8 + 0x80)
+}
+
+/// Legacy encodings tried when content is not valid UTF-8, ties go to the first one
+const CANDIDATES: &[EncodingRef] = &[KOI8_R, WINDOWS_1251, IBM866, CP437, ISO_8859_1];
+
+/// Text decoded from gopher reply
+#[derive(Debug, PartialEq)]
+pub struct Decoded {
+    pub text: String,
+    /// Name of detected encoding, like `utf-8` or `koi8-r`
+    pub encoding: &'static str,
+}
+
+/// Finds encoding by name, both WHATWG labels and `cp437` are accepted
+pub fn by_name(name: &str) -> Option<EncodingRef> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "cp437" | "ibm437" | "437" => Some(CP437),
+        name => encoding_from_whatwg_label(name),
+    }
+}
+
+/// Guesses encoding of text. Valid UTF-8 is taken as is, otherwise every legacy
+/// encoding is scored by how much decoded text looks like a natural language.
+pub fn detect(data: &[u8]) -> EncodingRef {
+    if std::str::from_utf8(data).is_ok() {
+        return UTF_8;
+    }
+    let mut best = (ISO_8859_1 as EncodingRef, i64::MIN);
+    for &encoding in CANDIDATES {
+        let Ok(text) = encoding.decode(data, DecoderTrap::Strict) else {
+            continue;
+        };
+        let score = score(&text);
+        log::trace!("[charset] {} scored {score}", encoding.name());
+        if score > best.1 {
+            best = (encoding, score);
+        }
+    }
+    best.0
+}
+
+/// Detects encoding and decodes text, undecodable bytes are replaced
+pub fn decode(data: &[u8]) -> Decoded {
+    let encoding = detect(data);
+    Decoded {
+        text: encoding
+            .decode(data, DecoderTrap::Replace)
+            .unwrap_or_else(|_| String::from_utf8_lossy(data).into_owned()),
+        encoding: encoding.name(),
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Greek,
+}
+
+fn script(c: char) -> Option<Script> {
+    match c {
+        '\u{0370}'..='\u{03ff}' => Some(Script::Greek),
+        '\u{0400}'..='\u{04ff}' => Some(Script::Cyrillic),
+        c if c.is_alphabetic() => Some(Script::Latin),
+        _ => None,
+    }
+}
+
+/// Wrong encoding gives words mixing scripts, capitals in the middle of
+/// words, long runs of the same letter (box drawing lines read as text)
+/// and control characters; right one gives lowercase words and,
+/// for DOS art, box drawing.
+fn score(text: &str) -> i64 {
+    let mut score = 0;
+    let mut prev: Option<char> = None;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if prev == Some(c) { run + 1 } else { 0 };
+        if !c.is_ascii() {
+            score += match c {
+                '\u{80}'..='\u{9f}' => -5,
+                '\u{2500}'..='\u{259f}' => 1,
+                c if c.is_alphabetic() && run >= 2 => -4,
+                c if c.is_lowercase() => 2,
+                c if c.is_uppercase() => match prev {
+                    Some(p) if p.is_lowercase() => -2,
+                    _ => 1,
+                },
+                _ => -1,
+            };
+        }
+        if let (Some(a), Some(b)) = (prev.and_then(script), script(c)) {
+            if a != b {
+                score -= 3;
+            }
+        }
+        prev = Some(c);
+    }
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding::EncoderTrap;
+
+    fn roundtrip(text: &str, encoding: EncodingRef) -> Decoded {
+        decode(&encoding.encode(text, EncoderTrap::Strict).unwrap())
+    }
+
+    #[test]
+    fn detecting() {
+        let russian = "Добро пожаловать в нашу нору! Здесь лежат старые тексты.";
+        assert_eq!(
+            roundtrip(russian, KOI8_R),
+            Decoded {
+                text: String::from(russian),
+                encoding: "koi8-r"
+            }
+        );
+        assert_eq!(roundtrip(russian, WINDOWS_1251).encoding, "windows-1251");
+        assert_eq!(roundtrip(russian, IBM866).encoding, "ibm866");
+
+        let french = "Bienvenue à la tanière, où l'on trouve des écrits très anciens.";
+        assert_eq!(roundtrip(french, ISO_8859_1).text, french);
+        assert_eq!(roundtrip(french, ISO_8859_1).encoding, "iso-8859-1");
+
+        let art = "╔══════════╗\r\n║ BBS list ║\r\n╚══════════╝\r\n░▒▓█ café █▓▒░";
+        assert_eq!(roundtrip(art, CP437).text, art);
+        assert_eq!(roundtrip(art, CP437).encoding, "cp437");
+
+        assert_eq!(decode("ünïcödé".as_bytes()).encoding, "utf-8");
+        assert_eq!(by_name("IBM437").unwrap().name(), "cp437");
+        assert_eq!(by_name("latin1").unwrap().name(), "windows-1252");
+    }
+}
@@ -0,0 +1,434 @@
+use crate::gopher::{check_reply, request_line, GopherURL, Menu};
+use crate::gopher_plus::ItemAttributes;
+use crate::metrics::Metrics;
+use crate::tls::{self, TlsMode};
+use anyhow::{anyhow, Context, Result};
+use smol::{
+    future::FutureExt,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    lock::{Mutex, Semaphore},
+    net::TcpStream,
+    Timer,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Max number of requests in flight, across all hosts
+    pub max_in_flight: usize,
+    /// Max number of requests in flight to a single host
+    pub max_in_flight_per_host: usize,
+    /// Min delay between two subsequent requests to the same host
+    pub host_delay: Duration,
+    /// Max delay a host can ask for in robots.txt
+    pub max_host_delay: Duration,
+    pub connect_timeout: Duration,
+    /// Timeout for every single read or write on the connection
+    pub read_timeout: Duration,
+    /// Longer replies are rejected, binary items can be huge
+    pub max_reply_size: usize,
+    /// When to use TLS for `gopher://` URLs
+    pub tls: TlsMode,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 64,
+            max_in_flight_per_host: 2,
+            host_delay: Duration::from_millis(500),
+            max_host_delay: Duration::from_secs(60),
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(5),
+            max_reply_size: 16 << 20,
+            tls: TlsMode::Off,
+        }
+    }
+}
+
+struct Host {
+    in_flight: Semaphore,
+    schedule: Mutex<Schedule>,
+}
+
+struct Schedule {
+    next_request: Instant,
+    delay: Duration,
+}
+
+/// Async counterpart of [`crate::gopher::fetch_url`].
+///
+/// Gopher servers close connection after every reply, so there is nothing
+/// to keep alive - instead client keeps pool of connection slots, bounded
+/// both globally and per host, and spaces out requests to the same host.
+pub struct Client {
+    opts: ClientOptions,
+    in_flight: Semaphore,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+    /// Whether TLS handshake succeeded last time, by (host, port)
+    tls_hosts: std::sync::Mutex<HashMap<(String, u16), bool>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Client {
+    pub fn new(opts: ClientOptions) -> Self {
+        Self {
+            in_flight: Semaphore::new(opts.max_in_flight),
+            hosts: Mutex::new(HashMap::new()),
+            tls_hosts: std::sync::Mutex::new(HashMap::new()),
+            metrics: Arc::default(),
+            opts,
+        }
+    }
+
+    /// Fetches whole reply, waiting for a free slot if needed
+    pub async fn fetch(&self, url: &GopherURL, query: Option<String>) -> Result<Vec<u8>> {
+        let host = self.host(&url.host).await;
+        let _host_permit = host.in_flight.acquire().await;
+        let start = {
+            let mut schedule = host.schedule.lock().await;
+            let start = Instant::now().max(schedule.next_request);
+            schedule.next_request = start + schedule.delay;
+            start
+        };
+        Timer::at(start).await;
+        let _permit = self.in_flight.acquire().await;
+
+        log::debug!("fetching {url}");
+        let started = Instant::now();
+        match self.request(url, query).await {
+            Ok(data) => {
+                self.metrics.record_fetch(started.elapsed(), data.len());
+                Ok(data)
+            }
+            Err(Failure { kind, error }) => {
+                let latency = started.elapsed();
+                self.metrics.record_error(latency, &url.host, kind.as_str());
+                Err(error)
+            }
+        }
+    }
+
+    /// Statistics of requests made by this client
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub async fn menu(&self, url: &GopherURL, query: Option<String>) -> Result<Menu> {
+        let reply = self
+            .fetch(url, query)
+            .await
+            .context(format!("fetching {url}"))?;
+        Ok(Menu::from_reader(reply.as_slice()))
+    }
+
+    /// Fetches gopher+ attributes of single item
+    pub async fn attributes(&self, url: &GopherURL) -> Result<ItemAttributes> {
+        let reply = self
+            .fetch(url, Some(String::from("!")))
+            .await
+            .context(format!("fetching attributes of {url}"))?;
+        Ok(ItemAttributes::parse(reply.as_slice()))
+    }
+
+    /// Fetches gopher+ attributes of all items in directory
+    pub async fn dir_attributes(&self, url: &GopherURL) -> Result<Vec<ItemAttributes>> {
+        let reply = self
+            .fetch(url, Some(String::from("$")))
+            .await
+            .context(format!("fetching directory attributes of {url}"))?;
+        Ok(ItemAttributes::parse_all(reply.as_slice()))
+    }
+
+    /// Sets delay between requests to the host, if it is longer than default one,
+    /// but no longer than max one
+    pub async fn set_host_delay(&self, host: &str, delay: Duration) {
+        let delay = delay
+            .min(self.opts.max_host_delay)
+            .max(self.opts.host_delay);
+        self.host(host).await.schedule.lock().await.delay = delay;
+    }
+
+    /// Whether the server spoke TLS last time it was tried, `None` if it wasn't
+    pub fn tls_support(&self, host: &str, port: u16) -> Option<bool> {
+        let hosts = self.tls_hosts.lock().unwrap();
+        hosts.get(&(host.to_lowercase(), port)).copied()
+    }
+
+    /// Sets known TLS support of the server, e.g. from previous crawl.
+    /// Servers known to support TLS are always connected to with TLS.
+    pub fn set_tls_support(&self, host: &str, port: u16, tls: bool) {
+        let mut hosts = self.tls_hosts.lock().unwrap();
+        hosts.insert((host.to_lowercase(), port), tls);
+    }
+
+    async fn host(&self, host: &str) -> Arc<Host> {
+        self.hosts
+            .lock()
+            .await
+            .entry(host.to_lowercase())
+            .or_insert_with(|| {
+                Arc::new(Host {
+                    in_flight: Semaphore::new(self.opts.max_in_flight_per_host),
+                    schedule: Mutex::new(Schedule {
+                        next_request: Instant::now(),
+                        delay: self.opts.host_delay,
+                    }),
+                })
+            })
+            .clone()
+    }
+
+    async fn request(
+        &self,
+        url: &GopherURL,
+        query: Option<String>,
+    ) -> std::result::Result<Vec<u8>, Failure> {
+        let addr = smol::net::resolve((url.host.as_str(), url.port))
+            .await
+            .context("resolving host")
+            .or_fail(ErrorKind::Dns)?
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("no address resolved"))
+            .or_fail(ErrorKind::Dns)?;
+        let connect = || async {
+            timeout(self.opts.connect_timeout, TcpStream::connect(addr))
+                .await
+                .context(format!("connecting to {addr}"))
+                .or_fail(ErrorKind::Connect)
+        };
+        let known = self.tls_support(&url.host, url.port);
+        let use_tls = url.tls
+            || known == Some(true)
+            || match self.opts.tls {
+                TlsMode::Off => false,
+                TlsMode::Opportunistic => known.is_none(),
+                TlsMode::Strict => true,
+            };
+        if !use_tls {
+            return self.exchange(connect().await?, url, query).await;
+        }
+
+        let connector = tls::connector(self.opts.tls == TlsMode::Strict);
+        let name = tls::server_name(&url.host).or_fail(ErrorKind::Tls)?;
+        let handshake = connector.connect(name, connect().await?);
+        match timeout(self.opts.connect_timeout, handshake).await {
+            Ok(stream) => {
+                self.set_tls_support(&url.host, url.port, true);
+                self.exchange(stream, url, query).await
+            }
+            Err(e) if !url.tls && self.opts.tls == TlsMode::Opportunistic => {
+                log::debug!("[tls] no TLS on {addr}, falling back to plain text: {e}");
+                let reply = self.exchange(connect().await?, url, query).await;
+                // handshake may have failed on timeout or flaky network,
+                // only a plain text answer tells the server doesn't speak TLS
+                let answered = match &reply {
+                    Ok(_) => true,
+                    Err(f) => matches!(f.kind, ErrorKind::ErrorItem | ErrorKind::TooLarge),
+                };
+                if answered {
+                    self.set_tls_support(&url.host, url.port, false);
+                }
+                reply
+            }
+            Err(e) => Err(e)
+                .context(format!("TLS handshake with {addr}"))
+                .or_fail(ErrorKind::Tls),
+        }
+    }
+
+    /// Sends request over connected stream and reads whole reply
+    async fn exchange(
+        &self,
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+        url: &GopherURL,
+        query: Option<String>,
+    ) -> std::result::Result<Vec<u8>, Failure> {
+        timeout(self.opts.read_timeout, async {
+            stream
+                .write_all(request_line(url, query).as_bytes())
+                .await?;
+            stream.flush().await
+        })
+        .await
+        .context(format!("querying {}:{}", url.host, url.port))
+        .or_fail(ErrorKind::Io)?;
+
+        let mut reply = Vec::new();
+        let mut buf = vec![0; 8192];
+        loop {
+            let n = match timeout(self.opts.read_timeout, stream.read(&mut buf)).await {
+                // TLS servers often close connection without close_notify
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+                n => n.context("reading gopher reply").or_fail(ErrorKind::Io)?,
+            };
+            if n == 0 {
+                break;
+            }
+            reply.extend_from_slice(&buf[0..n]);
+            if reply.len() > self.opts.max_reply_size {
+                return Err(anyhow!(
+                    "reply is longer than {} bytes",
+                    self.opts.max_reply_size
+                ))
+                .or_fail(ErrorKind::TooLarge);
+            }
+        }
+        check_reply(url, &reply[0..reply.len().min(256)]).or_fail(ErrorKind::ErrorItem)?;
+        Ok(reply)
+    }
+}
+
+/// What failed in [`Client::request`], for metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Dns,
+    Connect,
+    Tls,
+    Io,
+    Timeout,
+    TooLarge,
+    /// Server sent error item instead of what was asked for
+    ErrorItem,
+}
+
+impl ErrorKind {
+    /// Short name used as metrics label
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Dns => "dns",
+            ErrorKind::Connect => "connect",
+            ErrorKind::Tls => "tls",
+            ErrorKind::Io => "io",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::TooLarge => "too_large",
+            ErrorKind::ErrorItem => "error_item",
+        }
+    }
+}
+
+/// Error of [`Client::request`] along with its kind
+struct Failure {
+    kind: ErrorKind,
+    error: anyhow::Error,
+}
+
+trait OrFail<T> {
+    /// Tags error with its kind, timeouts are told apart by their io error
+    fn or_fail(self, kind: ErrorKind) -> std::result::Result<T, Failure>;
+}
+
+impl<T, E: Into<anyhow::Error>> OrFail<T> for std::result::Result<T, E> {
+    fn or_fail(self, kind: ErrorKind) -> std::result::Result<T, Failure> {
+        self.map_err(|e| {
+            let error = e.into();
+            let timed_out = error.chain().any(|cause| {
+                cause
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+            });
+            let kind = match timed_out {
+                true => ErrorKind::Timeout,
+                false => kind,
+            };
+            Failure { kind, error }
+        })
+    }
+}
+
+/// Fails with `TimedOut` if `f` isn't done in `d`
+pub(crate) async fn timeout<T>(
+    d: Duration,
+    f: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    f.or(async {
+        Timer::after(d).await;
+        Err(io::ErrorKind::TimedOut.into())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gopher::GopherItem;
+    use crate::testing::{MockGopherServer, Response};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn host_delay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut selector = String::new();
+                BufReader::new(&stream).read_line(&mut selector).unwrap();
+                write!(stream, "you asked for {}", selector.trim_end()).unwrap();
+            }
+        });
+
+        let client = Client::new(ClientOptions {
+            host_delay: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let url =
+            GopherURL::try_from(format!("gopher://127.0.0.1:{port}/0/hello").as_str()).unwrap();
+        let started = Instant::now();
+        let replies = smol::block_on(async {
+            let a = client.fetch(&url, None);
+            let b = client.fetch(&url, None);
+            let c = client.fetch(&url, None);
+            smol::future::zip(a, smol::future::zip(b, c)).await
+        });
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(replies.0.unwrap(), b"you asked for /hello");
+        assert!(replies.1 .0.is_ok());
+        assert!(replies.1 .1.is_ok());
+
+        // robots.txt can make delay longer, within limits
+        let delay = |d| {
+            smol::block_on(async {
+                client.set_host_delay("example.org", d).await;
+                client.host("example.org").await.schedule.lock().await.delay
+            })
+        };
+        assert_eq!(delay(Duration::from_secs(2)), Duration::from_secs(2));
+        assert_eq!(delay(Duration::ZERO), Duration::from_millis(100));
+        assert_eq!(
+            delay(Duration::from_secs(99999999)),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn timeouts_and_limits() {
+        let server = MockGopherServer::new().unwrap();
+        let reply = || Box::new(Response::text("0123456789"));
+        server.route("/slow", Response::Slow(Duration::from_millis(500), reply()));
+        server.route("/fast", *reply());
+        let client = Client::new(ClientOptions {
+            read_timeout: Duration::from_millis(100),
+            max_reply_size: 5,
+            host_delay: Duration::ZERO,
+            ..Default::default()
+        });
+        let slow = server.url(GopherItem::TextFile, "/slow");
+        assert!(smol::block_on(client.fetch(&slow, None)).is_err());
+        let fast = server.url(GopherItem::TextFile, "/fast");
+        let e = smol::block_on(client.fetch(&fast, None)).unwrap_err();
+        assert!(e.to_string().contains("longer than 5 bytes"), "{e}");
+        let errors = client.metrics().snapshot().errors;
+        let kinds: Vec<&str> = errors.keys().map(|(_, kind)| *kind).collect();
+        assert_eq!(kinds, ["timeout", "too_large"]);
+    }
+}
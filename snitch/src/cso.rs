@@ -0,0 +1,156 @@
+//! Client for CSO phonebook (ph) servers, linked from type 2 menu items.
+//!
+//! Request is a `query` command, reply lines look like
+//! `-200:1:  email: someone@example.edu`: code, negative if more lines
+//! follow, entry number and field. Codes from 400 up are errors.
+
+use anyhow::{anyhow, Context, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Phonebook entry, fields in the order server sent them
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub fields: Vec<(String, String)>,
+}
+
+impl Entry {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Looks up entries matching `query`, like `name=smith` or just `smith`
+pub fn query(host: &str, port: u16, query: &str) -> Result<Vec<Entry>> {
+    let addr = (host, port)
+        .to_socket_addrs()
+        .context("resolving host")?
+        .next()
+        .ok_or(anyhow!("no address resolved"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))
+        .context(format!("connecting to {addr}"))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let query = query.replace(['\r', '\n'], " ");
+    write!(stream, "query {query} return all\r\nquit\r\n")
+        .and_then(|_| stream.flush())
+        .context(format!("querying {addr}"))?;
+
+    // server closes connection after quit
+    let mut reply = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line.context("reading CSO reply")?;
+        let last = !line.starts_with('-') && status(&line).is_some_and(|code| code >= 200);
+        reply.push(line);
+        if last {
+            break;
+        }
+    }
+    parse_reply(&reply.join("\n"))
+}
+
+/// Collects entries from reply to a single query
+pub fn parse_reply(text: &str) -> Result<Vec<Entry>> {
+    let mut entries: Vec<(String, Entry)> = Vec::new();
+    for line in text.lines() {
+        let Some(code) = status(line) else {
+            continue;
+        };
+        let rest = line.split_once(':').map_or("", |(_, rest)| rest);
+        match code {
+            // "no matches" is not an error
+            501 => return Ok(Vec::new()),
+            400.. => return Err(anyhow!("CSO server error {code}: {}", rest.trim())),
+            200..=299 if line.starts_with('-') => {
+                let Some((index, field)) = rest.split_once(':') else {
+                    continue;
+                };
+                let entry = match entries.last_mut() {
+                    Some((i, entry)) if i == index => entry,
+                    _ => {
+                        entries.push((String::from(index), Entry::default()));
+                        &mut entries.last_mut().unwrap().1
+                    }
+                };
+                let (name, value) = field.split_once(':').unwrap_or(("", field));
+                match (name.trim(), entry.fields.last_mut()) {
+                    // long values continue on lines without field name
+                    ("", Some((_, v))) => {
+                        v.push('\n');
+                        v.push_str(value.trim());
+                    }
+                    (name, _) => entry
+                        .fields
+                        .push((String::from(name), String::from(value.trim()))),
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// Reply code of the line, without sign of continuation lines
+fn status(line: &str) -> Option<u16> {
+    line.trim_start_matches('-')
+        .split(':')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const REPLY: &str = "102:There were 2 matches to your request.\r\n\
+        -200:1:         name: Smith, John\r\n\
+        -200:1:        email: jsmith@example.edu\r\n\
+        -200:1:      address: 1 Main St.\r\n\
+        -200:1:             : Springfield\r\n\
+        -200:2:         name: Smith, Jane\r\n\
+        200:Ok.\r\n";
+
+    #[test]
+    fn querying() {
+        let entries = parse_reply(REPLY).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].get("email"), Some("jsmith@example.edu"));
+        assert_eq!(entries[0].get("address"), Some("1 Main St.\nSpringfield"));
+        assert_eq!(
+            entries[1].fields,
+            vec![(String::from("name"), String::from("Smith, Jane"))]
+        );
+        assert!(parse_reply("501:No matches to your query.\r\n")
+            .unwrap()
+            .is_empty());
+        let e = parse_reply("598:name=:Command not recognized.\r\n").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "CSO server error 598: name=:Command not recognized."
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            BufReader::new(&stream).read_line(&mut request).unwrap();
+            stream.write_all(REPLY.as_bytes()).unwrap();
+            request
+        });
+        let entries = query("127.0.0.1", port, "name=smith\r\nquit").unwrap();
+        assert_eq!(entries[1].get("name"), Some("Smith, Jane"));
+        assert_eq!(
+            server.join().unwrap(),
+            "query name=smith  quit return all\r\n"
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! Near-duplicate detection, so mirrors and link loops don't fill up the index.
+//!
+//! Every page text gets a 64-bit simhash of its word shingles. Texts that
+//! differ in a few words have hashes that differ in a few bits, so pages
+//! within [`Dedup::max_distance`] bits of an already indexed page are
+//! duplicates. To avoid comparing with every page, hashes are split into
+//! bands: with distance below the number of bands, at least one band of
+//! a duplicate is equal to the original's one.
+
+use crate::index::add_column;
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+
+const BANDS: u32 = 8;
+const BAND_BITS: u32 = 64 / BANDS;
+
+/// Words per shingle
+const SHINGLE: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct Dedup {
+    /// Max number of different simhash bits of duplicates, less than 8
+    pub max_distance: u32,
+    /// Shorter texts are never duplicates, their hashes are too noisy
+    pub min_words: usize,
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Self {
+            max_distance: 6,
+            min_words: 50,
+        }
+    }
+}
+
+/// Adds simhash columns and band index
+pub fn init(conn: &Connection) -> Result<()> {
+    add_column(conn, "pages", "simhash", "INTEGER")?;
+    add_column(conn, "pages", "duplicate_of", "TEXT")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS simhash_bands(
+            band INTEGER NOT NULL, value INTEGER NOT NULL, url TEXT NOT NULL,
+            PRIMARY KEY(band, url))",
+        (),
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS simhash_bands_value ON simhash_bands(band, value)",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Url of the page this one duplicates, as found by last [`Dedup::check`]
+pub fn duplicate_of(conn: &Connection, url: &str) -> Result<Option<String>> {
+    Ok(conn
+        .query_row(
+            "SELECT duplicate_of FROM pages WHERE url = ?1",
+            [url],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten())
+}
+
+impl Dedup {
+    /// Stores simhash of page text and looks for a page it duplicates.
+    /// Returns url of the original page, which is also stored in `duplicate_of`.
+    pub fn check(&self, conn: &Connection, url: &str, text: &str) -> Result<Option<String>> {
+        conn.execute("DELETE FROM simhash_bands WHERE url = ?1", [url])?;
+        let Some(hash) = simhash(text, self.min_words) else {
+            conn.execute(
+                "UPDATE pages SET simhash = NULL, duplicate_of = NULL WHERE url = ?1",
+                [url],
+            )?;
+            return Ok(None);
+        };
+
+        let mut original = None;
+        let mut stmt = conn.prepare(
+            "SELECT pages.url, pages.simhash FROM simhash_bands
+             JOIN pages ON pages.url = simhash_bands.url
+             WHERE band = ?1 AND value = ?2 AND pages.url != ?3",
+        )?;
+        for (band, value) in bands(hash) {
+            let mut rows = stmt.query(params![band, value, url])?;
+            while let Some(row) = rows.next()? {
+                let other: i64 = row.get(1)?;
+                if (hash ^ other as u64).count_ones() <= self.max_distance {
+                    original = Some(row.get::<_, String>(0)?);
+                    break;
+                }
+            }
+            if original.is_some() {
+                break;
+            }
+        }
+
+        conn.execute(
+            "UPDATE pages SET simhash = ?2, duplicate_of = ?3 WHERE url = ?1",
+            params![url, hash as i64, original],
+        )?;
+        // only originals are matched against, so chains of
+        // slightly changing copies don't drift away from them
+        if original.is_none() {
+            for (band, value) in bands(hash) {
+                conn.execute(
+                    "INSERT INTO simhash_bands (band, value, url) VALUES (?1, ?2, ?3)",
+                    params![band, value, url],
+                )?;
+            }
+        }
+        Ok(original)
+    }
+}
+
+fn bands(hash: u64) -> impl Iterator<Item = (u32, i64)> {
+    (0..BANDS).map(move |i| {
+        (
+            i,
+            ((hash >> (i * BAND_BITS)) & ((1 << BAND_BITS) - 1)) as i64,
+        )
+    })
+}
+
+/// Simhash of word shingles, `None` for texts shorter than `min_words`
+pub fn simhash(text: &str, min_words: usize) -> Option<u64> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.len() < min_words.max(SHINGLE) {
+        return None;
+    }
+    let mut weights = [0i64; 64];
+    for shingle in words.windows(SHINGLE) {
+        let h = fnv1a(shingle);
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += if h >> bit & 1 == 1 { 1 } else { -1 };
+        }
+    }
+    Some(
+        weights
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| **w > 0)
+            .fold(0, |hash, (bit, _)| hash | 1 << bit),
+    )
+}
+
+/// FNV-1a, unlike std hashers it is stable across Rust versions,
+/// and hashes are stored in DB
+fn fnv1a(words: &[String]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for word in words {
+        for b in word.bytes().chain([b' ']) {
+            h ^= b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::init_db;
+
+    #[test]
+    fn detecting_duplicates() {
+        let text = "Gopher is a protocol for distributing, searching and retrieving \
+            documents over the Internet. It was designed in 1991 at the University \
+            of Minnesota and its menus of links were popular before the Web took over. \
+            A gopher server answers every request with a single reply and closes the \
+            connection, there are no headers, cookies or scripts. Menus list items \
+            with a type, a label, a selector, a host and a port, so a client needs \
+            little code to browse them. Many people still run gopher holes today, \
+            writing phlogs, sharing text files and keeping archives of old software, \
+            and search engines like Veronica index the whole gopherspace every week.";
+        let mirrored = format!("{text} Mirrored from gopher.floodgap.com");
+        let other = "Phlogs are the gopher counterpart of blogs, plain text entries \
+            listed in a menu by date, often written by people who like old computers \
+            and keep their holes running on tiny machines at home for years. Entries \
+            are rarely long, there are no comments, and readers answer by writing \
+            entries in their own phlogs, linking back to the original post. Some \
+            phlogs have run for more than a decade, and aggregators collect new \
+            entries from hundreds of holes into a single menu updated every hour, \
+            which is how most readers find them now.";
+        let distance = |a, b| (simhash(a, 50).unwrap() ^ simhash(b, 50).unwrap()).count_ones();
+        assert!(distance(text, &mirrored) <= 6);
+        assert!(distance(text, other) > 10);
+        assert_eq!(simhash("too short", 50), None);
+
+        let conn = init_db(":memory:").unwrap();
+        for url in ["a", "b", "c", "d"] {
+            conn.execute("INSERT INTO pages (url) VALUES (?1)", [url])
+                .unwrap();
+        }
+        let dedup = Dedup::default();
+        assert_eq!(dedup.check(&conn, "a", text).unwrap(), None);
+        assert_eq!(
+            dedup.check(&conn, "b", &mirrored).unwrap().as_deref(),
+            Some("a")
+        );
+        assert_eq!(dedup.check(&conn, "c", other).unwrap(), None);
+        assert_eq!(duplicate_of(&conn, "b").unwrap().as_deref(), Some("a"));
+        // rechecking the original doesn't match itself
+        assert_eq!(dedup.check(&conn, "a", text).unwrap(), None);
+        assert_eq!(dedup.check(&conn, "b", "short now").unwrap(), None);
+        assert_eq!(duplicate_of(&conn, "b").unwrap(), None);
+        assert_eq!(duplicate_of(&conn, "d").unwrap(), None);
+    }
+}
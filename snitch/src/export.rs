@@ -0,0 +1,410 @@
+//! Exports crawled pages as WARC file or as static gopher mirror
+
+use crate::charset;
+use crate::extract::sha256_hex;
+use crate::frontier::now;
+use crate::gopher::{GopherItem, GopherURL, Menu};
+use crate::index::add_column;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Creates table of raw replies
+pub fn init(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS replies(url TEXT PRIMARY KEY, data BLOB NOT NULL)",
+        (),
+    )?;
+    // url the reply was fetched by, NULL when it is the canonical one
+    add_column(conn, "replies", "listed_url", "TEXT")?;
+    Ok(())
+}
+
+/// Stores raw reply of the page fetched by `url`, keyed by canonical url like pages.
+/// Unless `replace` is set, existing reply is kept, so unchanged pages are only
+/// written once.
+pub fn store_reply(conn: &Connection, url: &GopherURL, data: &[u8], replace: bool) -> Result<()> {
+    let sql = match replace {
+        true => "INSERT OR REPLACE INTO replies (url, listed_url, data) VALUES (?1, ?2, ?3)",
+        false => "INSERT OR IGNORE INTO replies (url, listed_url, data) VALUES (?1, ?2, ?3)",
+    };
+    conn.execute(
+        sql,
+        params![url.canonical().to_string(), url.to_string(), data],
+    )?;
+    Ok(())
+}
+
+/// Number of pages with stored reply, there are none unless spider keeps them
+pub fn reply_count(conn: &Connection) -> Result<usize> {
+    Ok(conn.query_row("SELECT count(*) FROM replies", (), |row| row.get(0))?)
+}
+
+/// Page with stored reply
+struct Page {
+    url: GopherURL,
+    /// Url the reply was fetched by
+    listed: GopherURL,
+    data: Vec<u8>,
+    /// Unix time of last fetch
+    fetched: u64,
+    mime: Option<String>,
+}
+
+/// Calls `f` for every page with stored reply, optionally only for one host
+fn for_each_page(
+    conn: &Connection,
+    host: Option<&str>,
+    mut f: impl FnMut(Page) -> Result<()>,
+) -> Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT replies.url, replies.data, coalesce(pages.last_fetched, 0), pages.mime,
+                coalesce(replies.listed_url, replies.url)
+         FROM replies JOIN pages USING(url) ORDER BY replies.url",
+    )?;
+    let mut rows = stmt.query(())?;
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        let url: String = row.get(0)?;
+        let Ok(url) = GopherURL::try_from(url.as_str()) else {
+            log::warn!("[export] skipping invalid url {url}");
+            continue;
+        };
+        if host.is_some_and(|h| !h.eq_ignore_ascii_case(&url.host)) {
+            continue;
+        }
+        let listed: String = row.get(4)?;
+        f(Page {
+            listed: GopherURL::try_from(listed.as_str()).unwrap_or_else(|_| url.clone()),
+            url,
+            data: row.get(1)?,
+            fetched: row.get(2)?,
+            mime: row.get(3)?,
+        })?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Writes pages as WARC 1.1 records, a request and a response for every page,
+/// returns number of pages written. Record ids are derived from record
+/// content, so exports of the same crawl are identical.
+pub fn warc(conn: &Connection, mut out: impl Write, host: Option<&str>) -> Result<usize> {
+    let info = format!(
+        "software: snitch/{}\r\nformat: WARC File Format 1.1\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    let now = now();
+    write_record(
+        &mut out,
+        &[
+            ("WARC-Type", "warcinfo"),
+            ("WARC-Record-ID", &record_id(&["warcinfo", &info])),
+            ("WARC-Date", &iso8601(now)),
+            ("Content-Type", "application/warc-fields"),
+        ],
+        info.as_bytes(),
+    )?;
+    let count = for_each_page(conn, host, |page| {
+        // records show what was actually asked for
+        let url = page.listed.to_string();
+        let date = iso8601(page.fetched);
+        let request = crate::gopher::request_line(&page.listed, None);
+        let request_id = record_id(&["request", &url, &date]);
+        let response_id = record_id(&["response", &url, &date]);
+        let mime = match page.url.gopher_type {
+            GopherItem::Submenu => "application/gopher-menu",
+            _ => page.mime.as_deref().unwrap_or("application/octet-stream"),
+        };
+        write_record(
+            &mut out,
+            &[
+                ("WARC-Type", "request"),
+                ("WARC-Record-ID", &request_id),
+                ("WARC-Date", &date),
+                ("WARC-Target-URI", &url),
+                ("WARC-Concurrent-To", &response_id),
+                ("Content-Type", "text/plain"),
+            ],
+            request.as_bytes(),
+        )?;
+        write_record(
+            &mut out,
+            &[
+                ("WARC-Type", "response"),
+                ("WARC-Record-ID", &response_id),
+                ("WARC-Date", &date),
+                ("WARC-Target-URI", &url),
+                (
+                    "WARC-Block-Digest",
+                    &format!("sha256:{}", sha256_hex(&page.data)),
+                ),
+                ("Content-Type", mime),
+            ],
+            &page.data,
+        )
+    })?;
+    out.flush()?;
+    Ok(count)
+}
+
+fn write_record(out: &mut impl Write, headers: &[(&str, &str)], block: &[u8]) -> Result<()> {
+    write!(out, "WARC/1.1\r\n")?;
+    for (name, value) in headers {
+        write!(out, "{name}: {value}\r\n")?;
+    }
+    write!(out, "Content-Length: {}\r\n\r\n", block.len())?;
+    out.write_all(block)?;
+    write!(out, "\r\n\r\n")?;
+    Ok(())
+}
+
+/// UUID URN made of hash of given parts
+fn record_id(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    let mut b = hasher.finalize()[0..16].to_vec();
+    // version 8, i.e. custom, and RFC 4122 variant
+    b[6] = (b[6] & 0x0f) | 0x80;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let h: String = b.iter().map(|x| format!("{x:02x}")).collect();
+    format!(
+        "<urn:uuid:{}-{}-{}-{}-{}>",
+        &h[0..8],
+        &h[8..12],
+        &h[12..16],
+        &h[16..20],
+        &h[20..32]
+    )
+}
+
+/// Formats unix time as UTC timestamp like `2024-01-31T12:00:00Z`
+fn iso8601(secs: u64) -> String {
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// Writes pages as directory tree that any gopher server with gophermap
+/// support can serve. Every host gets its own directory, menus become
+/// gophermaps with links to mirrored items made local, and root gophermap
+/// lists the hosts. Returns number of pages written.
+pub fn mirror(conn: &Connection, dir: &Path, host: Option<&str>) -> Result<usize> {
+    let mut mirrored = HashSet::new();
+    conn.prepare("SELECT url FROM replies")?
+        .query_map((), |row| row.get::<_, String>(0))?
+        .filter_map(|url| GopherURL::try_from(url.ok()?.as_str()).ok())
+        .filter(|url| host.is_none_or(|h| h.eq_ignore_ascii_case(&url.host)))
+        .for_each(|url| {
+            mirrored.insert(url);
+        });
+
+    let mut hosts = Vec::new();
+    let mut written = HashSet::new();
+    let count = for_each_page(conn, host, |page| {
+        let (file, data) = match page.url.gopher_type {
+            GopherItem::Submenu => (
+                mirror_path(&page.url).join("gophermap"),
+                gophermap(&page.data, &mirrored).into_bytes(),
+            ),
+            _ => (mirror_path(&page.url), page.data),
+        };
+        // selectors of different items may map to the same path,
+        // like text file /a and menu /a/, first one wins
+        if !written.insert(file.clone()) {
+            log::warn!(
+                "[export] skipping {}, {} is taken",
+                page.url,
+                file.display()
+            );
+            return Ok(());
+        }
+        let path = dir.join(&file);
+        if let Err(e) = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, data))
+        {
+            log::warn!("[export] skipping {}: {e}", page.url);
+            return Ok(());
+        }
+        log::debug!("[export] {} -> {}", page.url, file.display());
+        if let Some(host) = file.iter().next() {
+            if !hosts.iter().any(|h| h == host) {
+                hosts.push(host.to_os_string());
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut root = String::new();
+    for host in &hosts {
+        root.push_str(&format!("1{0}\t/{0}\r\n", host.to_string_lossy()));
+    }
+    std::fs::write(dir.join("gophermap"), root).context("writing root gophermap")?;
+    Ok(count)
+}
+
+/// Path of item relative to mirror root, menus are directories
+fn mirror_path(url: &GopherURL) -> PathBuf {
+    let mut path = PathBuf::from(format!("{}_{}", url.host.to_lowercase(), url.port));
+    for part in url.selector.split('/') {
+        if part.is_empty() || part == "." || part == ".." {
+            continue;
+        }
+        let part: String = part
+            .chars()
+            .map(|c| match c {
+                c if c.is_alphanumeric() || "-_.,+~@".contains(c) => c,
+                _ => '_',
+            })
+            .collect();
+        path.push(part);
+    }
+    if url.gopher_type != GopherItem::Submenu && path.components().count() == 1 {
+        path.push("index");
+    }
+    path
+}
+
+/// Rewrites menu as gophermap, mirrored items get local selectors and no
+/// host, which gopher servers fill in with their own
+fn gophermap(data: &[u8], mirrored: &HashSet<GopherURL>) -> String {
+    let menu = Menu::parse(&charset::decode(data).text);
+    let mut map = String::new();
+    for item in &menu.items {
+        // pages are stored under canonical URLs
+        match item.url.as_ref().map(GopherURL::canonical) {
+            Some(url) if mirrored.contains(&url) => {
+                let selector = mirror_path(&url)
+                    .iter()
+                    .map(|x| x.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                map.push_str(&format!(
+                    "{}{}\t/{selector}\r\n",
+                    item.item_type, item.label
+                ));
+            }
+            _ => map.push_str(&format!("{item}\r\n")),
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientOptions;
+    use crate::index::init_db;
+    use crate::spider::Spider;
+    use crate::testing::{MockGopherServer, Response};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn exporting() {
+        let server = MockGopherServer::new().unwrap();
+        server.route(
+            "",
+            server.menu(&[
+                (GopherItem::Info, "Welcome", ""),
+                (GopherItem::Submenu, "Phlog", "/phlog/"),
+                (GopherItem::TextFile, "About", "/about.txt"),
+            ]),
+        );
+        server.route(
+            "/phlog/",
+            server.menu(&[(GopherItem::TextFile, "Hello", "/phlog/hello world.txt")]),
+        );
+        server.route("/about.txt", Response::text("about this hole"));
+        server.route("/phlog/hello world.txt", Response::text("hello, world"));
+        let spider = Spider {
+            client: ClientOptions {
+                host_delay: Duration::ZERO,
+                ..ClientOptions::default()
+            },
+            ignore_robots: true,
+            keep_replies: true,
+            threads: 1,
+            ..Spider::default()
+        };
+        let seed = server.url(GopherItem::Submenu, "");
+        let db = std::env::temp_dir().join(format!("snitch-export-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db);
+        let db = db.to_str().unwrap();
+        spider
+            .run(init_db(db).unwrap(), &[seed], Arc::default())
+            .unwrap();
+        let conn = init_db(db).unwrap();
+        assert_eq!(reply_count(&conn).unwrap(), 4);
+        assert_eq!(reply_count(&init_db(":memory:").unwrap()).unwrap(), 0);
+
+        let mut out = Vec::new();
+        assert_eq!(warc(&conn, &mut out, None).unwrap(), 4);
+        let text = String::from_utf8_lossy(&out);
+        assert_eq!(text.matches("WARC/1.1\r\n").count(), 9);
+        assert_eq!(text.matches("WARC-Type: response\r\n").count(), 4);
+        let about = server.url(GopherItem::TextFile, "/about.txt");
+        assert!(text.contains(&format!("WARC-Target-URI: {about}\r\nWARC-Concurrent-To")));
+        assert!(text.contains("Content-Length: 15\r\n\r\nabout this hole\r\n\r\n"));
+        assert!(text.contains("Content-Length: 12\r\n\r\n/about.txt\r\n\r\n\r\n"));
+        // phlog is stored as /phlog, but was asked for as listed
+        let phlog = server.url(GopherItem::Submenu, "/phlog/");
+        assert!(text.contains(&format!("WARC-Target-URI: {phlog}\r\n")));
+        assert!(text.contains("Content-Length: 9\r\n\r\n/phlog/\r\n\r\n\r\n"));
+        let mut again = Vec::new();
+        warc(&conn, &mut again, None).unwrap();
+        // only warcinfo date may differ
+        assert_eq!(out.len(), again.len());
+        assert_eq!(warc(&conn, &mut again, Some("example.org")).unwrap(), 0);
+
+        let dir = std::env::temp_dir().join(format!("snitch-mirror-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(mirror(&conn, &dir, None).unwrap(), 4);
+        let host = format!("127.0.0.1_{}", server.port());
+        assert_eq!(
+            std::fs::read_to_string(dir.join(&host).join("phlog/hello_world.txt")).unwrap(),
+            "hello, world"
+        );
+        let mirror = MockGopherServer::with_dir(&dir).unwrap();
+        let root = Menu::from_url(&mirror.url(GopherItem::Submenu, ""), None).unwrap();
+        assert_eq!(root.items[0].label, host);
+        let home = Menu::from_url(root.items[0].url.as_ref().unwrap(), None).unwrap();
+        assert_eq!(home.items[0].label, "Welcome");
+        let phlog = home.items[1].url.as_ref().unwrap();
+        assert_eq!(phlog.port, mirror.port());
+        assert_eq!(phlog.selector, format!("/{host}/phlog"));
+        let phlog = Menu::from_url(phlog, None).unwrap();
+        let hello = phlog.items[0].url.as_ref().unwrap();
+        assert_eq!(hello.selector, format!("/{host}/phlog/hello_world.txt"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn formatting_dates() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(iso8601(1792286442), "2026-10-18T01:20:42Z");
+    }
+}
@@ -0,0 +1,648 @@
+use crate::charset;
+use crate::gopher::GopherItem;
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// What extractor got out of the item
+#[derive(Debug, Default)]
+pub struct Content {
+    /// Text to put into full-text index
+    pub text: Option<String>,
+    /// Decoded file, for encoded items like uuencode or BinHex
+    pub payload: Option<Vec<u8>>,
+    /// Detected character encoding of text items
+    pub encoding: Option<&'static str>,
+}
+
+/// Extracts content from items of some type
+pub trait Extractor: Send + Sync {
+    fn extract(&self, data: &[u8]) -> Result<Content>;
+}
+
+/// Extracted content along with metadata of the item.
+/// For encoded items metadata describes decoded payload.
+#[derive(Debug)]
+pub struct Extracted {
+    pub text: Option<String>,
+    pub size: usize,
+    pub sha256: String,
+    pub mime: &'static str,
+    pub encoding: Option<&'static str>,
+}
+
+/// Extractors by item type, items without extractor are not fetched at all
+pub struct Extractors(HashMap<GopherItem, Box<dyn Extractor>>);
+
+impl Default for Extractors {
+    fn default() -> Self {
+        let mut e = Self(HashMap::new());
+        e.register(GopherItem::TextFile, PlainText);
+        e.register(GopherItem::HtmlFile, Markup { html: true });
+        e.register(GopherItem::XmlFile, Markup { html: false });
+        e.register(GopherItem::RtfFile, Rtf);
+        e.register(GopherItem::UuencodeFile, Uuencode);
+        e.register(GopherItem::BinHex, BinHex::default());
+        for item in [
+            GopherItem::Dos,
+            GopherItem::BinaryFile,
+            GopherItem::GifFile,
+            GopherItem::ImageFile,
+            GopherItem::BitmapFile,
+            GopherItem::MovieFile,
+            GopherItem::SoundFile,
+            GopherItem::DocFile,
+            GopherItem::PngFile,
+            GopherItem::WavFile,
+            GopherItem::PdfFile,
+        ] {
+            e.register(item, Binary);
+        }
+        e
+    }
+}
+
+impl Extractors {
+    pub fn register(&mut self, item: GopherItem, extractor: impl Extractor + 'static) {
+        self.0.insert(item, Box::new(extractor));
+    }
+
+    pub fn handles(&self, item: GopherItem) -> bool {
+        self.0.contains_key(&item)
+    }
+
+    pub fn extract(&self, item: GopherItem, data: &[u8]) -> Result<Extracted> {
+        let extractor = self
+            .0
+            .get(&item)
+            .ok_or(anyhow!("no extractor for item type {item}"))?;
+        let content = extractor.extract(data)?;
+        let payload = content.payload.as_deref().unwrap_or(data);
+        Ok(Extracted {
+            size: payload.len(),
+            sha256: sha256_hex(payload),
+            mime: sniff_mime(payload),
+            text: content.text,
+            encoding: content.encoding,
+        })
+    }
+}
+
+/// Hex encoded SHA-256 digest of data
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+/// Guesses MIME type by magic numbers
+pub fn sniff_mime(data: &[u8]) -> &'static str {
+    const MAGIC: &[(usize, &[u8], &str)] = &[
+        (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+        (0, b"GIF87a", "image/gif"),
+        (0, b"GIF89a", "image/gif"),
+        (0, b"\xff\xd8\xff", "image/jpeg"),
+        (0, b"BM", "image/bmp"),
+        (0, b"%PDF-", "application/pdf"),
+        (0, b"%!PS", "application/postscript"),
+        (0, b"{\\rtf", "application/rtf"),
+        (0, b"PK\x03\x04", "application/zip"),
+        (0, b"\x1f\x8b", "application/gzip"),
+        (0, b"BZh", "application/x-bzip2"),
+        (0, b"\xfd7zXZ\x00", "application/x-xz"),
+        (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (0, b"Rar!\x1a\x07", "application/vnd.rar"),
+        (257, b"ustar", "application/x-tar"),
+        (0, b"\x7fELF", "application/x-executable"),
+        (0, b"MZ", "application/x-msdownload"),
+        (0, b"OggS", "audio/ogg"),
+        (0, b"fLaC", "audio/flac"),
+        (0, b"ID3", "audio/mpeg"),
+        (0, b"\xff\xfb", "audio/mpeg"),
+        (8, b"WAVE", "audio/wav"),
+        (8, b"AVI ", "video/x-msvideo"),
+        (4, b"ftyp", "video/mp4"),
+        (0, b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1", "application/msword"),
+    ];
+    if let Some((_, _, mime)) = MAGIC
+        .iter()
+        .find(|(offset, magic, _)| data.get(*offset..offset + magic.len()) == Some(magic))
+    {
+        return mime;
+    }
+
+    let head = String::from_utf8_lossy(&data[0..data.len().min(512)]).to_lowercase();
+    let head = head.trim_start();
+    if head.starts_with("<!doctype html") || head.starts_with("<html") {
+        "text/html"
+    } else if head.starts_with("<?xml") {
+        "application/xml"
+    } else if !data.contains(&0) && std::str::from_utf8(data).is_ok() {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+pub struct PlainText;
+
+impl Extractor for PlainText {
+    fn extract(&self, data: &[u8]) -> Result<Content> {
+        let decoded = charset::decode(data);
+        Ok(Content {
+            text: Some(decoded.text),
+            payload: None,
+            encoding: Some(decoded.encoding),
+        })
+    }
+}
+
+/// Binaries have nothing to index, only metadata is recorded
+pub struct Binary;
+
+impl Extractor for Binary {
+    fn extract(&self, _data: &[u8]) -> Result<Content> {
+        Ok(Content::default())
+    }
+}
+
+/// Strips tags from HTML or XML
+pub struct Markup {
+    /// Skip contents of script and style elements, break lines on block elements
+    pub html: bool,
+}
+
+impl Extractor for Markup {
+    fn extract(&self, data: &[u8]) -> Result<Content> {
+        let decoded = charset::decode(data);
+        Ok(Content {
+            text: Some(strip_markup(&decoded.text, self.html)),
+            payload: None,
+            encoding: Some(decoded.encoding),
+        })
+    }
+}
+
+fn strip_markup(s: &str, html: bool) -> String {
+    const BLOCKS: &[&str] = &[
+        "p",
+        "br",
+        "div",
+        "li",
+        "tr",
+        "td",
+        "th",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "pre",
+        "blockquote",
+        "title",
+        "hr",
+    ];
+    let mut text = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&decode_entities(&rest[..start]));
+        rest = &rest[start..];
+        if let Some(r) = rest.strip_prefix("<!--") {
+            rest = r.find("-->").map_or("", |end| &r[end + 3..]);
+            continue;
+        }
+        if let Some(r) = rest.strip_prefix("<![CDATA[") {
+            let end = r.find("]]>").unwrap_or(r.len());
+            text.push_str(&r[..end]);
+            rest = r.get(end + 3..).unwrap_or("");
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let closing = rest[1..end].starts_with('/');
+        let tag = rest[1..end].trim_start_matches('/').to_lowercase();
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        rest = &rest[end + 1..];
+        if html && !closing && (name == "script" || name == "style") {
+            let close = format!("</{name}");
+            rest = match rest.to_ascii_lowercase().find(&close) {
+                Some(i) => &rest[i..],
+                None => "",
+            };
+        } else if html && BLOCKS.contains(&name) {
+            text.push('\n');
+        } else {
+            text.push(' ');
+        }
+    }
+    text.push_str(&decode_entities(rest));
+
+    text.lines()
+        .map(|l| l.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn decode_entities(s: &str) -> String {
+    let mut text = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let decoded = entity.and_then(|e| match e {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            e => e
+                .strip_prefix("#x")
+                .or(e.strip_prefix("#X"))
+                .map(|x| u32::from_str_radix(x, 16))
+                .or(e.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        });
+        match (entity, decoded) {
+            (Some(entity), Some(c)) => {
+                text.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                text.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+/// Extracts plain text from RTF, skipping font tables, pictures and such
+pub struct Rtf;
+
+impl Extractor for Rtf {
+    fn extract(&self, data: &[u8]) -> Result<Content> {
+        Ok(Content {
+            text: Some(rtf_to_text(data)),
+            payload: None,
+            encoding: None,
+        })
+    }
+}
+
+fn rtf_to_text(data: &[u8]) -> String {
+    const SKIP: &[&str] = &[
+        "fonttbl",
+        "colortbl",
+        "stylesheet",
+        "info",
+        "pict",
+        "header",
+        "footer",
+        "object",
+    ];
+    let mut text = String::new();
+    // whether current group is skipped, for every nesting level
+    let mut groups = vec![false];
+    let mut skip_chars = 0;
+    let mut i = 0;
+    let skipping = |groups: &Vec<bool>| groups.last().copied().unwrap_or(false);
+
+    while i < data.len() {
+        let c = data[i];
+        i += 1;
+        match c {
+            b'{' => groups.push(skipping(&groups)),
+            b'}' => {
+                groups.pop();
+            }
+            b'\r' | b'\n' => {}
+            b'\\' => {
+                let Some(&next) = data.get(i) else {
+                    break;
+                };
+                i += 1;
+                if next.is_ascii_alphabetic() {
+                    let start = i - 1;
+                    while i < data.len() && data[i].is_ascii_alphabetic() {
+                        i += 1;
+                    }
+                    let word = std::str::from_utf8(&data[start..i]).unwrap_or_default();
+                    let param_start = i;
+                    if i < data.len() && data[i] == b'-' {
+                        i += 1;
+                    }
+                    while i < data.len() && data[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                    let param: Option<i32> = std::str::from_utf8(&data[param_start..i])
+                        .ok()
+                        .and_then(|p| p.parse().ok());
+                    if i < data.len() && data[i] == b' ' {
+                        i += 1;
+                    }
+                    let skip = skipping(&groups);
+                    match word {
+                        w if SKIP.contains(&w) => {
+                            if let Some(g) = groups.last_mut() {
+                                *g = true;
+                            }
+                        }
+                        "par" | "line" | "row" if !skip => text.push('\n'),
+                        "tab" | "cell" if !skip => text.push('\t'),
+                        "u" if !skip => {
+                            let code = param.unwrap_or_default() as u16 as u32;
+                            text.extend(char::from_u32(code));
+                            // unicode char is followed by its ANSI replacement
+                            skip_chars = 1;
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                match next {
+                    b'*' => {
+                        if let Some(g) = groups.last_mut() {
+                            *g = true;
+                        }
+                    }
+                    b'\'' => {
+                        let hex = data.get(i..i + 2).unwrap_or_default();
+                        i += hex.len();
+                        let byte = std::str::from_utf8(hex)
+                            .ok()
+                            .and_then(|h| u8::from_str_radix(h, 16).ok());
+                        if let (Some(b), false) = (byte, skipping(&groups)) {
+                            if skip_chars > 0 {
+                                skip_chars -= 1;
+                            } else {
+                                // ANSI code page, latin1 is the best guess
+                                text.push(b as char);
+                            }
+                        }
+                    }
+                    b'~' if !skipping(&groups) => text.push(' '),
+                    b'\\' | b'{' | b'}' if !skipping(&groups) => text.push(next as char),
+                    _ => {}
+                }
+            }
+            c if !skipping(&groups) => {
+                if skip_chars > 0 {
+                    skip_chars -= 1;
+                } else {
+                    text.push(c as char);
+                }
+            }
+            _ => {}
+        }
+    }
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Decodes uuencoded file, file name is indexed as text
+pub struct Uuencode;
+
+impl Extractor for Uuencode {
+    fn extract(&self, data: &[u8]) -> Result<Content> {
+        let text = String::from_utf8_lossy(data);
+        let mut lines = text.lines().skip_while(|l| !l.starts_with("begin "));
+        let name = lines
+            .next()
+            .ok_or(anyhow!("no uuencoded data"))?
+            .splitn(3, ' ')
+            .nth(2)
+            .map(String::from);
+        let mut payload = Vec::new();
+        for line in lines.take_while(|l| *l != "end") {
+            let line = line.as_bytes();
+            let Some(&len) = line.first() else {
+                continue;
+            };
+            let len = (len.wrapping_sub(b' ') & 0x3f) as usize;
+            let mut decoded: Vec<u8> = line[1..]
+                .chunks(4)
+                .flat_map(|c| {
+                    let c: Vec<u32> = (0..4)
+                        .map(|i| (c.get(i).unwrap_or(&b' ').wrapping_sub(b' ') & 0x3f) as u32)
+                        .collect();
+                    let n = c[0] << 18 | c[1] << 12 | c[2] << 6 | c[3];
+                    [(n >> 16) as u8, (n >> 8) as u8, n as u8]
+                })
+                .collect();
+            decoded.truncate(len);
+            payload.extend(decoded);
+        }
+        Ok(Content {
+            text: name,
+            payload: Some(payload),
+            encoding: None,
+        })
+    }
+}
+
+/// Decodes data fork of BinHex 4.0 file, file name is indexed as text.
+/// CRCs are not verified.
+pub struct BinHex {
+    /// Limit of decoded data, run-length encoding lets small file expand a lot
+    pub max_size: usize,
+}
+
+impl Default for BinHex {
+    fn default() -> Self {
+        Self { max_size: 16 << 20 }
+    }
+}
+
+impl Extractor for BinHex {
+    fn extract(&self, data: &[u8]) -> Result<Content> {
+        const ALPHABET: &[u8] =
+            b"!\"#$%&'()*+,-012345689@ABCDEFGHIJKLMNPQRSTUVXYZ[`abcdefhijklmpqr";
+        let start = find(data, b"(This file must be converted with BinHex")
+            .ok_or(anyhow!("no BinHex header"))?;
+        let data = &data[start..];
+        let start = find(data, b"\n:")
+            .or(find(data, b"\r:"))
+            .ok_or(anyhow!("no BinHex data"))?
+            + 2;
+        let end = data[start..]
+            .iter()
+            .position(|c| *c == b':')
+            .ok_or(anyhow!("unterminated BinHex data"))?;
+
+        let sextets = data[start..start + end]
+            .iter()
+            .filter(|c| !c.is_ascii_whitespace())
+            .map(|c| ALPHABET.iter().position(|a| a == c).map(|x| x as u32))
+            .collect::<Option<Vec<u32>>>()
+            .ok_or(anyhow!("bad BinHex character"))?;
+        let mut encoded = Vec::with_capacity(sextets.len() * 3 / 4);
+        for c in sextets.chunks(4) {
+            let n = c
+                .iter()
+                .chain([0, 0, 0].iter())
+                .take(4)
+                .fold(0, |n, x| n << 6 | x);
+            encoded.extend(&[(n >> 16) as u8, (n >> 8) as u8, n as u8][..c.len() - 1]);
+        }
+
+        // run-length decoding, 0x90 is followed by repeat count
+        let mut decoded: Vec<u8> = Vec::with_capacity(encoded.len());
+        let mut bytes = encoded.into_iter();
+        while let Some(b) = bytes.next() {
+            match (b, decoded.last().copied()) {
+                (0x90, last) => match (bytes.next(), last) {
+                    (Some(0), _) => decoded.push(0x90),
+                    (Some(n), Some(last)) => decoded.extend((1..n).map(|_| last)),
+                    _ => return Err(anyhow!("bad BinHex run length")),
+                },
+                (b, _) => decoded.push(b),
+            }
+            if decoded.len() > self.max_size {
+                return Err(anyhow!("BinHex data over {} bytes", self.max_size));
+            }
+        }
+
+        // name length, name, version, type, creator, flags, data fork length
+        let name_len = *decoded.first().ok_or(anyhow!("empty BinHex data"))? as usize;
+        let name = decoded
+            .get(1..1 + name_len)
+            .ok_or(anyhow!("short BinHex header"))?;
+        let len_at = 1 + name_len + 1 + 4 + 4 + 2;
+        let len = decoded
+            .get(len_at..len_at + 4)
+            .ok_or(anyhow!("short BinHex header"))?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        let fork_at = len_at + 4 + 4 + 2;
+        let fork = decoded
+            .get(fork_at..fork_at + len)
+            .ok_or(anyhow!("truncated BinHex data fork"))?;
+
+        Ok(Content {
+            text: Some(String::from_utf8_lossy(name).into_owned()),
+            payload: Some(fork.to_vec()),
+            encoding: None,
+        })
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffing() {
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n...."), "image/png");
+        assert_eq!(sniff_mime(b"GIF89a...."), "image/gif");
+        assert_eq!(sniff_mime(b"%PDF-1.4"), "application/pdf");
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WAVEfmt "), "audio/wav");
+        assert_eq!(sniff_mime(b"  <!DOCTYPE html><html>"), "text/html");
+        assert_eq!(sniff_mime(b"just text"), "text/plain");
+        assert_eq!(sniff_mime(b"\0\x01\x02"), "application/octet-stream");
+    }
+
+    #[test]
+    fn extracting_markup() {
+        let html = b"<html><head><title>Hi &amp; bye</title><style>p {}</style>\
+            <script>alert('x')</script></head><body><p>Hello,&nbsp;<b>gopher</b>\
+            &#33;</p><!-- comment --></body></html>";
+        let e = Extractors::default()
+            .extract(GopherItem::HtmlFile, html)
+            .unwrap();
+        assert_eq!(e.text.unwrap(), "Hi & bye\nHello, gopher !");
+        assert_eq!(e.mime, "text/html");
+
+        let xml = b"<?xml version=\"1.0\"?><doc><a>one</a><b><![CDATA[<two>]]></b></doc>";
+        let e = Extractors::default()
+            .extract(GopherItem::XmlFile, xml)
+            .unwrap();
+        assert_eq!(e.text.unwrap(), "one <two>");
+    }
+
+    #[test]
+    fn extracting_rtf() {
+        let rtf = br"{\rtf1\ansi{\fonttbl{\f0 Times;}}{\*\generator Foo;}\f0 Hello \b world\b0\par Caf\'e9 na\u239?ve\par}";
+        let e = Extractors::default()
+            .extract(GopherItem::RtfFile, rtf)
+            .unwrap();
+        assert_eq!(e.text.unwrap(), "Hello world\nCafé naïve");
+        assert_eq!(e.mime, "application/rtf");
+    }
+
+    #[test]
+    fn decoding_uuencode() {
+        let uu = b"some preamble\nbegin 644 cat.txt\n#8V%T\n`\nend\n";
+        let e = Extractors::default()
+            .extract(GopherItem::UuencodeFile, uu)
+            .unwrap();
+        assert_eq!(e.text.as_deref(), Some("cat.txt"));
+        assert_eq!(e.size, 3);
+        assert_eq!(
+            e.sha256,
+            "77af778b51abd4a3c51c5ddd97204a9c3ae614ebccb75a606c3b6865aed6744e"
+        );
+    }
+
+    #[test]
+    fn decoding_binhex() {
+        const ALPHABET: &[u8] =
+            b"!\"#$%&'()*+,-012345689@ABCDEFGHIJKLMNPQRSTUVXYZ[`abcdefhijklmpqr";
+        let mut raw = vec![5];
+        raw.extend(b"a.txt\0TEXTttxt\0\0");
+        raw.extend(6u32.to_be_bytes());
+        raw.extend(0u32.to_be_bytes());
+        raw.extend([0, 0]);
+        // run of 3 x's is encoded as x 0x90 3, literal 0x90 as 0x90 0
+        raw.extend(b"x\x90\x03\x90\x00!!\0\0");
+        let mut encoded = String::from("(This file must be converted with BinHex 4.0)\n:");
+        for c in raw.chunks(3) {
+            let n = c
+                .iter()
+                .chain([0, 0].iter())
+                .take(3)
+                .fold(0u32, |n, b| n << 8 | *b as u32);
+            for i in 0..c.len() + 1 {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            }
+        }
+        encoded.push(':');
+
+        let e = Extractors::default()
+            .extract(GopherItem::BinHex, encoded.as_bytes())
+            .unwrap();
+        assert_eq!(e.text.as_deref(), Some("a.txt"));
+        assert_eq!(e.size, 6);
+        let expected = Extractors::default()
+            .extract(GopherItem::BinaryFile, b"xxx\x90!!")
+            .unwrap();
+        assert_eq!(e.sha256, expected.sha256);
+
+        let mut e = Extractors::default();
+        e.register(GopherItem::BinHex, BinHex { max_size: 29 });
+        let err = e
+            .extract(GopherItem::BinHex, encoded.as_bytes())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "BinHex data over 29 bytes");
+    }
+}
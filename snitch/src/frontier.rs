@@ -0,0 +1,330 @@
+use crate::gopher::GopherURL;
+use crate::index::add_column;
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// State of url in crawl frontier
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Queued,
+    InFlight,
+    Done,
+    Failed,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::InFlight => "in-flight",
+            Self::Done => "done",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Crawl frontier persisted in spider DB, so crawl can be resumed after restart.
+/// Every url ever seen by spider is there, so it doubles as a visited set.
+/// Urls are keyed by their canonical form, so spellings of the same item are
+/// fetched once, but fetched as they were listed, since servers may tell them apart.
+#[derive(Debug, Clone)]
+pub struct Frontier {
+    /// Failed urls are retried until they fail that many times
+    pub max_attempts: u32,
+    /// Delay before first retry, doubled on every next one
+    pub backoff: Duration,
+}
+
+impl Default for Frontier {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Frontier {
+    /// Creates frontier table
+    pub fn init(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS frontier(
+                url TEXT PRIMARY KEY, state TEXT NOT NULL, attempts INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT, next_attempt INTEGER)",
+            (),
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS frontier_state ON frontier(state, next_attempt)",
+            (),
+        )?;
+        add_column(conn, "frontier", "depth", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column(conn, "frontier", "host", "TEXT")?;
+        // NULL for urls queued before, which were queued in canonical form
+        add_column(conn, "frontier", "listed_url", "TEXT")?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS frontier_host ON frontier(host)",
+            (),
+        )?;
+        // urls queued before host column was added
+        let urls: Vec<String> = conn
+            .prepare("SELECT url FROM frontier WHERE host IS NULL")?
+            .query_map((), |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for url in urls {
+            match GopherURL::try_from(url.as_str()) {
+                Ok(parsed) => conn.execute(
+                    "UPDATE frontier SET host = ?1 WHERE url = ?2",
+                    [parsed.canonical().host, url],
+                )?,
+                Err(e) => {
+                    log::error!("[frontier] bad url {url}: {e:#}");
+                    continue;
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Puts urls left in flight by previous run back to queue
+    pub fn resume(&self, conn: &Connection) -> Result<usize> {
+        Ok(conn.execute(
+            "UPDATE frontier SET state = ?1 WHERE state = ?2",
+            [State::Queued.as_str(), State::InFlight.as_str()],
+        )?)
+    }
+
+    /// Adds url found `depth` links away from seeds to queue,
+    /// returns false if url was already seen, in any spelling.
+    /// Depth of seen urls is lowered when a shorter path to them is found.
+    pub fn push(&self, conn: &Connection, url: &GopherURL, depth: u32) -> Result<bool> {
+        let canonical = url.canonical();
+        let seen = conn
+            .query_row(
+                "SELECT 1 FROM frontier WHERE url = ?1",
+                [canonical.to_string()],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        conn.execute(
+            "INSERT INTO frontier (url, state, depth, host, listed_url)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(url) DO UPDATE SET depth = min(depth, excluded.depth)",
+            params![
+                canonical.to_string(),
+                State::Queued.as_str(),
+                depth,
+                canonical.host,
+                url.to_string()
+            ],
+        )?;
+        Ok(!seen)
+    }
+
+    /// Number of links from seeds to url, 0 for seeds and unknown urls
+    pub fn depth(&self, conn: &Connection, url: &GopherURL) -> Result<u32> {
+        Ok(conn
+            .query_row(
+                "SELECT depth FROM frontier WHERE url = ?1",
+                [key(url)],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    /// Number of urls of the host seen so far, in any state
+    pub fn host_count(&self, conn: &Connection, host: &str) -> Result<usize> {
+        Ok(conn.query_row(
+            "SELECT count(*) FROM frontier WHERE host = ?1",
+            [host.to_lowercase()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Takes up to `n` urls ready to be fetched, as they were listed,
+    /// marking them as in flight. Queued urls go first, failed ones
+    /// are taken after their backoff expires.
+    pub fn take(&self, conn: &Connection, n: usize) -> Result<Vec<GopherURL>> {
+        let tx = conn.unchecked_transaction()?;
+        let urls: Vec<(String, Option<String>)> = tx
+            .prepare(
+                "SELECT url, listed_url FROM frontier
+                 WHERE state = ?1 OR (state = ?2 AND attempts < ?3 AND next_attempt <= ?4)
+                 ORDER BY state = ?2, rowid
+                 LIMIT ?5",
+            )?
+            .query_map(
+                params![
+                    State::Queued.as_str(),
+                    State::Failed.as_str(),
+                    self.max_attempts,
+                    now(),
+                    n as i64
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?
+            .collect::<Result<_, _>>()?;
+        for (url, _) in &urls {
+            tx.execute(
+                "UPDATE frontier SET state = ?1 WHERE url = ?2",
+                [State::InFlight.as_str(), url],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(urls
+            .iter()
+            .map(|(url, listed)| listed.as_ref().unwrap_or(url))
+            .filter_map(|url| match GopherURL::try_from(url.as_str()) {
+                Ok(url) => Some(url),
+                Err(e) => {
+                    log::error!("[frontier] bad url {url}: {e:#}");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Puts url taken from frontier back to queue
+    pub fn requeue(&self, conn: &Connection, url: &GopherURL) -> Result<()> {
+        self.set_state(conn, url, State::Queued)
+    }
+
+    pub fn done(&self, conn: &Connection, url: &GopherURL) -> Result<()> {
+        self.set_state(conn, url, State::Done)
+    }
+
+    /// Records failure and schedules retry with exponential backoff
+    pub fn failed(&self, conn: &Connection, url: &GopherURL, error: &str) -> Result<()> {
+        let attempts: u32 = conn
+            .query_row(
+                "SELECT attempts FROM frontier WHERE url = ?1",
+                [key(url)],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0)
+            + 1;
+        let backoff = self.backoff.as_secs() << (attempts - 1).min(16);
+        conn.execute(
+            "INSERT INTO frontier (url, state, attempts, last_error, next_attempt)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(url) DO UPDATE SET state = excluded.state, attempts = excluded.attempts,
+                last_error = excluded.last_error, next_attempt = excluded.next_attempt",
+            params![
+                key(url),
+                State::Failed.as_str(),
+                attempts,
+                error,
+                now() + backoff
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Number of urls in given state
+    pub fn count(&self, conn: &Connection, state: State) -> Result<usize> {
+        Ok(conn.query_row(
+            "SELECT count(*) FROM frontier WHERE state = ?1",
+            [state.as_str()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Time left until next failed url may be retried, if there are any
+    pub fn next_retry(&self, conn: &Connection) -> Result<Option<Duration>> {
+        let next: Option<u64> = conn.query_row(
+            "SELECT min(next_attempt) FROM frontier WHERE state = ?1 AND attempts < ?2",
+            params![State::Failed.as_str(), self.max_attempts],
+            |row| row.get(0),
+        )?;
+        Ok(next.map(|t| Duration::from_secs(t.saturating_sub(now()))))
+    }
+
+    fn set_state(&self, conn: &Connection, url: &GopherURL, state: State) -> Result<()> {
+        conn.execute(
+            "UPDATE frontier SET state = ?1 WHERE url = ?2",
+            [state.as_str(), key(url).as_str()],
+        )?;
+        Ok(())
+    }
+}
+
+/// Frontier key of url, the same for all its spellings
+fn key(url: &GopherURL) -> String {
+    url.canonical().to_string()
+}
+
+/// Seconds since Unix epoch, as timestamps are stored in DB
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::init_db;
+
+    #[test]
+    fn frontier() {
+        let conn = init_db(":memory:").unwrap();
+        let frontier = Frontier {
+            max_attempts: 2,
+            backoff: Duration::ZERO,
+        };
+        let a = GopherURL::try_from("gopher://a.org/1/a").unwrap();
+        let b = GopherURL::try_from("gopher://b.org/0/b").unwrap();
+
+        assert!(frontier.push(&conn, &a, 0).unwrap());
+        assert!(frontier.push(&conn, &b, 1).unwrap());
+        assert!(!frontier.push(&conn, &a, 2).unwrap());
+        assert_eq!(frontier.depth(&conn, &a).unwrap(), 0);
+        assert_eq!(frontier.depth(&conn, &b).unwrap(), 1);
+        // shorter path found later
+        assert!(!frontier.push(&conn, &b, 0).unwrap());
+        assert_eq!(frontier.depth(&conn, &b).unwrap(), 0);
+        assert!(!frontier.push(&conn, &b, 1).unwrap());
+        assert_eq!(frontier.depth(&conn, &b).unwrap(), 0);
+        assert_eq!(frontier.host_count(&conn, "A.org").unwrap(), 1);
+
+        assert_eq!(frontier.take(&conn, 1).unwrap(), vec![a.clone()]);
+        assert_eq!(frontier.count(&conn, State::InFlight).unwrap(), 1);
+        // crash, a is still in flight
+        assert_eq!(frontier.resume(&conn).unwrap(), 1);
+        assert_eq!(
+            frontier.take(&conn, 10).unwrap(),
+            vec![a.clone(), b.clone()]
+        );
+        assert!(frontier.take(&conn, 10).unwrap().is_empty());
+
+        frontier.done(&conn, &a).unwrap();
+        frontier.failed(&conn, &b, "connection refused").unwrap();
+        assert_eq!(frontier.next_retry(&conn).unwrap(), Some(Duration::ZERO));
+        assert_eq!(frontier.take(&conn, 10).unwrap(), vec![b.clone()]);
+        frontier.failed(&conn, &b, "connection refused").unwrap();
+        // out of attempts
+        assert!(frontier.take(&conn, 10).unwrap().is_empty());
+        assert_eq!(frontier.next_retry(&conn).unwrap(), None);
+        assert_eq!(frontier.count(&conn, State::Failed).unwrap(), 1);
+        assert_eq!(frontier.count(&conn, State::Done).unwrap(), 1);
+
+        // other spellings are the same url, fetched as first listed
+        let c = GopherURL::try_from("gopher://C.org/1/c/").unwrap();
+        assert!(frontier.push(&conn, &c, 0).unwrap());
+        assert!(!frontier.push(&conn, &c.canonical(), 0).unwrap());
+        assert_eq!(frontier.take(&conn, 10).unwrap(), vec![c.clone()]);
+        frontier.done(&conn, &c.canonical()).unwrap();
+        assert_eq!(frontier.count(&conn, State::Done).unwrap(), 2);
+
+        // rows queued before host column was added
+        conn.execute("UPDATE frontier SET host = NULL", ()).unwrap();
+        Frontier::init(&conn).unwrap();
+        assert_eq!(frontier.host_count(&conn, "c.org").unwrap(), 1);
+        assert_eq!(frontier.host_count(&conn, "a.org").unwrap(), 1);
+    }
+}
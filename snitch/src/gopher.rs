@@ -1,6 +1,8 @@
+use crate::charset;
+use crate::tls::{self, TlsMode};
 use anyhow::{anyhow, Context, Result};
 use std::io::Read;
-use std::net::{self, ToSocketAddrs};
+use std::net::ToSocketAddrs;
 use std::time::Duration;
 use std::{fmt::Display, io::BufRead};
 use std::{
@@ -12,6 +14,8 @@ const _INVALID_ENTRY: DirEntry = DirEntry {
     item_type: GopherItem::Unknown,
     label: String::new(),
     url: None,
+    external: None,
+    gopher_plus: false,
 };
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
@@ -77,42 +81,42 @@ impl From<char> for GopherItem {
     }
 }
 
-impl Into<char> for GopherItem {
-    fn into(self) -> char {
-        match self {
-            Self::TextFile => '0',
-            Self::Submenu => '1',
-            Self::Nameserver => '2',
-            Self::Error => '3',
-            Self::BinHex => '4',
-            Self::Dos => '5',
-            Self::UuencodeFile => '6',
-            Self::FullTextSearch => '7',
-            Self::Telnet => '8',
-            Self::BinaryFile => '9',
-            Self::Mirror => '+',
-            Self::GifFile => 'g',
-            Self::ImageFile => 'I',
-            Self::Telnet3270 => 'T',
-            Self::BitmapFile => ':',
-            Self::MovieFile => ';',
-            Self::SoundFile => '<',
-            Self::DocFile => 'd',
-            Self::HtmlFile => 'h',
-            Self::Info => 'i',
-            Self::PngFile => 'p',
-            Self::RtfFile => 'r',
-            Self::WavFile => 's',
-            Self::PdfFile => 'P',
-            Self::XmlFile => 'X',
-            Self::Unknown => '?',
+impl From<GopherItem> for char {
+    fn from(item: GopherItem) -> char {
+        match item {
+            GopherItem::TextFile => '0',
+            GopherItem::Submenu => '1',
+            GopherItem::Nameserver => '2',
+            GopherItem::Error => '3',
+            GopherItem::BinHex => '4',
+            GopherItem::Dos => '5',
+            GopherItem::UuencodeFile => '6',
+            GopherItem::FullTextSearch => '7',
+            GopherItem::Telnet => '8',
+            GopherItem::BinaryFile => '9',
+            GopherItem::Mirror => '+',
+            GopherItem::GifFile => 'g',
+            GopherItem::ImageFile => 'I',
+            GopherItem::Telnet3270 => 'T',
+            GopherItem::BitmapFile => ':',
+            GopherItem::MovieFile => ';',
+            GopherItem::SoundFile => '<',
+            GopherItem::DocFile => 'd',
+            GopherItem::HtmlFile => 'h',
+            GopherItem::Info => 'i',
+            GopherItem::PngFile => 'p',
+            GopherItem::RtfFile => 'r',
+            GopherItem::WavFile => 's',
+            GopherItem::PdfFile => 'P',
+            GopherItem::XmlFile => 'X',
+            GopherItem::Unknown => '?',
         }
     }
 }
 
 impl Display for GopherItem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", Into::<char>::into(self.clone()))
+        write!(f, "{}", char::from(*self))
     }
 }
 
@@ -122,13 +126,15 @@ pub struct GopherURL {
     pub port: u16,
     pub gopher_type: GopherItem,
     pub selector: String,
+    /// `gophers://` URL, fetched over TLS only
+    pub tls: bool,
 }
 
 impl TryFrom<&str> for GopherURL {
     type Error = anyhow::Error;
     fn try_from(url_str: &str) -> Result<Self> {
         let gopher_url_re = regex_static::static_regex!(
-            r#"(?:gopher://)?(?P<host>[^:/]+)(?::(?P<port>\d+))?(?:/(?P<type>[A-z0-9:+:;<?])(?P<selector>.*))?$"#
+            r#"(?:(?P<scheme>gophers?)://)?(?P<host>[^:/]+)(?::(?P<port>\d+))?(?:/(?P<type>[A-z0-9:+:;<?])(?P<selector>.*))?$"#
         );
         let Some(matches) = gopher_url_re.captures(url_str) else {
             return Err(anyhow!("failed to parse URL"));
@@ -144,18 +150,22 @@ impl TryFrom<&str> for GopherURL {
             selector: matches
                 .name("selector")
                 .map_or(String::from(""), |s| String::from(s.as_str())),
+            tls: matches
+                .name("scheme")
+                .is_some_and(|s| s.as_str() == "gophers"),
         })
     }
 }
 
 impl Display for GopherURL {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.tls { "gophers" } else { "gopher" };
         if self.selector.is_empty() {
-            write!(f, "gopher://{}:{}", self.host, self.port)
+            write!(f, "{scheme}://{}:{}", self.host, self.port)
         } else {
             write!(
                 f,
-                "gopher://{}:{}/{}{}",
+                "{scheme}://{}:{}/{}{}",
                 self.host, self.port, self.gopher_type, self.selector
             )
         }
@@ -163,12 +173,122 @@ impl Display for GopherURL {
 }
 
 impl GopherURL {
+    /// Canonical form of URL, so that spellings of the same item are crawled once:
+    /// lowercase host without trailing dot, and selectors that look like paths
+    /// with single leading slash and no trailing one. Root menu has empty selector.
+    pub fn canonical(&self) -> Self {
+        let path_like = !self.selector.starts_with("URL:") && !self.selector.contains(['?', '\t']);
+        let selector = match self.selector.trim_matches('/') {
+            _ if !path_like => self.selector.clone(),
+            "" => String::new(),
+            path => format!("/{path}"),
+        };
+        Self {
+            host: self.host.trim_end_matches('.').to_lowercase(),
+            selector,
+            ..self.clone()
+        }
+    }
+
+    /// Link outside of gopherspace this URL stands for, if any
+    pub fn external(&self) -> Option<ExternalLink> {
+        let (host, port) = (self.host.clone(), self.port);
+        if let Some(url) = self.selector.strip_prefix("URL:") {
+            return Some(ExternalLink::Url(String::from(url)));
+        }
+        match self.gopher_type {
+            GopherItem::Telnet => Some(ExternalLink::Telnet {
+                host,
+                port,
+                login: self.selector.clone(),
+            }),
+            GopherItem::Telnet3270 => Some(ExternalLink::Tn3270 {
+                host,
+                port,
+                login: self.selector.clone(),
+            }),
+            GopherItem::Nameserver => Some(ExternalLink::Cso { host, port }),
+            _ => None,
+        }
+    }
+
     fn new(host: &str, port: &str, item_type: &GopherItem, selector: &str) -> Self {
         Self {
             host: String::from(host),
             port: port.parse().unwrap_or(70),
-            gopher_type: item_type.clone(),
+            gopher_type: *item_type,
             selector: String::from(selector),
+            tls: false,
+        }
+    }
+}
+
+/// Menu item leading out of gopherspace. Such items are linked to,
+/// but never fetched over gopher.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ExternalLink {
+    /// `URL:` selector, usually of `h` item, as in "URL:https://example.org/"
+    Url(String),
+    /// Telnet session, selector is login name, if any
+    Telnet {
+        host: String,
+        port: u16,
+        login: String,
+    },
+    /// IBM 3270 terminal session
+    Tn3270 {
+        host: String,
+        port: u16,
+        login: String,
+    },
+    /// CSO phonebook server, see [`crate::cso`]
+    Cso { host: String, port: u16 },
+}
+
+impl ExternalLink {
+    /// Host of the link, empty for URLs without one, like `mailto:`
+    pub fn host(&self) -> &str {
+        match self {
+            Self::Url(url) => {
+                let Some((_, rest)) = url.split_once("://") else {
+                    return "";
+                };
+                let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+                let host = authority.rsplit('@').next().unwrap_or_default();
+                match host.strip_prefix('[') {
+                    Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+                    None => host.split(':').next().unwrap_or_default(),
+                }
+            }
+            Self::Telnet { host, .. } | Self::Tn3270 { host, .. } | Self::Cso { host, .. } => host,
+        }
+    }
+}
+
+/// Formats link as URL, like `telnet://guest@example.org:23`
+impl Display for ExternalLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let login = |login: &str| match login {
+            "" => String::new(),
+            login => format!("{login}@"),
+        };
+        match self {
+            Self::Url(url) => write!(f, "{url}"),
+            Self::Telnet {
+                host,
+                port,
+                login: l,
+            } => {
+                write!(f, "telnet://{}{host}:{port}", login(l))
+            }
+            Self::Tn3270 {
+                host,
+                port,
+                login: l,
+            } => {
+                write!(f, "tn3270://{}{host}:{port}", login(l))
+            }
+            Self::Cso { host, port } => write!(f, "cso://{host}:{port}"),
         }
     }
 }
@@ -177,7 +297,13 @@ impl GopherURL {
 pub struct DirEntry {
     pub item_type: GopherItem,
     pub label: String,
+    /// Location of the item, as listed in menu
     pub url: Option<GopherURL>,
+    /// Set for items that are not gopher documents, they should not be
+    /// fetched from `url`
+    pub external: Option<ExternalLink>,
+    /// Item is served by gopher+ server, i.e. has attributes
+    pub gopher_plus: bool,
 }
 
 impl From<&str> for DirEntry {
@@ -193,7 +319,12 @@ impl From<&str> for DirEntry {
                     }
                 };
                 let label: String = s.collect();
-                DirEntry::new(t, label.as_str(), selector, host, port)
+                let mut entry = DirEntry::new(t, label.as_str(), selector, host, port);
+                // gopher+ servers add fifth field, "+" or "?" for items with ASK block
+                entry.gopher_plus = e
+                    .next()
+                    .is_some_and(|x| x.starts_with('+') || x.starts_with('?'));
+                entry
             }
             _ => _INVALID_ENTRY,
         }
@@ -207,12 +338,44 @@ impl DirEntry {
                 item_type,
                 label: String::from(label),
                 url: None,
+                external: None,
+                gopher_plus: false,
             },
-            _ => DirEntry {
-                item_type,
-                label: String::from(label),
-                url: Some(GopherURL::new(host, port, &item_type, selector)),
-            },
+            _ => {
+                let url = GopherURL::new(host, port, &item_type, selector);
+                DirEntry {
+                    item_type,
+                    label: String::from(label),
+                    external: url.external(),
+                    url: Some(url),
+                    gopher_plus: false,
+                }
+            }
+        }
+    }
+}
+
+/// Formats entry as menu line (without line terminator).
+/// Entries without url, like info ones, are split by lines.
+impl Display for DirEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.url {
+            Some(url) => write!(
+                f,
+                "{}{}\t{}\t{}\t{}",
+                self.item_type, self.label, url.selector, url.host, url.port
+            ),
+            // empty label has no lines, but still needs one not to end up a bare CRLF
+            None if self.label.is_empty() => write!(f, "{}\tfake\t(NULL)\t0", self.item_type),
+            None => {
+                for (i, line) in self.label.lines().enumerate() {
+                    if i > 0 {
+                        write!(f, "\r\n")?;
+                    }
+                    write!(f, "{}{}\tfake\t(NULL)\t0", self.item_type, line)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -221,17 +384,39 @@ pub struct Menu {
     pub items: Vec<DirEntry>,
 }
 
+/// Formats menu as it is sent by server, including terminating line
+impl Display for Menu {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for item in &self.items {
+            write!(f, "{item}\r\n")?;
+        }
+        write!(f, ".\r\n")
+    }
+}
+
 impl Menu {
     pub fn from_url(url: &GopherURL, query: Option<String>) -> Result<Self, anyhow::Error> {
+        let response = fetch_url(url, query).context(format!("fetching {url}"))?;
+        Ok(Self::from_reader(response))
+    }
+
+    /// Parses menu from already fetched response, detecting its encoding
+    pub fn from_reader(mut reader: impl BufRead) -> Self {
+        let mut data = Vec::new();
+        if let Err(e) = reader.read_to_end(&mut data) {
+            log::warn!("reading menu: {e:#}");
+        }
+        Self::parse(&charset::decode(&data).text)
+    }
+
+    /// Parses menu from decoded text
+    pub fn parse(text: &str) -> Self {
         let mut items: Vec<DirEntry> = Vec::new();
-        let mut response = fetch_url(&url, query)
-            .context(format!("fetching {url}"))?
-            .lines();
-        while let Some(Ok(line)) = response.next() {
+        for line in text.lines() {
             if line == "." {
                 break;
             }
-            let entry = DirEntry::from(line.as_str());
+            let entry = DirEntry::from(line);
             match entry.item_type {
                 GopherItem::Unknown => continue,
                 GopherItem::Info => {
@@ -249,30 +434,52 @@ impl Menu {
             }
         }
 
-        Ok(Self { items: items })
+        Self { items }
     }
 }
 
 pub fn fetch_url(url: &GopherURL, query: Option<String>) -> Result<impl BufRead> {
+    fetch_url_with(url, query, TlsMode::Off)
+}
+
+/// Like [`fetch_url`], using TLS for `gopher://` URLs according to `mode`
+pub fn fetch_url_with(
+    url: &GopherURL,
+    query: Option<String>,
+    mode: TlsMode,
+) -> Result<impl BufRead> {
     log::debug!("fetching {url}");
-    let addr: net::SocketAddr;
-    match format!("{}:{}", url.host, url.port)
+    let addr = match format!("{}:{}", url.host, url.port)
         .to_socket_addrs()
         .context("resolving host")?
         .next()
     {
-        Some(x) => addr = x,
+        Some(x) => x,
         None => return Err(anyhow!("no address resolved")),
-    }
-    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))
-        .context(format!("connecting to {addr}"))?;
-    let selector = query.map_or(format!("{}\r\n", url.selector), |q| {
-        format!("{}\t{}\r\n", url.selector, q)
-    });
-    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
-    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    };
+    let connect = || -> Result<TcpStream> {
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))
+            .context(format!("connecting to {addr}"))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(stream)
+    };
+    let mut stream: Box<dyn Stream> = if url.tls || mode != TlsMode::Off {
+        match tls::connect(connect()?, &url.host, mode == TlsMode::Strict) {
+            Ok(stream) => Box::new(stream),
+            Err(e) if !url.tls && mode == TlsMode::Opportunistic => {
+                log::debug!("[tls] no TLS on {addr}, falling back to plain text: {e:#}");
+                Box::new(connect()?)
+            }
+            Err(e) => return Err(e.context(format!("connecting to {addr}"))),
+        }
+    } else {
+        Box::new(connect()?)
+    };
+    let selector = request_line(url, query);
     stream
         .write_all(selector.as_bytes())
+        .and_then(|_| stream.flush())
         .context(format!("querying {addr}"))?;
     let mut buf = BufReader::new(stream);
 
@@ -285,8 +492,27 @@ pub fn fetch_url(url: &GopherURL, query: Option<String>) -> Result<impl BufRead>
     */
     let mut header = vec![0; 256];
     let bytes_read = buf.read(&mut header).context("reading gopher reply")?;
-    if let Ok(first_line) = String::from_utf8(header.clone()) {
-        match DirEntry::from(first_line.as_str()) {
+    check_reply(url, &header[0..bytes_read])?;
+    Ok(Cursor::new(header[0..bytes_read].to_vec()).chain(buf))
+}
+
+/// Either plain or TLS connection
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+pub(crate) fn request_line(url: &GopherURL, query: Option<String>) -> String {
+    query.map_or(format!("{}\r\n", url.selector), |q| {
+        format!("{}\t{}\r\n", url.selector, q)
+    })
+}
+
+/// Checks whether reply (or at least its beginning) is an error dir entry
+pub(crate) fn check_reply(url: &GopherURL, header: &[u8]) -> Result<()> {
+    // binaries may look like anything, but never have zero bytes in error entries
+    if !header.contains(&0) {
+        let first_line = header.split(|b| *b == b'\n').next().unwrap_or_default();
+        match DirEntry::from(charset::decode(first_line).text.as_str()) {
             entry if entry.item_type == GopherItem::Error => {
                 log::error!("got error fetching {}: {}", url, entry.label);
                 return Err(anyhow!(entry.label));
@@ -294,12 +520,14 @@ pub fn fetch_url(url: &GopherURL, query: Option<String>) -> Result<impl BufRead>
             _ => {}
         }
     }
-    Ok(Cursor::new(header[0..bytes_read].to_vec()).chain(buf))
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::{MockGopherServer, Response};
+    use encoding::all::KOI8_R;
 
     #[test]
     fn parsing_entries() {
@@ -314,6 +542,80 @@ mod tests {
         assert_eq!(url.host, "1.1.1.1");
         assert_eq!(url.selector, "selector");
         assert_eq!(url.gopher_type, GopherItem::TextFile);
+        assert!(!e.gopher_plus);
+        e = DirEntry::from("1Plus entry\t1/plus\texample.com\t7070\t+\r\n");
+        assert!(e.gopher_plus);
+        assert_eq!(e.url.unwrap().port, 7070);
+        assert_eq!(e.external, None);
+    }
+
+    #[test]
+    fn parsing_external_links() {
+        let external = |line| DirEntry::from(line).external.unwrap();
+        let web = external("hWeb\tURL:https://user@[::1]:8080/a?b\texample.com\t70");
+        assert_eq!(
+            web,
+            ExternalLink::Url(String::from("https://user@[::1]:8080/a?b"))
+        );
+        assert_eq!(web.host(), "::1");
+        let mail = external("hMail me\tURL:mailto:me@example.com\texample.com\t70");
+        assert_eq!(mail.host(), "");
+        // any item may have URL: selector
+        let ftp = external("1FTP\tURL:ftp://ftp.example.org/pub\texample.com\t70");
+        assert_eq!(ftp.host(), "ftp.example.org");
+
+        let telnet = external("8Chat\tguest\tbbs.example.org\t23");
+        assert_eq!(telnet.to_string(), "telnet://guest@bbs.example.org:23");
+        assert_eq!(telnet.host(), "bbs.example.org");
+        let tn3270 = external("TMainframe\t\tibm.example.org\t23");
+        assert_eq!(tn3270.to_string(), "tn3270://ibm.example.org:23");
+        let cso = external("2Phonebook\t\tns.example.edu\t105");
+        assert_eq!(cso.to_string(), "cso://ns.example.edu:105");
+
+        // menus are written back as they were
+        let line = "hWeb\tURL:https://example.org/\texample.com\t70";
+        assert_eq!(DirEntry::from(line).to_string(), line);
+        assert_eq!(DirEntry::from("0Text\t/t\texample.com\t70").external, None);
+    }
+
+    #[test]
+    fn formatting_menus() {
+        let menu = Menu::from_reader(
+            "iHello\tfake\t(NULL)\t0\r\n\
+             iworld\tfake\t(NULL)\t0\r\n\
+             0About\t/about.txt\texample.com\t70\r\n\
+             .\r\n"
+                .as_bytes(),
+        );
+        assert_eq!(menu.items.len(), 2);
+        assert_eq!(
+            menu.to_string(),
+            "iHello\tfake\t(NULL)\t0\r\n\
+             iworld\tfake\t(NULL)\t0\r\n\
+             0About\t/about.txt\texample.com\t70\r\n\
+             .\r\n"
+        );
+        let empty = DirEntry::new(GopherItem::Info, "", "", "", "");
+        assert_eq!(empty.to_string(), "i\tfake\t(NULL)\t0");
+    }
+
+    #[test]
+    fn parsing_encoded_menus() {
+        // KOI8-R, "Добро пожаловать" and "Статьи"
+        let mut reply =
+            b"i\xe4\xcf\xc2\xd2\xcf \xd0\xcf\xd6\xc1\xcc\xcf\xd7\xc1\xd4\xd8\tfake\t(NULL)\t0\r\n"
+                .to_vec();
+        reply.extend(b"1\xf3\xd4\xc1\xd4\xd8\xc9\t/articles\texample.ru\t70\r\n.\r\n");
+        let menu = Menu::from_reader(reply.as_slice());
+        assert_eq!(menu.items.len(), 2);
+        assert_eq!(menu.items[0].label, "Добро пожаловать");
+        assert_eq!(menu.items[1].label, "Статьи");
+
+        assert!(check_reply(
+            &GopherURL::try_from("gopher://example.ru/0/x").unwrap(),
+            b"3\xef\xdb\xc9\xc2\xcb\xc1\tfake\t(NULL)\t0\r\n"
+        )
+        .is_err());
     }
 
     #[test]
@@ -339,14 +641,112 @@ mod tests {
 
         u = GopherURL::new("1.1.1.1", "70", &GopherItem::TextFile, "some-selector");
         assert_eq!(u.to_string(), "gopher://1.1.1.1:70/0some-selector");
+        assert!(!u.tls);
+
+        let canonical = GopherURL::try_from("gopher://host:70/1/foo").unwrap();
+        for variant in [
+            "gopher://host/1/foo",
+            "gopher://host:70/1/foo/",
+            "gopher://HOST./1foo",
+            "gopher://host/1//foo//",
+        ] {
+            let u = GopherURL::try_from(variant).unwrap().canonical();
+            assert_eq!(u, canonical, "{variant}");
+        }
+        let root = GopherURL::try_from("gopher://host/1/").unwrap().canonical();
+        assert_eq!(root.to_string(), "gopher://host:70");
+        let query = GopherURL::try_from("gopher://host/0cgi?a/").unwrap();
+        assert_eq!(query.canonical(), query);
+
+        u = GopherURL::try_from("gophers://secure.example.org/1/phlog").unwrap();
+        assert!(u.tls);
+        assert_eq!(u.host, "secure.example.org");
+        assert_eq!(u.to_string(), "gophers://secure.example.org:70/1/phlog");
+        assert_eq!(GopherURL::try_from(u.to_string().as_str()).unwrap(), u);
     }
 
     #[test]
     fn fetching() {
-        let url = GopherURL::try_from("gopher://gopher.386server.info:70/1/Photos/Iraq/").unwrap();
-        let mut c = fetch_url(&url, None).unwrap();
-        let mut s = String::new();
-        c.read_to_string(&mut s).unwrap();
-        println!("{s}");
+        let server = MockGopherServer::new().unwrap();
+        server.route("/hello.txt", Response::text("hello\r\nworld\r\n"));
+        server.route("/gone", Response::Error(String::from("Gone fishing")));
+        server.route(
+            "/slow",
+            Response::Slow(Duration::from_millis(200), Box::new(Response::text("zzz"))),
+        );
+        server.route(
+            "/cut",
+            Response::Truncated(5, Box::new(Response::text("truncated reply"))),
+        );
+
+        let read = |url: &GopherURL| -> Result<String> {
+            let mut s = String::new();
+            fetch_url(url, None)?.read_to_string(&mut s)?;
+            Ok(s)
+        };
+        let url = server.url(GopherItem::TextFile, "/hello.txt");
+        assert_eq!(read(&url).unwrap(), "hello\r\nworld\r\n");
+        let e = read(&server.url(GopherItem::TextFile, "/gone")).unwrap_err();
+        assert_eq!(e.to_string(), "Gone fishing");
+        assert_eq!(
+            read(&server.url(GopherItem::TextFile, "/slow")).unwrap(),
+            "zzz"
+        );
+        assert_eq!(
+            read(&server.url(GopherItem::TextFile, "/cut")).unwrap(),
+            "trunc"
+        );
+        assert!(read(&server.url(GopherItem::TextFile, "/nothing")).is_err());
+
+        fetch_url(&url, Some(String::from("gopher stuff"))).unwrap();
+        assert_eq!(
+            server.requests().last().unwrap(),
+            "/hello.txt\tgopher stuff"
+        );
+
+        drop(server);
+        assert!(read(&url).is_err());
+    }
+
+    #[test]
+    fn fetching_menus() {
+        let server = MockGopherServer::new().unwrap();
+        server.route(
+            "",
+            server.menu(&[
+                (GopherItem::Info, "Welcome", ""),
+                (GopherItem::Submenu, "Russian", "/ru"),
+                (GopherItem::TextFile, "About", "/about.txt"),
+            ]),
+        );
+        server.route(
+            "/ru",
+            Response::Encoded(
+                KOI8_R,
+                String::from("iДобро пожаловать\tfake\t(NULL)\t0\r\n.\r\n"),
+            ),
+        );
+        server.route(
+            "/cut",
+            Response::Truncated(
+                40,
+                Box::new(server.menu(&[
+                    (GopherItem::TextFile, "First", "/1"),
+                    (GopherItem::TextFile, "Second", "/2"),
+                ])),
+            ),
+        );
+
+        let menu = Menu::from_url(&server.url(GopherItem::Submenu, ""), None).unwrap();
+        assert_eq!(menu.items.len(), 3);
+        let ru = menu.items[1].url.as_ref().unwrap();
+        assert_eq!((ru.host.as_str(), ru.port), (server.host(), server.port()));
+        let menu = Menu::from_url(ru, None).unwrap();
+        assert_eq!(menu.items[0].label, "Добро пожаловать");
+
+        // partial last line is dropped, as it has not enough fields
+        let menu = Menu::from_url(&server.url(GopherItem::Submenu, "/cut"), None).unwrap();
+        assert_eq!(menu.items.len(), 1);
+        assert_eq!(menu.items[0].label, "First");
     }
 }
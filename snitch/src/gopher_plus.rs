@@ -0,0 +1,230 @@
+use crate::charset;
+use crate::gopher::{DirEntry, GopherURL};
+use std::io::BufRead;
+
+/// Gopher+ attributes of a single item, as returned by `!` and `$` requests
+/// (see gopher+ spec, section 2.6)
+#[derive(Debug, Default)]
+pub struct ItemAttributes {
+    /// +INFO block, i.e. menu line of the item
+    pub info: Option<DirEntry>,
+    pub admin: Option<Admin>,
+    pub views: Vec<View>,
+    pub abstract_text: Option<String>,
+    /// Any other blocks, like +ASK, as they are
+    pub other: Vec<(String, String)>,
+}
+
+/// +ADMIN block
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Admin {
+    /// Administrator contact, like `Joe Admin <joe@example.com>`
+    pub admin: Option<String>,
+    /// Modification date as `YYYY-MM-DD hh:mm:ss`
+    pub mod_date: Option<String>,
+    /// Other fields, like Score, TTL, Site etc
+    pub extra: Vec<(String, String)>,
+}
+
+/// Single line in +VIEWS block
+#[derive(Debug, PartialEq, Eq)]
+pub struct View {
+    pub mime: String,
+    pub language: Option<String>,
+    /// Approximate size, like `12k`
+    pub size: Option<String>,
+}
+
+impl ItemAttributes {
+    /// URL of item these attributes belong to
+    pub fn url(&self) -> Option<&GopherURL> {
+        self.info.as_ref().and_then(|i| i.url.as_ref())
+    }
+
+    /// Parses attributes of single item, reply to `!` request
+    pub fn parse(reader: impl BufRead) -> Self {
+        Self::parse_all(reader)
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+    }
+
+    /// Parses sequence of attribute blocks, every +INFO block starts new item,
+    /// like in reply to `$` request
+    pub fn parse_all(mut reader: impl BufRead) -> Vec<Self> {
+        let mut data = Vec::new();
+        if let Err(e) = reader.read_to_end(&mut data) {
+            log::warn!("reading attributes: {e:#}");
+        }
+        let mut items = Vec::new();
+        let mut current: Option<Self> = None;
+        let mut block: Option<(String, Vec<String>)> = None;
+
+        for line in charset::decode(&data).text.lines() {
+            if line == "." {
+                break;
+            }
+            if let Some(rest) = line.strip_prefix('+') {
+                // "+-1" and "+-2" are data transfer headers, not blocks
+                let Some((name, value)) = rest.split_once(':') else {
+                    continue;
+                };
+                if let Some((name, lines)) = block.take() {
+                    current
+                        .get_or_insert_with(Self::default)
+                        .add_block(&name, lines);
+                }
+                let value = value.trim();
+                if name == "INFO" {
+                    items.extend(current.take());
+                    current = Some(Self {
+                        info: Some(DirEntry::from(value)),
+                        ..Default::default()
+                    });
+                } else {
+                    let lines = match value {
+                        "" => Vec::new(),
+                        v => vec![String::from(v)],
+                    };
+                    block = Some((String::from(name), lines));
+                }
+            } else if let Some((_, lines)) = block.as_mut() {
+                lines.push(String::from(line.strip_prefix(' ').unwrap_or(line)));
+            }
+        }
+        if let Some((name, lines)) = block.take() {
+            current
+                .get_or_insert_with(Self::default)
+                .add_block(&name, lines);
+        }
+        items.extend(current);
+        items
+    }
+
+    fn add_block(&mut self, name: &str, lines: Vec<String>) {
+        match name {
+            "ADMIN" => self.admin = Some(Admin::parse(&lines)),
+            "VIEWS" => self.views = lines.iter().filter_map(|l| View::parse(l)).collect(),
+            "ABSTRACT" => self.abstract_text = Some(lines.join("\n")),
+            _ => self.other.push((String::from(name), lines.join("\n"))),
+        }
+    }
+}
+
+impl Admin {
+    fn parse(lines: &[String]) -> Self {
+        let mut admin = Self::default();
+        for (k, v) in lines.iter().filter_map(|l| l.split_once(':')) {
+            let v = v.trim();
+            match k.trim() {
+                "Admin" => admin.admin = Some(String::from(v)),
+                "Mod-Date" => admin.mod_date = Some(parse_mod_date(v)),
+                k => admin.extra.push((String::from(k), String::from(v))),
+            }
+        }
+        admin
+    }
+}
+
+impl View {
+    fn parse(line: &str) -> Option<Self> {
+        let (view, size) = line.rsplit_once(':')?;
+        let mut view = view.split_whitespace();
+        let size = size.trim().trim_start_matches('<').trim_end_matches('>');
+        Some(Self {
+            mime: String::from(view.next()?),
+            language: view.next().map(String::from),
+            size: (!size.is_empty()).then(|| String::from(size)),
+        })
+    }
+}
+
+/// Mod-Date is human readable date followed by `<YYYYMMDDhhmmss>`,
+/// latter one is converted to `YYYY-MM-DD hh:mm:ss`.
+/// Returns date as is if there is no such timestamp.
+fn parse_mod_date(date: &str) -> String {
+    let ts = date
+        .rsplit_once('<')
+        .and_then(|(_, ts)| ts.strip_suffix('>'))
+        .filter(|ts| ts.len() == 14 && ts.chars().all(|c| c.is_ascii_digit()));
+    match ts {
+        Some(ts) => format!(
+            "{}-{}-{} {}:{}:{}",
+            &ts[0..4],
+            &ts[4..6],
+            &ts[6..8],
+            &ts[8..10],
+            &ts[10..12],
+            &ts[12..14]
+        ),
+        None => String::from(date),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gopher::GopherItem;
+
+    #[test]
+    fn parsing_attributes() {
+        let reply = "+-2\r\n\
+            +INFO: 0About us\t0/about\texample.com\t70\t+\r\n\
+            +ADMIN:\r\n \
+            Admin: Joe Admin <joe@example.com>\r\n \
+            Mod-Date: Wed Jul 28 17:02:01 1993 <19930728170201>\r\n \
+            TTL: 3600\r\n\
+            +VIEWS:\r\n \
+            text/plain: <2k>\r\n \
+            application/postscript En_US: <10k>\r\n\
+            +ABSTRACT:\r\n \
+            All about us.\r\n \
+            And some more.\r\n\
+            +INFO: 1Stuff\t1/stuff\texample.com\t70\t+\r\n\
+            +ADMIN:\r\n \
+            Admin: Jane <jane@example.com>\r\n\
+            .\r\n";
+        let items = ItemAttributes::parse_all(reply.as_bytes());
+        assert_eq!(items.len(), 2);
+        let first = ItemAttributes::parse(reply.as_bytes());
+        assert_eq!(first.url(), items[0].url());
+
+        let about = &items[0];
+        assert_eq!(about.url().unwrap().selector, "0/about");
+        assert_eq!(about.url().unwrap().gopher_type, GopherItem::TextFile);
+        let admin = about.admin.as_ref().unwrap();
+        assert_eq!(admin.admin.as_deref(), Some("Joe Admin <joe@example.com>"));
+        assert_eq!(admin.mod_date.as_deref(), Some("1993-07-28 17:02:01"));
+        assert_eq!(
+            admin.extra,
+            vec![(String::from("TTL"), String::from("3600"))]
+        );
+        assert_eq!(
+            about.views,
+            vec![
+                View {
+                    mime: String::from("text/plain"),
+                    language: None,
+                    size: Some(String::from("2k")),
+                },
+                View {
+                    mime: String::from("application/postscript"),
+                    language: Some(String::from("En_US")),
+                    size: Some(String::from("10k")),
+                },
+            ]
+        );
+        assert_eq!(
+            about.abstract_text.as_deref(),
+            Some("All about us.\nAnd some more.")
+        );
+
+        let stuff = &items[1];
+        assert_eq!(stuff.info.as_ref().unwrap().label, "Stuff");
+        assert!(stuff.views.is_empty());
+        assert_eq!(
+            stuff.admin.as_ref().unwrap().admin.as_deref(),
+            Some("Jane <jane@example.com>")
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use crate::browse;
+use crate::dedup;
+use crate::export;
+use crate::frontier::Frontier;
+use crate::gopher::GopherItem;
+use crate::rank;
+use crate::recrawl::Schedule;
+use crate::tls;
+use anyhow::{Context, Result};
+use rusqlite::{functions::FunctionFlags, params, Connection};
+
+/// Opens spider DB, creating or upgrading schema if needed
+pub fn init_db(file: &str) -> Result<Connection> {
+    let conn = Connection::open(file)?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pages(
+            url TEXT PRIMARY KEY, type TEXT, content_id INTEGER, skip_reason TEXT,
+            size INTEGER, sha256 TEXT, mime TEXT)",
+        (),
+    )?;
+    add_column(&conn, "pages", "skip_reason", "TEXT")?;
+    add_column(&conn, "pages", "size", "INTEGER")?;
+    add_column(&conn, "pages", "sha256", "TEXT")?;
+    add_column(&conn, "pages", "mime", "TEXT")?;
+    add_column(&conn, "pages", "encoding", "TEXT")?;
+    conn.execute(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS page_content USING fts4(content TEXT, tokenize=unicode61)",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS attributes(
+            url TEXT PRIMARY KEY, admin TEXT, mod_date TEXT, abstract TEXT, views TEXT)",
+        (),
+    )?;
+    // modules own their tables, after pages which some of them extend
+    Frontier::init(&conn)?;
+    Schedule::init(&conn)?;
+    rank::init(&conn)?;
+    browse::init(&conn)?;
+    tls::init(&conn)?;
+    export::init(&conn)?;
+    dedup::init(&conn)?;
+    Ok(conn)
+}
+
+/// Deletes content rows no page refers to anymore, left by older versions
+/// that inserted new content on every visit
+pub fn gc(conn: &Connection) -> Result<usize> {
+    Ok(conn.execute(
+        "DELETE FROM page_content
+         WHERE rowid NOT IN (SELECT content_id FROM pages WHERE content_id IS NOT NULL)",
+        (),
+    )?)
+}
+
+/// Adds column missing in DBs created by older versions
+pub(crate) fn add_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{table}') WHERE name = ?1"
+        ))?
+        .exists([column])?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            (),
+        )?;
+    }
+    Ok(())
+}
+
+/// Full-text query with optional filters
+#[derive(Debug, Clone)]
+pub struct Query {
+    /// FTS4 query, like `gopher AND (phlog OR blog)`
+    pub text: String,
+    pub host: Option<String>,
+    pub item_type: Option<GopherItem>,
+    /// Strings to put around matches in snippets
+    pub highlight: (String, String),
+    /// How much page rank boosts text relevance, 0 disables it
+    pub rank_weight: f64,
+}
+
+impl Query {
+    pub fn new(text: &str) -> Self {
+        Self {
+            text: String::from(text),
+            host: None,
+            item_type: None,
+            highlight: (String::from("["), String::from("]")),
+            rank_weight: 1.0,
+        }
+    }
+}
+
+impl From<&str> for Query {
+    fn from(text: &str) -> Self {
+        Self::new(text)
+    }
+}
+
+#[derive(Debug)]
+pub struct SearchResult {
+    /// Url as spider found and fetched it, not necessarily canonical
+    pub url: String,
+    pub item_type: GopherItem,
+    pub snippet: String,
+    pub score: f64,
+    /// Admin contact from gopher+ attributes
+    pub admin: Option<String>,
+    /// Modification date from gopher+ attributes
+    pub mod_date: Option<String>,
+}
+
+/// Searches index, best matches first.
+/// Text relevance is boosted by page rank computed by [`crate::rank::Ranker`].
+pub fn search(
+    conn: &Connection,
+    query: impl Into<Query>,
+    limit: usize,
+) -> Result<Vec<SearchResult>> {
+    let query = query.into();
+    conn.create_scalar_function(
+        "bm25",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| Ok(bm25(&ctx.get::<Vec<u8>>(0)?)),
+    )?;
+    let mut stmt = conn.prepare(
+        "SELECT coalesce(frontier.listed_url, pages.url), pages.type,
+                snippet(page_content, ?2, ?3, '...', -1, 16),
+                bm25(matchinfo(page_content, 'pcnalx')) * (1 + ?7 * coalesce(pages.rank, 0))
+                    AS score,
+                attributes.admin, attributes.mod_date
+         FROM page_content
+         JOIN pages ON pages.content_id = page_content.rowid
+         LEFT JOIN attributes ON attributes.url = pages.url
+         LEFT JOIN frontier ON frontier.url = pages.url
+         WHERE page_content MATCH ?1
+           AND (?4 IS NULL OR pages.type = ?4)
+           AND (?5 IS NULL OR pages.url LIKE '%://' || ?5 || ':%' ESCAPE '\\')
+         ORDER BY score DESC
+         LIMIT ?6",
+    )?;
+    let results = stmt
+        .query_map(
+            params![
+                query.text,
+                query.highlight.0,
+                query.highlight.1,
+                query.item_type.map(|t| t.to_string()),
+                query.host.as_deref().map(escape_like),
+                limit as i64,
+                query.rank_weight,
+            ],
+            |row| {
+                Ok(SearchResult {
+                    url: row.get(0)?,
+                    item_type: row
+                        .get::<_, String>(1)?
+                        .chars()
+                        .next()
+                        .map_or(GopherItem::Unknown, GopherItem::from),
+                    snippet: row.get(2)?,
+                    score: row.get(3)?,
+                    admin: row.get(4)?,
+                    mod_date: row.get(5)?,
+                })
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()
+        .context("querying index")?;
+    Ok(results)
+}
+
+/// Escapes LIKE wildcards, so user input matches literally
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Okapi BM25 over FTS4 `matchinfo(..., 'pcnalx')` output
+fn bm25(matchinfo: &[u8]) -> f64 {
+    const K1: f64 = 1.2;
+    const B: f64 = 0.75;
+    let info: Vec<f64> = matchinfo
+        .chunks_exact(4)
+        .map(|x| u32::from_ne_bytes([x[0], x[1], x[2], x[3]]) as f64)
+        .collect();
+    let (phrases, columns, rows) = (info[0] as usize, info[1] as usize, info[2]);
+    let avg_len = &info[3..3 + columns];
+    let len = &info[3 + columns..3 + 2 * columns];
+    let hits = &info[3 + 2 * columns..];
+
+    let mut score = 0.0;
+    for phrase in 0..phrases {
+        for column in 0..columns {
+            let x = 3 * (phrase * columns + column);
+            let (tf, docs) = (hits[x], hits[x + 2]);
+            // clamp idf so terms present in most of documents don't lower the score
+            let idf = ((rows - docs + 0.5) / (docs + 0.5)).ln().max(0.01);
+            let norm = 1.0 - B + B * len[column] / avg_len[column].max(1.0);
+            score += idf * tf * (K1 + 1.0) / (tf + K1 * norm);
+        }
+    }
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_page(conn: &Connection, url: &str, item_type: GopherItem, content: &str) {
+        conn.execute("INSERT INTO page_content(content) VALUES(?1)", [content])
+            .unwrap();
+        conn.execute(
+            "INSERT INTO pages (url, type, content_id) VALUES (?1, ?2, ?3)",
+            params![url, item_type.to_string(), conn.last_insert_rowid()],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn searching() {
+        let conn = init_db(":memory:").unwrap();
+        add_page(
+            &conn,
+            "gopher://a.org:70/0/phlog",
+            GopherItem::TextFile,
+            "my phlog about gopher, gopher and more gopher",
+        );
+        add_page(
+            &conn,
+            "gopher://b.org:70/1/",
+            GopherItem::Submenu,
+            "welcome to b.org, we have gopher stuff and lots of other things here",
+        );
+        add_page(
+            &conn,
+            "gopher://b.org:70/0/recipes",
+            GopherItem::TextFile,
+            "pancakes",
+        );
+        conn.execute(
+            "INSERT INTO attributes (url, admin) VALUES (?1, ?2)",
+            ["gopher://a.org:70/0/phlog", "Joe <joe@a.org>"],
+        )
+        .unwrap();
+
+        let results = search(&conn, "gopher", 10).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].url, "gopher://a.org:70/0/phlog");
+        assert_eq!(results[0].item_type, GopherItem::TextFile);
+        assert_eq!(results[0].admin.as_deref(), Some("Joe <joe@a.org>"));
+        assert!(results[0].snippet.contains("[gopher]"));
+        assert!(results[0].score > results[1].score);
+
+        let mut query = Query::new("gopher");
+        query.host = Some(String::from("b.org"));
+        let results = search(&conn, query.clone(), 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].url, "gopher://b.org:70/1/");
+
+        // wildcards in host match literally
+        query.host = Some(String::from("b_org"));
+        assert!(search(&conn, query.clone(), 10).unwrap().is_empty());
+        query.host = Some(String::from("b.org"));
+
+        query.item_type = Some(GopherItem::TextFile);
+        assert!(search(&conn, query, 10).unwrap().is_empty());
+
+        assert_eq!(search(&conn, "gopher", 1).unwrap().len(), 1);
+
+        // well linked page wins over better text match
+        conn.execute(
+            "UPDATE pages SET rank = 10 WHERE url = 'gopher://b.org:70/1/'",
+            (),
+        )
+        .unwrap();
+        assert_eq!(
+            search(&conn, "gopher", 1).unwrap()[0].url,
+            "gopher://b.org:70/1/"
+        );
+        let mut query = Query::new("gopher");
+        query.rank_weight = 0.0;
+        assert_eq!(
+            search(&conn, query, 1).unwrap()[0].url,
+            "gopher://a.org:70/0/phlog"
+        );
+    }
+}
@@ -3,4 +3,23 @@
 //! Spider for gopherspace
 //!
 
+pub mod browse;
+pub mod charset;
+pub mod client;
+pub mod cso;
+pub mod dedup;
+pub mod export;
+pub mod extract;
+pub mod frontier;
 pub mod gopher;
+pub mod gopher_plus;
+pub mod index;
+pub mod metrics;
+pub mod rank;
+pub mod recrawl;
+pub mod robots;
+pub mod scope;
+pub mod server;
+pub mod spider;
+pub mod testing;
+pub mod tls;
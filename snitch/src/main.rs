@@ -1,96 +1,399 @@
-use anyhow::Context;
-use clap::Parser;
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use rusqlite::{params, Connection};
-use snitch::gopher::{fetch_url, GopherItem, GopherURL, Menu};
-use std::collections::HashSet;
-use std::env;
-use std::io::Read;
-use std::thread;
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use regex::Regex;
+use snitch::browse::Browser;
+use snitch::client::ClientOptions;
+use snitch::dedup::Dedup;
+use snitch::export;
+use snitch::frontier::Frontier;
+use snitch::gopher::{GopherItem, GopherURL};
+use snitch::index::{self, init_db, Query};
+use snitch::rank::{self, Ranker};
+use snitch::recrawl::Schedule;
+use snitch::scope::Scope;
+use snitch::server::SearchServer;
+use snitch::spider::Spider;
+use snitch::tls::TlsMode;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 
-struct Site {
-    url: GopherURL,
-    text: Option<String>,
-    links: Option<Vec<GopherURL>>,
-}
-
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
-    #[arg(short = 'f', long, default_value = "./db.db")]
+    #[arg(short = 'f', long, default_value = "./db.db", global = true)]
     db_file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    crawl: CrawlArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Crawl gopherspace (default)
+    Crawl(Box<CrawlArgs>),
+    /// Search crawled pages
+    Search(SearchArgs),
+    /// Serve crawled pages as gopher type 7 search
+    Serve(ServeArgs),
+    /// Compute page ranks over crawled link graph
+    Rank(RankArgs),
+    /// Browse gopherspace interactively
+    Browse(BrowseArgs),
+    /// Export pages crawled with --keep-replies as WARC file or static gopher mirror
+    Export(ExportArgs),
+}
+
+#[derive(clap::Args)]
+struct CrawlArgs {
     #[arg(short, long)]
     seed_urls: Vec<String>,
     #[arg(short = 'd', long)]
     seed_from_db: bool,
-    #[arg(short, long, default_value_t = 10)]
+    /// Number of threads running fetch workers
+    #[arg(short, long, default_value_t = 2)]
     threads: usize,
+    /// Max number of requests in flight
+    #[arg(short, long, default_value_t = 64)]
+    concurrency: usize,
+    /// Max number of requests in flight to a single host
+    #[arg(long, default_value_t = 2)]
+    per_host: usize,
+    /// Min delay between requests to a single host, in milliseconds
+    #[arg(long, default_value_t = 500)]
+    host_delay: u64,
+    /// Max Crawl-delay of robots.txt obeyed, in seconds
+    #[arg(long, default_value_t = 60)]
+    max_host_delay: u64,
+    /// Connect timeout, in seconds
+    #[arg(long, default_value_t = 5)]
+    connect_timeout: u64,
+    /// Read timeout, in seconds
+    #[arg(long, default_value_t = 5)]
+    read_timeout: u64,
+    /// Max size of fetched item, in bytes
+    #[arg(long, default_value_t = 16 << 20)]
+    max_size: usize,
+    /// Don't fetch or honour robots.txt and caps.txt
+    #[arg(long)]
+    ignore_robots: bool,
+    /// TLS for gopher:// URLs: off, opportunistic (try TLS, fall back to plain text)
+    /// or strict (TLS only, certificates verified). gophers:// URLs always use TLS.
+    #[arg(long, default_value = "off")]
+    tls: TlsMode,
+    /// Give up on url after that many failed attempts
+    #[arg(long, default_value_t = 3)]
+    max_attempts: u32,
+    /// Delay before retrying failed url, in seconds, doubled on every attempt
+    #[arg(long, default_value_t = 60)]
+    retry_backoff: u64,
+    /// Keep running and revisit pages when they are due, instead of exiting
+    /// once frontier is empty
+    #[arg(long)]
+    recrawl: bool,
+    /// Initial recrawl interval, in hours, halved when page changes and doubled when it doesn't
+    #[arg(long, default_value_t = 24)]
+    recrawl_interval: u64,
+    /// Min recrawl interval, in hours
+    #[arg(long, default_value_t = 1)]
+    min_recrawl_interval: u64,
+    /// Max recrawl interval, in hours
+    #[arg(long, default_value_t = 720)]
+    max_recrawl_interval: u64,
+    /// Keep raw replies in DB along with extracted text, which export needs
+    #[arg(long)]
+    keep_replies: bool,
+    /// Serve crawl metrics over HTTP on this address, like 127.0.0.1:9170,
+    /// in Prometheus format at /metrics and as summary at /
+    #[arg(long)]
+    metrics_bind: Option<String>,
+    /// Index and follow pages with the same text as already indexed ones
+    #[arg(long)]
+    keep_duplicates: bool,
+    /// Max number of different simhash bits of near-duplicate pages, 0 to 7
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..8))]
+    max_duplicate_distance: u32,
+    /// Crawl scope file with Allow-host, Deny-host, Include-selector,
+    /// Exclude-selector, Max-depth and Max-pages-per-host lines,
+    /// options below are added to it
+    #[arg(long)]
+    scope: Option<PathBuf>,
+    /// Crawl only hosts matching one of these globs, like *.floodgap.com
+    #[arg(long)]
+    allow_host: Vec<String>,
+    /// Never crawl hosts matching these globs
+    #[arg(long)]
+    deny_host: Vec<String>,
+    /// Crawl only selectors matching one of these regexes
+    #[arg(long)]
+    include_selector: Vec<Regex>,
+    /// Never crawl selectors matching these regexes
+    #[arg(long)]
+    exclude_selector: Vec<Regex>,
+    /// Max number of links from seeds to crawled pages
+    #[arg(long)]
+    max_depth: Option<u32>,
+    /// Max number of pages crawled from a single host
+    #[arg(long)]
+    max_pages_per_host: Option<usize>,
+}
+
+#[derive(clap::Args)]
+struct SearchArgs {
+    /// FTS4 query, like `gopher AND (phlog OR blog)`
+    #[arg(required = true)]
+    query: Vec<String>,
+    #[arg(short = 'n', long, default_value_t = 20)]
+    limit: usize,
+    /// Show only pages from this host
+    #[arg(long)]
+    host: Option<String>,
+    /// Show only items of this gopher type, like 0 or 1
+    #[arg(long)]
+    item_type: Option<char>,
+}
+
+#[derive(clap::Args)]
+struct RankArgs {
+    /// Probability of following a link instead of jumping to a random page
+    #[arg(long, default_value_t = 0.85)]
+    damping: f64,
+    #[arg(long, default_value_t = 50)]
+    max_iterations: usize,
+    /// Number of top pages to print
+    #[arg(short = 'n', long, default_value_t = 20)]
+    limit: usize,
+    /// Print per-host link statistics instead of top pages
+    #[arg(long)]
+    hosts: bool,
+}
+
+#[derive(clap::Args)]
+struct BrowseArgs {
+    url: Option<String>,
+    /// Lines per page of text viewer
+    #[arg(long, default_value_t = 20)]
+    page_size: usize,
+    /// Where binary items are saved
+    #[arg(long, default_value = ".")]
+    download_dir: String,
+}
+
+#[derive(clap::Args)]
+struct ExportArgs {
+    /// WARC file or mirror directory
+    output: PathBuf,
+    /// Write static mirror with gophermaps instead of WARC file
+    #[arg(long)]
+    mirror: bool,
+    /// Export only pages from this host
+    #[arg(long)]
+    host: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct ServeArgs {
+    #[arg(short, long, default_value = "[::]:7070")]
+    bind: String,
+    /// Hostname put into menus, must be reachable by clients
+    #[arg(long, default_value = "localhost")]
+    hostname: String,
+    /// Max number of results per query
+    #[arg(short = 'n', long, default_value_t = 50)]
+    limit: usize,
 }
 
 fn main() -> Result<()> {
     env_logger::init();
     let args = Args::parse();
 
-    spider(args)?;
+    match args.command {
+        Some(Command::Crawl(crawl)) => spider(&args.db_file, *crawl)?,
+        Some(Command::Search(search)) => search_cmd(&args.db_file, search)?,
+        Some(Command::Serve(serve)) => serve_cmd(&args.db_file, serve)?,
+        Some(Command::Rank(rank)) => rank_cmd(&args.db_file, rank)?,
+        Some(Command::Browse(browse)) => browse_cmd(&args.db_file, browse)?,
+        Some(Command::Export(export)) => export_cmd(&args.db_file, export)?,
+        None => spider(&args.db_file, args.crawl)?,
+    }
 
     Ok(())
 }
 
-fn init_db(file: &str) -> Result<Connection> {
-    let conn = Connection::open(file)?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS pages(url TEXT PRIMARY KEY, type TEXT, content_id INTEGER)",
-        (),
-    )?;
-    conn.execute(
-        "CREATE VIRTUAL TABLE IF NOT EXISTS page_content USING fts4(content TEXT, tokenize=unicode61)",
-        (),
-    )?;
-    Ok(conn)
+fn search_cmd(db_file: &str, args: SearchArgs) -> Result<()> {
+    let conn = init_db(db_file)?;
+    let mut query = Query::new(&args.query.join(" "));
+    query.host = args.host;
+    query.item_type = args.item_type.map(GopherItem::from);
+    if std::io::stdout().is_terminal() {
+        query.highlight = (String::from("\x1b[1m"), String::from("\x1b[0m"));
+    }
+    for r in index::search(&conn, query, args.limit)? {
+        println!("{:.2}\t{}\t{}", r.score, r.item_type, r.url);
+        println!("\t{}", r.snippet.replace('\n', " "));
+        if let Some(admin) = r.admin {
+            println!("\tadmin: {admin}");
+        }
+        if let Some(date) = r.mod_date {
+            println!("\tmodified: {date}");
+        }
+    }
+    Ok(())
 }
 
-fn store_site(conn: &mut Connection, site: &Site) -> Result<()> {
-    let tx = conn.transaction()?;
-    if let Some(content) = &site.text {
-        tx.execute("INSERT INTO page_content(content) VALUES(?1)", [content])?;
-        tx.execute(
-            "INSERT INTO pages (url, type, content_id) VALUES (?1, ?2, ?3)
-             ON CONFLICT(url) DO UPDATE SET content_id=excluded.content_id",
-            params![
-                site.url.to_string(),
-                site.url.gopher_type.to_string(),
-                tx.last_insert_rowid()
-            ],
-        )?;
+fn rank_cmd(db_file: &str, args: RankArgs) -> Result<()> {
+    let conn = init_db(db_file)?;
+    let ranker = Ranker {
+        damping: args.damping,
+        max_iterations: args.max_iterations,
+        ..Ranker::default()
+    };
+    let ranked = ranker.rank(&conn)?;
+    log::info!("[rank] ranked {ranked} pages");
+
+    if args.hosts {
+        println!("in\tout\tdead\tpages\thost");
+        for h in rank::host_stats(&conn)?.iter().take(args.limit) {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                h.in_degree, h.out_degree, h.dead_links, h.pages, h.host
+            );
+        }
+        return Ok(());
+    }
+    let mut stmt = conn.prepare("SELECT rank, url FROM pages ORDER BY rank DESC LIMIT ?1")?;
+    let top = stmt.query_map([args.limit as i64], |row| {
+        Ok((row.get::<_, f64>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for page in top {
+        let (rank, url) = page?;
+        println!("{rank:.3}\t{url}");
     }
-    tx.commit()?;
     Ok(())
 }
 
-fn store_url(conn: &mut Connection, url: &GopherURL) -> Result<()> {
-    conn.execute(
-        "INSERT INTO pages (url, type) VALUES (?1, ?2)",
-        params![url.to_string(), url.gopher_type.to_string()],
-    )?;
+fn browse_cmd(db_file: &str, args: BrowseArgs) -> Result<()> {
+    let conn = init_db(db_file)?;
+    let start = args
+        .url
+        .map(|url| GopherURL::try_from(url.as_str()))
+        .transpose()?;
+    let mut browser = Browser::new(&conn, std::io::stdin().lock(), std::io::stdout());
+    browser.page_size = args.page_size;
+    browser.download_dir = PathBuf::from(args.download_dir);
+    browser.run(start)
+}
+
+fn export_cmd(db_file: &str, args: ExportArgs) -> Result<()> {
+    let conn = init_db(db_file)?;
+    if export::reply_count(&conn)? == 0 {
+        bail!("no raw replies in {db_file}, crawl with --keep-replies to export pages");
+    }
+    let host = args.host.as_deref();
+    let count = if args.mirror {
+        export::mirror(&conn, &args.output, host)?
+    } else {
+        let file = std::fs::File::create(&args.output)
+            .context(format!("creating {}", args.output.display()))?;
+        export::warc(&conn, std::io::BufWriter::new(file), host)?
+    };
+    log::info!(
+        "[export] exported {count} pages to {}",
+        args.output.display()
+    );
     Ok(())
 }
 
-fn spider(mut args: Args) -> Result<()> {
-    let mut visited: HashSet<GopherURL> = HashSet::new();
-    let (urls_tx, urls_rx) = unbounded();
-    let (sites_tx, sites_rx) = unbounded();
-    let mut workers = Vec::with_capacity(args.threads);
-    let mut conn = init_db(&args.db_file)?;
-
-    for i in 0..workers.capacity() {
-        let urls_rx = urls_rx.clone();
-        let sites_tx = sites_tx.clone();
-        workers.push(thread::spawn(move || worker(i, urls_rx, sites_tx)));
+fn serve_cmd(db_file: &str, args: ServeArgs) -> Result<()> {
+    let conn = init_db(db_file)?;
+    smol::block_on(async {
+        let listener = smol::net::TcpListener::bind(args.bind.as_str())
+            .await
+            .context(format!("binding to {}", args.bind))?;
+        let mut server = SearchServer::new(conn, &args.hostname, listener.local_addr()?.port());
+        server.limit = args.limit;
+        Arc::new(server).serve(listener).await
+    })
+}
+
+fn spider(db_file: &str, mut args: CrawlArgs) -> Result<()> {
+    if args.min_recrawl_interval > args.max_recrawl_interval {
+        bail!(
+            "min recrawl interval {}h is over max one {}h",
+            args.min_recrawl_interval,
+            args.max_recrawl_interval
+        );
     }
+    let conn = init_db(db_file)?;
+    let hours = |h: u64| Duration::from_secs(h * 60 * 60);
+    let mut scope = match &args.scope {
+        Some(path) => Scope::load(path)?,
+        None => Scope::default(),
+    };
+    let lowercase = |hosts: &[String]| hosts.iter().map(|h| h.to_lowercase()).collect::<Vec<_>>();
+    scope.allow_hosts.extend(lowercase(&args.allow_host));
+    scope.deny_hosts.extend(lowercase(&args.deny_host));
+    scope
+        .include_selectors
+        .extend(args.include_selector.iter().cloned());
+    scope
+        .exclude_selectors
+        .extend(args.exclude_selector.iter().cloned());
+    scope.max_depth = args.max_depth.or(scope.max_depth);
+    scope.max_pages_per_host = args.max_pages_per_host.or(scope.max_pages_per_host);
+    let spider = Spider {
+        client: ClientOptions {
+            max_in_flight: args.concurrency,
+            max_in_flight_per_host: args.per_host,
+            host_delay: Duration::from_millis(args.host_delay),
+            max_host_delay: Duration::from_secs(args.max_host_delay),
+            connect_timeout: Duration::from_secs(args.connect_timeout),
+            read_timeout: Duration::from_secs(args.read_timeout),
+            max_reply_size: args.max_size,
+            tls: args.tls,
+        },
+        frontier: Frontier {
+            max_attempts: args.max_attempts,
+            backoff: Duration::from_secs(args.retry_backoff),
+        },
+        schedule: Schedule {
+            initial_interval: hours(args.recrawl_interval),
+            min_interval: hours(args.min_recrawl_interval),
+            max_interval: hours(args.max_recrawl_interval),
+        },
+        threads: args.threads,
+        ignore_robots: args.ignore_robots,
+        recrawl: args.recrawl,
+        keep_replies: args.keep_replies,
+        metrics_bind: args.metrics_bind.clone(),
+        dedup: match args.keep_duplicates {
+            true => None,
+            false => Some(Dedup {
+                max_distance: args.max_duplicate_distance,
+                ..Dedup::default()
+            }),
+        },
+        scope,
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let s = stop.clone();
+    ctrlc::set_handler(move || {
+        if s.swap(true, Ordering::SeqCst) {
+            log::warn!("[spider] interrupted again, exiting right away");
+            std::process::exit(130);
+        }
+        log::warn!("[spider] interrupted, waiting for urls in flight");
+    })
+    .context("setting SIGINT handler")?;
 
     if args.seed_from_db {
+        // DBs created before frontier existed have unfetched menus only in pages table
         let mut stmt =
             conn.prepare("SELECT url FROM pages WHERE type = ?1 AND content_id IS NULL")?;
         let mut count = 0;
@@ -104,89 +407,13 @@ fn spider(mut args: Args) -> Result<()> {
             });
         log::info!("loaded {count} seed urls from DB");
     }
+    let seeds: Vec<GopherURL> = args
+        .seed_urls
+        .iter()
+        .filter_map(|url| GopherURL::try_from(url.as_str()).ok())
+        .collect();
 
-    for url in args.seed_urls {
-        if let Ok(url) = GopherURL::try_from(url.as_str()) {
-            visited.insert(url.clone());
-            urls_tx.send(url).context("sending seed url")?;
-        }
-    }
-
-    loop {
-        let site = sites_rx.recv().context("receiving site")?;
-        if site.url.selector.chars().filter(|c| *c == '/').count() >= 50 {
-            // limit selector depth
-            continue;
-        }
-        store_site(&mut conn, &site)
-            .unwrap_or_else(|e| log::error!("[spider] storing site data: {e:#}"));
-        log::info!(
-            "[spider] {} urls in queue, {} urls visited",
-            urls_tx.len(),
-            visited.len()
-        );
-        for url in site.links.into_iter().flatten() {
-            if !visited.insert(url.clone()) {
-                continue;
-            }
-            store_url(&mut conn, &url)
-                .unwrap_or_else(|e| log::error!("[spider] storing url {url}: {e:#}"));
-            urls_tx
-                .send(url)
-                .unwrap_or_else(|e| log::error!("[spider] sending url to worker: {e:#}"));
-        }
-    }
-}
-
-fn worker(id: usize, urls: Receiver<GopherURL>, sites: Sender<Site>) {
-    log::info!("worker {id} started");
-    loop {
-        if let Ok(url) = urls.recv() {
-            match get_url(&url) {
-                Ok(site) => sites
-                    .send(site)
-                    .unwrap_or_else(|e| log::error!("failed to sending {url}: {e:#}")),
-                Err(e) => {
-                    log::error!("failed to fetch {url}: {e:#}");
-                }
-            }
-            log::info!("[worker {id}] fetched {url}");
-        }
-    }
-}
-
-fn get_url(url: &GopherURL) -> Result<Site> {
-    match url.gopher_type {
-        GopherItem::TextFile => {
-            let mut text = String::new();
-            fetch_url(&url, None)
-                .context("fetching text file")?
-                .read_to_string(&mut text)
-                .context("reading text file")?;
-            Ok(Site {
-                text: Some(text),
-                url: url.clone(),
-                links: None,
-            })
-        }
-        GopherItem::Submenu => {
-            let site = Menu::from_url(&url, None).context("fetching menu")?;
-            Ok(Site {
-                text: Some(
-                    site.items
-                        .iter()
-                        .map(|x| x.label.clone())
-                        .collect::<Vec<String>>()
-                        .join("\n"),
-                ),
-                links: Some(site.items.iter().map(|x| x.url.clone()).flatten().collect()),
-                url: url.clone(),
-            })
-        }
-        _ => Ok(Site {
-            url: url.clone(),
-            text: None,
-            links: None,
-        }),
-    }
+    let summary = spider.run(conn, &seeds, stop)?;
+    print!("{summary}");
+    Ok(())
 }
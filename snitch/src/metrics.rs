@@ -0,0 +1,378 @@
+//! Crawl statistics, served over HTTP in Prometheus text format
+//! and printed as summary when crawl ends
+
+use anyhow::{Context, Result};
+use smol::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    Timer,
+};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write as _};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of fetch latency histogram buckets, in seconds
+pub const BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// How often queue depth is sampled
+const QUEUE_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Longer history is thinned out, so it still covers whole crawl
+const MAX_QUEUE_SAMPLES: usize = 1000;
+
+const MAX_REQUEST: u64 = 8192;
+
+/// Point-in-time copy of crawl statistics
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    /// Time since crawl started
+    pub elapsed: Duration,
+    /// Number of requests per latency bucket, the last one is for
+    /// requests slower than all of [`BUCKETS`]
+    pub latency: [u64; BUCKETS.len() + 1],
+    /// Total time spent in requests
+    pub latency_sum: Duration,
+    /// Successful requests
+    pub fetched: u64,
+    /// Bytes received in successful requests
+    pub bytes: u64,
+    /// Failed requests by (host, kind of error)
+    pub errors: BTreeMap<(String, &'static str), u64>,
+    pub queued: u64,
+    pub in_flight: u64,
+    pub visited: u64,
+    /// Queued urls over time, as (seconds since start, queued)
+    pub queue_depth: Vec<(u64, u64)>,
+}
+
+/// Statistics collected by client and spider
+pub struct Metrics {
+    started: Instant,
+    state: Mutex<Snapshot>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            state: Mutex::new(Snapshot::default()),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_fetch(&self, latency: Duration, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.observe(latency);
+        state.fetched += 1;
+        state.bytes += bytes as u64;
+    }
+
+    /// Records failed request, `kind` is short name like `timeout`
+    pub fn record_error(&self, latency: Duration, host: &str, kind: &'static str) {
+        let mut state = self.state.lock().unwrap();
+        state.observe(latency);
+        *state.errors.entry((host.to_lowercase(), kind)).or_default() += 1;
+    }
+
+    /// Updates frontier gauges, sampling queue depth every [`QUEUE_SAMPLE_INTERVAL`]
+    pub fn set_queue(&self, queued: u64, in_flight: u64, visited: u64) {
+        let mut state = self.state.lock().unwrap();
+        (state.queued, state.in_flight, state.visited) = (queued, in_flight, visited);
+        let now = self.started.elapsed().as_secs();
+        let due = state
+            .queue_depth
+            .last()
+            .is_none_or(|(t, _)| now >= t + QUEUE_SAMPLE_INTERVAL.as_secs());
+        if due {
+            state.queue_depth.push((now, queued));
+            if state.queue_depth.len() > MAX_QUEUE_SAMPLES {
+                let mut i = 0;
+                state.queue_depth.retain(|_| {
+                    i += 1;
+                    i % 2 == 1
+                });
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            elapsed: self.started.elapsed(),
+            ..self.state.lock().unwrap().clone()
+        }
+    }
+
+    /// Serves `/metrics` in Prometheus text format and summary at `/`
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        log::info!(
+            "[metrics] serving on http://{}/metrics",
+            listener.local_addr()?
+        );
+        loop {
+            // nothing restarts the listener, so errors must not end it
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("[metrics] accepting connection: {e}");
+                    Timer::after(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let metrics = self.clone();
+            smol::spawn(async move {
+                if let Err(e) = metrics.handle(stream).await {
+                    log::warn!("[metrics] handling request from {peer}: {e:#}");
+                }
+            })
+            .detach();
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.clone().take(MAX_REQUEST));
+        let mut request = String::new();
+        reader
+            .read_line(&mut request)
+            .await
+            .context("reading request")?;
+        // headers are read only to not reset connection with unread data
+        let mut header = String::new();
+        while reader.read_line(&mut header).await? > 2 {
+            header.clear();
+        }
+        let (status, content_type, body) = match request.split(' ').nth(1) {
+            Some("/metrics") => (
+                "200 OK",
+                "text/plain; version=0.0.4",
+                self.snapshot().prometheus(),
+            ),
+            Some("/") => ("200 OK", "text/plain", self.snapshot().to_string()),
+            _ => ("404 Not Found", "text/plain", String::from("not found\n")),
+        };
+        let reply = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream
+            .write_all(reply.as_bytes())
+            .await
+            .context("writing reply")?;
+        stream.flush().await.context("flushing reply")?;
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    fn observe(&mut self, latency: Duration) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|b| latency.as_secs_f64() <= *b)
+            .unwrap_or(BUCKETS.len());
+        self.latency[bucket] += 1;
+        self.latency_sum += latency;
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.latency.iter().sum()
+    }
+
+    pub fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    /// Upper bound of latency bucket the quantile falls into,
+    /// infinite if it is slower than all buckets
+    pub fn latency_quantile(&self, q: f64) -> Option<f64> {
+        let total = self.requests();
+        if total == 0 {
+            return None;
+        }
+        let rank = (q * total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.latency.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(BUCKETS.get(i).copied().unwrap_or(f64::INFINITY));
+            }
+        }
+        None
+    }
+
+    /// Formats statistics in Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let mut s = String::new();
+        s.push_str(
+            "# HELP snitch_fetch_duration_seconds Time to fetch gopher item\n\
+             # TYPE snitch_fetch_duration_seconds histogram\n",
+        );
+        let mut cumulative = 0;
+        for (i, count) in self.latency.iter().enumerate() {
+            cumulative += count;
+            let le = BUCKETS
+                .get(i)
+                .map_or(String::from("+Inf"), |b| b.to_string());
+            let _ = writeln!(
+                s,
+                "snitch_fetch_duration_seconds_bucket{{le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            s,
+            "snitch_fetch_duration_seconds_sum {}\nsnitch_fetch_duration_seconds_count {cumulative}",
+            self.latency_sum.as_secs_f64()
+        );
+        let counters = [
+            ("snitch_fetched_total", "Successful fetches", self.fetched),
+            (
+                "snitch_downloaded_bytes_total",
+                "Bytes downloaded",
+                self.bytes,
+            ),
+        ];
+        let gauges = [
+            (
+                "snitch_urls_queued",
+                "Urls waiting in frontier",
+                self.queued,
+            ),
+            (
+                "snitch_urls_in_flight",
+                "Urls being fetched",
+                self.in_flight,
+            ),
+            ("snitch_urls_visited", "Urls fetched", self.visited),
+        ];
+        for (kind, values) in [("counter", counters.as_slice()), ("gauge", &gauges)] {
+            for (name, help, value) in values {
+                let _ = write!(
+                    s,
+                    "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+                );
+            }
+        }
+        let _ = write!(
+            s,
+            "# HELP snitch_fetch_errors_total Failed fetches\n\
+             # TYPE snitch_fetch_errors_total counter\n"
+        );
+        for ((host, kind), count) in &self.errors {
+            let host = host
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            let _ = writeln!(
+                s,
+                "snitch_fetch_errors_total{{host=\"{host}\",kind=\"{kind}\"}} {count}"
+            );
+        }
+        s
+    }
+}
+
+/// Human readable summary
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "crawled for {:.0?}", self.elapsed)?;
+        writeln!(
+            f,
+            "  {} requests, {} fetched, {} failed, {:.1} MiB downloaded",
+            self.requests(),
+            self.fetched,
+            self.error_count(),
+            self.bytes as f64 / (1 << 20) as f64
+        )?;
+        if let (Some(p50), Some(p90), Some(p99)) = (
+            self.latency_quantile(0.5),
+            self.latency_quantile(0.9),
+            self.latency_quantile(0.99),
+        ) {
+            writeln!(f, "  latency p50 <= {p50}s, p90 <= {p90}s, p99 <= {p99}s")?;
+        }
+        let peak = self
+            .queue_depth
+            .iter()
+            .map(|(_, q)| *q)
+            .chain([self.queued])
+            .max()
+            .unwrap_or_default();
+        writeln!(
+            f,
+            "  {} urls visited, {} queued, {} in flight, queue peaked at {peak}",
+            self.visited, self.queued, self.in_flight
+        )?;
+        let mut kinds: BTreeMap<&str, u64> = BTreeMap::new();
+        let mut hosts: BTreeMap<&str, u64> = BTreeMap::new();
+        for ((host, kind), count) in &self.errors {
+            *kinds.entry(kind).or_default() += count;
+            *hosts.entry(host).or_default() += count;
+        }
+        if !kinds.is_empty() {
+            let kinds: Vec<String> = kinds.iter().map(|(k, n)| format!("{k} {n}")).collect();
+            writeln!(f, "  errors: {}", kinds.join(", "))?;
+            let mut hosts: Vec<_> = hosts.into_iter().collect();
+            hosts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+            for (host, count) in hosts.iter().take(10) {
+                writeln!(f, "    {count}\t{host}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn collecting_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.record_fetch(Duration::from_millis(30), 1000);
+        metrics.record_fetch(Duration::from_millis(80), 500);
+        metrics.record_fetch(Duration::from_secs(60), 0);
+        metrics.record_error(Duration::from_secs(5), "Slow.example.org", "timeout");
+        metrics.record_error(Duration::from_secs(5), "slow.example.org", "timeout");
+        metrics.set_queue(10, 2, 3);
+        metrics.set_queue(4, 2, 9);
+
+        let s = metrics.snapshot();
+        assert_eq!((s.requests(), s.fetched, s.bytes), (5, 3, 1500));
+        assert_eq!(s.errors[&(String::from("slow.example.org"), "timeout")], 2);
+        assert_eq!(s.latency_quantile(0.5), Some(5.0));
+        assert_eq!(s.latency_quantile(1.0), Some(f64::INFINITY));
+        // second update came too soon to be sampled
+        assert_eq!(s.queue_depth, vec![(0, 10)]);
+        assert_eq!(s.queued, 4);
+        let summary = s.to_string();
+        assert!(summary.contains("queue peaked at 10"), "{summary}");
+        assert!(summary.contains("errors: timeout 2\n"), "{summary}");
+
+        let text = s.prometheus();
+        assert!(text.contains("snitch_fetch_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(text.contains("snitch_fetch_duration_seconds_bucket{le=\"0.1\"} 2\n"));
+        assert!(text.contains("snitch_fetch_duration_seconds_bucket{le=\"+Inf\"} 5\n"));
+        assert!(text.contains("snitch_downloaded_bytes_total 1500\n"));
+        assert!(text
+            .contains("snitch_fetch_errors_total{host=\"slow.example.org\",kind=\"timeout\"} 2\n"));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = TcpListener::try_from(listener).unwrap();
+        smol::spawn(metrics.serve(listener)).detach();
+        let get = |path: &str| {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        };
+        let reply = get("/metrics");
+        assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(reply.ends_with(&text));
+        assert!(get("/").contains("5 requests, 3 fetched, 2 failed"));
+        assert!(get("/nothing").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,282 @@
+use crate::frontier::State;
+use crate::gopher::{ExternalLink, GopherURL};
+use crate::index::add_column;
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+
+/// Creates links table and rank column
+pub fn init(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS links(
+            src TEXT NOT NULL, dst TEXT NOT NULL, src_host TEXT NOT NULL, dst_host TEXT NOT NULL,
+            PRIMARY KEY (src, dst))",
+        (),
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS links_dst ON links(dst)", ())?;
+    add_column(conn, "links", "external", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(conn, "pages", "rank", "REAL")?;
+    Ok(())
+}
+
+/// Replaces outgoing links of the page with ones found on its last visit.
+/// External links are kept out of ranking.
+pub fn store_links(
+    conn: &Connection,
+    src: &GopherURL,
+    links: &[GopherURL],
+    external: &[ExternalLink],
+) -> Result<()> {
+    let src_url = src.to_string();
+    conn.execute("DELETE FROM links WHERE src = ?1", [&src_url])?;
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO links (src, dst, src_host, dst_host, external)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+    for dst in links {
+        stmt.execute(params![src_url, dst.to_string(), src.host, dst.host, false])?;
+    }
+    for dst in external {
+        stmt.execute(params![
+            src_url,
+            dst.to_string(),
+            src.host,
+            dst.host(),
+            true
+        ])?;
+    }
+    Ok(())
+}
+
+/// PageRank parameters
+#[derive(Debug, Clone)]
+pub struct Ranker {
+    /// Probability of following a link instead of jumping to a random page
+    pub damping: f64,
+    pub max_iterations: usize,
+    /// Iteration stops when sum of rank changes falls below that
+    pub tolerance: f64,
+}
+
+impl Default for Ranker {
+    fn default() -> Self {
+        Self {
+            damping: 0.85,
+            max_iterations: 50,
+            tolerance: 1e-6,
+        }
+    }
+}
+
+impl Ranker {
+    /// Computes PageRank over links table and stores it in `pages.rank`.
+    /// Stored rank is log-scaled as `ln(1 + N * PR)`, so it doesn't depend on
+    /// graph size and average page gets about 0.7. Returns number of ranked pages.
+    pub fn rank(&self, conn: &Connection) -> Result<usize> {
+        let mut ids: HashMap<String, usize> = HashMap::new();
+        let mut urls = Vec::new();
+        let mut id = |url: String| {
+            *ids.entry(url).or_insert_with_key(|url| {
+                urls.push(url.clone());
+                urls.len() - 1
+            })
+        };
+        let pages: Vec<String> = conn
+            .prepare("SELECT url FROM pages")?
+            .query_map((), |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for url in pages {
+            id(url);
+        }
+        let edges: Vec<(String, String)> = conn
+            .prepare("SELECT src, dst FROM links WHERE src != dst AND NOT external")?
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let edges: Vec<(usize, usize)> = edges
+            .into_iter()
+            .map(|(src, dst)| (id(src), id(dst)))
+            .collect();
+
+        let ranks = self.page_rank(urls.len(), &edges);
+        let n = urls.len() as f64;
+        let tx = conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare("UPDATE pages SET rank = ?2 WHERE url = ?1")?;
+            for (url, pr) in urls.iter().zip(ranks) {
+                stmt.execute(params![url, (1.0 + n * pr).ln()])?;
+            }
+        }
+        tx.commit()?;
+        Ok(urls.len())
+    }
+
+    /// Power iteration, rank of dangling pages is spread evenly over all pages
+    fn page_rank(&self, n: usize, edges: &[(usize, usize)]) -> Vec<f64> {
+        if n == 0 {
+            return Vec::new();
+        }
+        let mut out_degree = vec![0usize; n];
+        for &(src, _) in edges {
+            out_degree[src] += 1;
+        }
+        let mut ranks = vec![1.0 / n as f64; n];
+        for i in 0..self.max_iterations {
+            let dangling: f64 = (0..n)
+                .filter(|&p| out_degree[p] == 0)
+                .map(|p| ranks[p])
+                .sum();
+            let base = (1.0 - self.damping + self.damping * dangling) / n as f64;
+            let mut next = vec![base; n];
+            for &(src, dst) in edges {
+                next[dst] += self.damping * ranks[src] / out_degree[src] as f64;
+            }
+            let delta: f64 = ranks.iter().zip(&next).map(|(a, b)| (a - b).abs()).sum();
+            ranks = next;
+            if delta < self.tolerance {
+                log::debug!("[rank] converged after {} iterations", i + 1);
+                break;
+            }
+        }
+        ranks
+    }
+}
+
+/// Link statistics of a single host
+#[derive(Debug, Default, PartialEq)]
+pub struct HostStats {
+    pub host: String,
+    pub pages: usize,
+    /// Links from other hosts to this one
+    pub in_degree: usize,
+    /// Links from this host to other ones
+    pub out_degree: usize,
+    /// Links from this host to urls spider failed to fetch
+    pub dead_links: usize,
+}
+
+/// Per-host link statistics, hosts with most incoming links first
+pub fn host_stats(conn: &Connection) -> Result<Vec<HostStats>> {
+    let mut stats: HashMap<String, HostStats> = HashMap::new();
+    fn entry(stats: &mut HashMap<String, HostStats>, host: String) -> &mut HostStats {
+        stats.entry(host.clone()).or_insert_with(|| HostStats {
+            host,
+            ..HostStats::default()
+        })
+    }
+
+    let pages: Vec<String> = conn
+        .prepare("SELECT url FROM pages")?
+        .query_map((), |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    for url in pages {
+        if let Ok(url) = GopherURL::try_from(url.as_str()) {
+            entry(&mut stats, url.host).pages += 1;
+        }
+    }
+    let mut stmt = conn.prepare(
+        "SELECT src_host, dst_host, frontier.state = ?1
+         FROM links LEFT JOIN frontier ON frontier.url = links.dst WHERE NOT external",
+    )?;
+    let links = stmt.query_map([State::Failed.as_str()], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<bool>>(2)?.unwrap_or(false),
+        ))
+    })?;
+    for link in links {
+        let (src, dst, dead) = link?;
+        if dead {
+            entry(&mut stats, src.clone()).dead_links += 1;
+        }
+        if src != dst {
+            entry(&mut stats, src).out_degree += 1;
+            entry(&mut stats, dst).in_degree += 1;
+        }
+    }
+
+    let mut stats: Vec<HostStats> = stats.into_values().collect();
+    stats.sort_by(|a, b| b.in_degree.cmp(&a.in_degree).then(a.host.cmp(&b.host)));
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontier::Frontier;
+    use crate::index::init_db;
+
+    fn url(s: &str) -> GopherURL {
+        GopherURL::try_from(s).unwrap()
+    }
+
+    #[test]
+    fn ranking() {
+        let conn = init_db(":memory:").unwrap();
+        let a = url("gopher://a.org/1/");
+        let b = url("gopher://b.org/1/");
+        let c = url("gopher://c.org/1/");
+        let dead = url("gopher://c.org/0/gone");
+        for u in [&a, &b, &c, &dead] {
+            conn.execute(
+                "INSERT INTO pages (url, type) VALUES (?1, '1')",
+                [u.to_string()],
+            )
+            .unwrap();
+        }
+        let telnet = ExternalLink::Telnet {
+            host: String::from("bbs.org"),
+            port: 23,
+            login: String::new(),
+        };
+        store_links(&conn, &b, &[a.clone(), c.clone()], &[]).unwrap();
+        store_links(&conn, &c, &[a.clone(), dead.clone()], &[telnet]).unwrap();
+        store_links(&conn, &a, &[c.clone(), b.clone()], &[]).unwrap();
+        // links are replaced on recrawl
+        store_links(&conn, &a, &[b.clone(), a.clone()], &[]).unwrap();
+        let frontier = Frontier::default();
+        frontier.failed(&conn, &dead, "connection refused").unwrap();
+
+        assert_eq!(Ranker::default().rank(&conn).unwrap(), 4);
+        let rank = |u: &GopherURL| -> f64 {
+            conn.query_row(
+                "SELECT rank FROM pages WHERE url = ?1",
+                [u.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        // b is the only link of well linked a
+        assert!(rank(&b) > rank(&a));
+        assert!(rank(&a) > rank(&c));
+        assert!(rank(&c) > rank(&dead));
+
+        let stats = host_stats(&conn).unwrap();
+        assert_eq!(
+            stats[0],
+            HostStats {
+                host: String::from("a.org"),
+                pages: 1,
+                in_degree: 2,
+                out_degree: 1,
+                dead_links: 0,
+            }
+        );
+        let c_stats = stats.iter().find(|s| s.host == "c.org").unwrap();
+        assert_eq!((c_stats.pages, c_stats.dead_links), (2, 1));
+        assert_eq!((c_stats.in_degree, c_stats.out_degree), (1, 1));
+        // external links are stored, but neither ranked nor counted
+        assert!(!stats.iter().any(|s| s.host == "bbs.org"));
+        let external: String = conn
+            .query_row("SELECT dst FROM links WHERE external", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(external, "telnet://bbs.org:23");
+    }
+
+    #[test]
+    fn page_rank_sums_to_one() {
+        let ranks = Ranker::default().page_rank(3, &[(0, 1), (1, 2), (2, 0), (0, 2)]);
+        assert!((ranks.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(ranks[2] > ranks[1]);
+    }
+}
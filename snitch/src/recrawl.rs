@@ -0,0 +1,183 @@
+use crate::frontier::{now, State};
+use crate::gopher::GopherURL;
+use crate::index::add_column;
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::time::Duration;
+
+/// Adaptive recrawl schedule: pages that change get revisited more often,
+/// interval is halved on every change and doubled when content is the same.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub initial_interval: Duration,
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            initial_interval: Duration::from_secs(24 * 60 * 60),
+            min_interval: Duration::from_secs(60 * 60),
+            max_interval: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Result of comparing fetched content with stored one
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    /// Page was never fetched before
+    New,
+    Changed,
+    Unchanged,
+}
+
+impl Schedule {
+    /// Adds freshness columns to pages
+    pub fn init(conn: &Connection) -> Result<()> {
+        add_column(conn, "pages", "last_fetched", "INTEGER")?;
+        add_column(conn, "pages", "content_hash", "TEXT")?;
+        add_column(conn, "pages", "change_count", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column(conn, "pages", "recrawl_interval", "INTEGER")?;
+        add_column(conn, "pages", "next_fetch", "INTEGER")?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS pages_next_fetch ON pages(next_fetch)",
+            (),
+        )?;
+        Ok(())
+    }
+
+    /// Records fetch of the page and schedules next one.
+    /// Page must be in pages table already.
+    pub fn record(&self, conn: &Connection, url: &GopherURL, content_hash: &str) -> Result<Change> {
+        let url = url.to_string();
+        let prev: Option<(Option<String>, Option<u64>)> = conn
+            .query_row(
+                "SELECT content_hash, recrawl_interval FROM pages WHERE url = ?1",
+                [&url],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (change, interval) = match prev {
+            Some((Some(hash), Some(interval))) => {
+                let interval = Duration::from_secs(interval);
+                match hash == content_hash {
+                    true => (Change::Unchanged, interval * 2),
+                    false => (Change::Changed, interval / 2),
+                }
+            }
+            _ => (Change::New, self.initial_interval),
+        };
+        // not clamp, which panics when bounds are the wrong way round
+        let interval = interval
+            .max(self.min_interval)
+            .min(self.max_interval)
+            .as_secs();
+        let now = now();
+        conn.execute(
+            "UPDATE pages SET last_fetched = ?2, content_hash = ?3, recrawl_interval = ?4,
+                next_fetch = ?5, change_count = change_count + ?6
+             WHERE url = ?1",
+            params![
+                url,
+                now,
+                content_hash,
+                interval,
+                now + interval,
+                (change == Change::Changed) as u32
+            ],
+        )?;
+        Ok(change)
+    }
+
+    /// Puts pages due for recrawl back to frontier queue
+    pub fn requeue_due(&self, conn: &Connection) -> Result<usize> {
+        Ok(conn.execute(
+            "UPDATE frontier SET state = ?1
+             WHERE state = ?2 AND url IN (SELECT url FROM pages WHERE next_fetch <= ?3)",
+            params![State::Queued.as_str(), State::Done.as_str(), now()],
+        )?)
+    }
+
+    /// Time left until next page is due for recrawl, if there are any
+    pub fn next_due(&self, conn: &Connection) -> Result<Option<Duration>> {
+        let next: Option<u64> = conn.query_row(
+            "SELECT min(next_fetch) FROM pages WHERE next_fetch IS NOT NULL",
+            (),
+            |row| row.get(0),
+        )?;
+        Ok(next.map(|t| Duration::from_secs(t.saturating_sub(now()))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontier::Frontier;
+    use crate::index::init_db;
+
+    #[test]
+    fn scheduling() {
+        let conn = init_db(":memory:").unwrap();
+        let schedule = Schedule {
+            initial_interval: Duration::from_secs(100),
+            min_interval: Duration::from_secs(50),
+            max_interval: Duration::from_secs(300),
+        };
+        let url = GopherURL::try_from("gopher://a.org/0/news").unwrap();
+        conn.execute(
+            "INSERT INTO pages (url, type) VALUES (?1, '0')",
+            [url.to_string()],
+        )
+        .unwrap();
+        let interval = || -> u64 {
+            conn.query_row(
+                "SELECT recrawl_interval FROM pages WHERE url = ?1",
+                [url.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(schedule.record(&conn, &url, "a").unwrap(), Change::New);
+        assert_eq!(interval(), 100);
+        assert_eq!(
+            schedule.record(&conn, &url, "a").unwrap(),
+            Change::Unchanged
+        );
+        assert_eq!(interval(), 200);
+        assert_eq!(
+            schedule.record(&conn, &url, "a").unwrap(),
+            Change::Unchanged
+        );
+        assert_eq!(interval(), 300);
+        assert_eq!(schedule.record(&conn, &url, "b").unwrap(), Change::Changed);
+        assert_eq!(interval(), 150);
+        assert_eq!(schedule.record(&conn, &url, "c").unwrap(), Change::Changed);
+        assert_eq!(schedule.record(&conn, &url, "d").unwrap(), Change::Changed);
+        assert_eq!(interval(), 50);
+        let changes: u32 = conn
+            .query_row("SELECT change_count FROM pages", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(changes, 3);
+
+        let frontier = Frontier::default();
+        frontier.push(&conn, &url, 0).unwrap();
+        frontier.take(&conn, 1).unwrap();
+        frontier.done(&conn, &url).unwrap();
+        assert_eq!(schedule.requeue_due(&conn).unwrap(), 0);
+        conn.execute("UPDATE pages SET next_fetch = 0", ()).unwrap();
+        assert_eq!(schedule.next_due(&conn).unwrap(), Some(Duration::ZERO));
+        assert_eq!(schedule.requeue_due(&conn).unwrap(), 1);
+        assert_eq!(frontier.take(&conn, 1).unwrap(), vec![url.clone()]);
+
+        // bounds the wrong way round don't panic, max wins
+        let odd = Schedule {
+            min_interval: Duration::from_secs(400),
+            ..schedule
+        };
+        assert_eq!(odd.record(&conn, &url, "e").unwrap(), Change::Changed);
+        assert_eq!(interval(), 300);
+    }
+}
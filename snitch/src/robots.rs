@@ -0,0 +1,287 @@
+use crate::client::Client;
+use crate::gopher::{GopherItem, GopherURL};
+use smol::lock::{Mutex, OnceCell};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Name spider looks for in `User-agent` lines
+pub const USER_AGENT: &str = "snitch";
+
+/// How long crawl policy of the host is cached, unless caps.txt says otherwise
+const POLICY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Rules from robots.txt that apply to us
+#[derive(Debug, Default)]
+pub struct Robots {
+    rules: Vec<Rule>,
+    pub crawl_delay: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    prefix: String,
+}
+
+impl Robots {
+    /// Parses robots.txt, picking group for `agent` or `*` group if there is none
+    pub fn parse(text: &str, agent: &str) -> Self {
+        let mut ours = None;
+        let mut any = None;
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+        let mut group = Self::default();
+        // product token, like `snitch` of `snitch/1.0`
+        let token = agent.split('/').next().unwrap_or_default().to_lowercase();
+
+        let mut finish_group = |agents: &mut Vec<String>, group: Self| {
+            if agents.iter().any(|a| a.starts_with(&token)) {
+                ours.get_or_insert(group);
+            } else if agents.iter().any(|a| a == "*") {
+                any.get_or_insert(group);
+            }
+            agents.clear();
+        };
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let Some((k, v)) = line.split_once(':') else {
+                continue;
+            };
+            let v = v.trim();
+            match k.trim().to_lowercase().as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        finish_group(&mut agents, std::mem::take(&mut group));
+                        in_rules = false;
+                    }
+                    agents.push(v.to_lowercase());
+                }
+                "disallow" | "allow" => {
+                    in_rules = true;
+                    // empty Disallow means everything is allowed
+                    if !v.is_empty() {
+                        group.rules.push(Rule {
+                            allow: k.trim().eq_ignore_ascii_case("allow"),
+                            prefix: normalize(v),
+                        });
+                    }
+                }
+                "crawl-delay" => {
+                    in_rules = true;
+                    // negative, NaN and huge delays are ignored rather than trusted
+                    group.crawl_delay = v
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+                }
+                _ => {}
+            }
+        }
+        finish_group(&mut agents, group);
+
+        ours.or(any).unwrap_or_default()
+    }
+
+    /// Checks selector against rules, longest matching rule wins
+    pub fn allowed(&self, selector: &str) -> Result<(), String> {
+        let selector = normalize(selector);
+        match self
+            .rules
+            .iter()
+            .filter(|r| selector.starts_with(&r.prefix))
+            .max_by_key(|r| (r.prefix.len(), r.allow))
+        {
+            Some(r) if !r.allow => Err(format!("disallowed by robots.txt: {}", r.prefix)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Gopher selectors don't have to start with slash, robots.txt rules usually do
+fn normalize(selector: &str) -> String {
+    match selector.starts_with('/') {
+        true => String::from(selector),
+        false => format!("/{selector}"),
+    }
+}
+
+/// Bitreich-style caps.txt, `KEY=value` lines after `CAPS` header
+#[derive(Debug, Default)]
+pub struct Caps(pub HashMap<String, String>);
+
+impl Caps {
+    pub fn parse(text: &str) -> Self {
+        let mut lines = text.lines();
+        if lines.next().map(str::trim) != Some("CAPS") {
+            return Self::default();
+        }
+        Self(
+            lines
+                .filter(|l| !l.starts_with('#'))
+                .filter_map(|l| l.split_once('='))
+                .map(|(k, v)| (String::from(k.trim()), String::from(v.trim())))
+                .collect(),
+        )
+    }
+
+    /// How long these caps (and robots.txt along with them) may be cached
+    pub fn expire_after(&self) -> Option<Duration> {
+        self.0
+            .get("ExpireCapsAfter")
+            .and_then(|x| x.parse().ok())
+            .map(Duration::from_secs)
+    }
+}
+
+/// Crawl policy of the host
+#[derive(Debug)]
+pub struct HostPolicy {
+    pub robots: Robots,
+    pub caps: Caps,
+    fetched: Instant,
+}
+
+impl HostPolicy {
+    fn expired(&self) -> bool {
+        self.fetched.elapsed() > self.caps.expire_after().unwrap_or(POLICY_TTL)
+    }
+}
+
+type PolicyCell = Arc<OnceCell<HostPolicy>>;
+
+/// Per-host cache of robots.txt and caps.txt
+pub struct Policies {
+    client: Arc<Client>,
+    hosts: Mutex<HashMap<(String, u16), PolicyCell>>,
+}
+
+impl Policies {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self {
+            client,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks whether spider may fetch url, returns reason if it may not.
+    /// Fetches policy of the host if it is not cached yet.
+    pub async fn check(&self, url: &GopherURL) -> Result<(), String> {
+        let policy = self.policy(&url.host, url.port).await;
+        let policy = policy
+            .get_or_init(|| self.fetch_policy(&url.host, url.port, url.tls))
+            .await;
+        policy.robots.allowed(&url.selector)
+    }
+
+    async fn policy(&self, host: &str, port: u16) -> PolicyCell {
+        let mut hosts = self.hosts.lock().await;
+        let cell = hosts
+            .entry((host.to_lowercase(), port))
+            .or_insert_with(|| Arc::new(OnceCell::new()));
+        if cell.get().is_some_and(HostPolicy::expired) {
+            *cell = Arc::new(OnceCell::new());
+        }
+        cell.clone()
+    }
+
+    async fn fetch_policy(&self, host: &str, port: u16, tls: bool) -> HostPolicy {
+        let robots = Robots::parse(
+            &self.fetch_text(host, port, tls, "robots.txt").await,
+            USER_AGENT,
+        );
+        let caps = Caps::parse(&self.fetch_text(host, port, tls, "caps.txt").await);
+        if let Some(delay) = robots.crawl_delay {
+            log::info!("[robots] using crawl delay {delay:?} for {host}");
+            self.client.set_host_delay(host, delay).await;
+        }
+        HostPolicy {
+            robots,
+            caps,
+            fetched: Instant::now(),
+        }
+    }
+
+    /// Fetches text file, missing files are treated as empty ones
+    async fn fetch_text(&self, host: &str, port: u16, tls: bool, selector: &str) -> String {
+        let url = GopherURL {
+            host: String::from(host),
+            port,
+            gopher_type: GopherItem::TextFile,
+            selector: String::from(selector),
+            tls,
+        };
+        match self.client.fetch(&url, None).await {
+            Ok(text) => String::from_utf8_lossy(&text).into_owned(),
+            Err(e) => {
+                log::debug!("[robots] no {selector} on {host}:{port}: {e:#}");
+                String::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_robots() {
+        let robots = Robots::parse(
+            "# comment\n\
+             User-agent: *\n\
+             Disallow: /private\n\
+             \n\
+             User-agent: veronica\n\
+             User-agent: snitch\n\
+             Disallow: /cgi-bin # no scripts\n\
+             Allow: /cgi-bin/search\n\
+             Crawl-delay: 2.5\n",
+            USER_AGENT,
+        );
+        assert_eq!(robots.crawl_delay, Some(Duration::from_millis(2500)));
+        assert!(robots.allowed("/private/stuff").is_ok());
+        assert!(robots.allowed("cgi-bin/guestbook").is_err());
+        assert!(robots.allowed("/cgi-bin/search").is_ok());
+
+        let robots = Robots::parse("User-agent: *\nDisallow: /private\n", USER_AGENT);
+        assert!(robots.crawl_delay.is_none());
+        assert_eq!(
+            robots.allowed("/private/stuff"),
+            Err(String::from("disallowed by robots.txt: /private"))
+        );
+        assert!(robots.allowed("/public").is_ok());
+
+        let robots = Robots::parse("User-agent: *\nDisallow:\n", USER_AGENT);
+        assert!(robots.allowed("/anything").is_ok());
+
+        // groups are picked by our product token, not by any part of it
+        let robots = Robots::parse(
+            "User-agent: s
+Disallow: /s
+
+User-agent: Snitch-old
+Disallow: /old
+",
+            USER_AGENT,
+        );
+        assert!(robots.allowed("/s").is_ok());
+        assert!(robots.allowed("/old").is_err());
+
+        for bad in ["-1", "NaN", "inf", "1e30", "soon"] {
+            let robots = Robots::parse(&format!("User-agent: *\nCrawl-delay: {bad}\n"), USER_AGENT);
+            assert!(robots.crawl_delay.is_none(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parsing_caps() {
+        let caps = Caps::parse("CAPS\n# comment\nCapsVersion=1\nExpireCapsAfter=3600\n");
+        assert_eq!(caps.expire_after(), Some(Duration::from_secs(3600)));
+        assert_eq!(caps.0.get("CapsVersion").map(String::as_str), Some("1"));
+        assert!(Caps::parse("Not caps\nExpireCapsAfter=1\n").0.is_empty());
+    }
+}
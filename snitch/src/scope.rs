@@ -0,0 +1,176 @@
+//! Crawl scope, so seeding one hole doesn't end up crawling all of gopherspace.
+//!
+//! Scope file has robots.txt-like `Key: value` lines, keys may repeat.
+//! Comments start with `#` at line start or after whitespace, so that
+//! selector patterns may have `#` in them:
+//!
+//! ```text
+//! # only floodgap, without its huge archives
+//! Allow-host: *.floodgap.com
+//! Deny-host: archive.floodgap.com
+//! Include-selector: ^/(phlog|gopher)/
+//! Exclude-selector: \.(zip|tar\.gz)$
+//! Max-depth: 5
+//! Max-pages-per-host: 10000
+//! ```
+
+use crate::gopher::GopherURL;
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use std::path::Path;
+
+/// Which links spider follows. Links out of scope are still stored in
+/// the link graph, they just aren't fetched.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    /// Host globs like `*.example.org`, other hosts are not crawled,
+    /// unless the list is empty
+    pub allow_hosts: Vec<String>,
+    /// Host globs never crawled, even if allowed
+    pub deny_hosts: Vec<String>,
+    /// Selectors, as menus list them, matching none of these are not crawled,
+    /// unless the list is empty
+    pub include_selectors: Vec<Regex>,
+    /// Selectors never crawled, even if included
+    pub exclude_selectors: Vec<Regex>,
+    /// Max number of links from seeds
+    pub max_depth: Option<u32>,
+    /// Max number of urls queued from a single host
+    pub max_pages_per_host: Option<usize>,
+}
+
+impl Scope {
+    /// Parses scope file, see module docs
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut scope = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (k, v) = line
+                .split_once(':')
+                .ok_or(anyhow!("line {}: expected `key: value`", n + 1))?;
+            let v = v.trim();
+            let regex = || Regex::new(v).context(format!("line {}: bad regex {v}", n + 1));
+            let number = || format!("line {}: bad number {v}", n + 1);
+            match k.trim().to_lowercase().as_str() {
+                "allow-host" => scope.allow_hosts.push(v.to_lowercase()),
+                "deny-host" => scope.deny_hosts.push(v.to_lowercase()),
+                "include-selector" => scope.include_selectors.push(regex()?),
+                "exclude-selector" => scope.exclude_selectors.push(regex()?),
+                "max-depth" => scope.max_depth = Some(v.parse().context(number())?),
+                "max-pages-per-host" => {
+                    scope.max_pages_per_host = Some(v.parse().context(number())?)
+                }
+                k => return Err(anyhow!("line {}: unknown key {k}", n + 1)),
+            }
+        }
+        Ok(scope)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).context(format!("reading {}", path.display()))?;
+        Self::parse(&text).context(format!("parsing {}", path.display()))
+    }
+
+    /// Checks host, selector and depth of url, but not the page count,
+    /// which needs the frontier
+    pub fn check(&self, url: &GopherURL, depth: u32) -> Result<(), String> {
+        let host = url.host.trim_end_matches('.').to_lowercase();
+        if !self.allow_hosts.is_empty() && !self.allow_hosts.iter().any(|g| glob(g, &host)) {
+            return Err(format!("host {host} is not allowed"));
+        }
+        if let Some(g) = self.deny_hosts.iter().find(|g| glob(g, &host)) {
+            return Err(format!("host {host} is denied by {g}"));
+        }
+        let selector = &url.selector;
+        if !self.include_selectors.is_empty()
+            && !self.include_selectors.iter().any(|r| r.is_match(selector))
+        {
+            return Err(format!("selector {selector} is not included"));
+        }
+        if let Some(r) = self.exclude_selectors.iter().find(|r| r.is_match(selector)) {
+            return Err(format!("selector {selector} is excluded by {r}"));
+        }
+        match self.max_depth {
+            Some(max) if depth > max => Err(format!("{depth} links away from seeds")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Cuts off comment, `#` that starts the line or follows whitespace
+fn strip_comment(line: &str) -> &str {
+    let comment = line.char_indices().find(|&(i, c)| {
+        c == '#'
+            && line[..i]
+                .chars()
+                .next_back()
+                .is_none_or(char::is_whitespace)
+    });
+    comment.map_or(line, |(i, _)| &line[..i])
+}
+
+/// Matches glob with `*` and `?` wildcards, ignoring ASCII case
+fn glob(pattern: &str, s: &str) -> bool {
+    fn matches(p: &[u8], s: &[u8]) -> bool {
+        match (p.split_first(), s.split_first()) {
+            (None, _) => s.is_empty(),
+            (Some((b'*', rest)), _) => matches(rest, s) || (!s.is_empty() && matches(p, &s[1..])),
+            (Some(_), None) => false,
+            (Some((b'?', p)), Some((_, s))) => matches(p, s),
+            (Some((a, p)), Some((b, s))) => a.eq_ignore_ascii_case(b) && matches(p, s),
+        }
+    }
+    matches(pattern.as_bytes(), s.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scoping() {
+        let scope = Scope::parse(
+            "# floodgap only\n\
+             Allow-host: *.floodgap.com\n\
+             allow-host: sdf.org \n\
+             Deny-host: archive.floodgap.com\n\
+             Include-selector: ^/(phlog|gopher)/\n\
+             Exclude-selector: \\.zip$  # too big\n\
+             Max-depth: 2\n",
+        )
+        .unwrap();
+        assert_eq!(scope.max_depth, Some(2));
+        assert_eq!(scope.max_pages_per_host, None);
+        let check = |url: &str, depth| scope.check(&GopherURL::try_from(url).unwrap(), depth);
+
+        assert!(check("gopher://gopher.floodgap.com/1/gopher/", 0).is_ok());
+        assert!(check("gopher://SDF.org./0/phlog/1.txt", 2).is_ok());
+        assert_eq!(
+            check("gopher://floodgap.com.evil.org/1/gopher/", 0),
+            Err(String::from("host floodgap.com.evil.org is not allowed"))
+        );
+        assert!(check("gopher://archive.floodgap.com/1/gopher/", 0).is_err());
+        assert!(check("gopher://sdf.org/1/users/", 0).is_err());
+        assert!(check("gopher://sdf.org/9/gopher/x.zip", 0).is_err());
+        assert_eq!(
+            check("gopher://sdf.org/1/phlog/", 3),
+            Err(String::from("3 links away from seeds"))
+        );
+
+        assert!(Scope::default()
+            .check(&GopherURL::try_from("gopher://any.org/1/x").unwrap(), 100)
+            .is_ok());
+        assert!(glob("gopher.*.?rg", "gopher.sdf.org"));
+        assert!(!glob("*.org", "org"));
+
+        let e = Scope::parse("max-depth: 2\nmax-pages: 10").unwrap_err();
+        assert_eq!(e.to_string(), "line 2: unknown key max-pages");
+        assert!(Scope::parse("Include-selector: (").is_err());
+
+        let scope = Scope::parse("#comment\nExclude-selector: ^/#private #x\n").unwrap();
+        assert_eq!(scope.exclude_selectors[0].as_str(), "^/#private");
+    }
+}
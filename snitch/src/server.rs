@@ -0,0 +1,248 @@
+use crate::client::timeout;
+use crate::gopher::{DirEntry, GopherItem, GopherURL, Menu};
+use crate::index::{self, Query, SearchResult};
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+use smol::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    Timer,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Selector of type 7 search item
+pub const SEARCH_SELECTOR: &str = "/search";
+
+/// Longest request line server is willing to read
+const MAX_REQUEST: u64 = 4096;
+
+/// Gopher server answering full-text queries over spider DB, a la Veronica-2
+pub struct SearchServer {
+    conn: Arc<Mutex<Connection>>,
+    /// Host and port put into menus, clients use them to send queries
+    hostname: String,
+    port: u16,
+    /// Max number of results per query
+    pub limit: usize,
+    /// How long clients may take to send request line
+    pub request_timeout: Duration,
+}
+
+impl SearchServer {
+    pub fn new(conn: Connection, hostname: &str, port: u16) -> Self {
+        Self {
+            conn: Arc::new(Mutex::new(conn)),
+            hostname: String::from(hostname),
+            port,
+            limit: 50,
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        log::info!("[serve] listening on {}", listener.local_addr()?);
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // errors like EMFILE persist for a while, retrying right away would spin
+                    log::error!("[serve] accepting connection: {e}");
+                    Timer::after(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let server = self.clone();
+            smol::spawn(async move {
+                if let Err(e) = server.handle(stream).await {
+                    log::error!("[serve] handling request from {peer}: {e:#}");
+                }
+            })
+            .detach();
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let mut line = String::new();
+        let mut reader = BufReader::new(stream.clone().take(MAX_REQUEST));
+        timeout(self.request_timeout, reader.read_line(&mut line))
+            .await
+            .context("reading request")?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (selector, query) = match line.split_once('\t') {
+            Some((selector, query)) => (selector, Some(query)),
+            None => (line, None),
+        };
+        log::info!("[serve] {selector:?} {query:?}");
+
+        let menu = self.reply(selector, query).await;
+        stream
+            .write_all(menu.to_string().as_bytes())
+            .await
+            .context("writing reply")?;
+        stream.flush().await.context("flushing reply")?;
+        Ok(())
+    }
+
+    async fn reply(&self, selector: &str, query: Option<&str>) -> Menu {
+        match (selector, query) {
+            (SEARCH_SELECTOR, Some(query)) if !query.trim().is_empty() => {
+                let conn = self.conn.clone();
+                let mut q = Query::new(query);
+                q.highlight = (String::from("*"), String::from("*"));
+                let limit = self.limit;
+                let results =
+                    smol::unblock(move || index::search(&conn.lock().unwrap(), q, limit)).await;
+                match results {
+                    Ok(results) => self.results_menu(query, &results),
+                    Err(e) => {
+                        log::warn!("[serve] query {query:?} failed: {e:#}");
+                        error_menu(&format!("Bad query: {}", plain(query)))
+                    }
+                }
+            }
+            ("" | "/" | SEARCH_SELECTOR, _) => self.root_menu(),
+            _ => error_menu("Not found"),
+        }
+    }
+
+    fn search_entry(&self) -> DirEntry {
+        DirEntry::new(
+            GopherItem::FullTextSearch,
+            "Search gopherspace",
+            SEARCH_SELECTOR,
+            &self.hostname,
+            &self.port.to_string(),
+        )
+    }
+
+    fn root_menu(&self) -> Menu {
+        Menu {
+            items: vec![
+                info("snitch - full-text search over crawled gopherspace"),
+                info(""),
+                self.search_entry(),
+            ],
+        }
+    }
+
+    fn results_menu(&self, query: &str, results: &[SearchResult]) -> Menu {
+        let mut items = vec![
+            info(&format!(
+                "{} results for \"{}\"",
+                results.len(),
+                plain(query)
+            )),
+            info(""),
+        ];
+        for r in results {
+            let Ok(url) = GopherURL::try_from(r.url.as_str()) else {
+                continue;
+            };
+            items.push(DirEntry::new(
+                r.item_type,
+                &r.url,
+                &url.selector,
+                &url.host,
+                &url.port.to_string(),
+            ));
+            items.push(info(&format!("  {}", plain(&r.snippet))));
+            if let Some(admin) = &r.admin {
+                items.push(info(&format!("  admin: {admin}")));
+            }
+            if let Some(date) = &r.mod_date {
+                items.push(info(&format!("  modified: {date}")));
+            }
+        }
+        items.push(info(""));
+        items.push(self.search_entry());
+        Menu { items }
+    }
+}
+
+/// Text with tabs and line breaks replaced, so it can't add fields or lines to menu
+fn plain(text: &str) -> String {
+    text.replace(['\r', '\n', '\t'], " ")
+}
+
+fn info(text: &str) -> DirEntry {
+    DirEntry::new(GopherItem::Info, text, "", "", "")
+}
+
+fn error_menu(text: &str) -> Menu {
+    Menu {
+        items: vec![DirEntry {
+            item_type: GopherItem::Error,
+            label: String::from(text),
+            url: None,
+            external: None,
+            gopher_plus: false,
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontier::Frontier;
+    use crate::index::init_db;
+    use rusqlite::params;
+    use std::thread;
+
+    #[test]
+    fn serving_search() {
+        let conn = init_db(":memory:").unwrap();
+        conn.execute(
+            "INSERT INTO page_content(content) VALUES('all about gopher holes')",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO pages (url, type, content_id) VALUES (?1, '0', ?2)",
+            params!["gopher://a.org:70/0/holes.txt", conn.last_insert_rowid()],
+        )
+        .unwrap();
+        // found with trailing slash, which is kept in links
+        let listed = GopherURL::try_from("gopher://a.org:70/0/holes.txt/").unwrap();
+        Frontier::default().push(&conn, &listed, 0).unwrap();
+
+        let listener = smol::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = Arc::new(SearchServer {
+            request_timeout: Duration::from_millis(200),
+            ..SearchServer::new(conn, "127.0.0.1", port)
+        });
+        thread::spawn(move || smol::block_on(server.serve(listener)));
+
+        let root = GopherURL::try_from(format!("gopher://127.0.0.1:{port}").as_str()).unwrap();
+        let menu = Menu::from_url(&root, None).unwrap();
+        let search = menu.items.last().unwrap();
+        assert_eq!(search.item_type, GopherItem::FullTextSearch);
+
+        let search = search.url.as_ref().unwrap();
+        assert_eq!(search.selector, SEARCH_SELECTOR);
+        let menu = Menu::from_url(search, Some(String::from("gopher"))).unwrap();
+        // blank info line after header is merged into it
+        assert_eq!(menu.items[0].label, "1 results for \"gopher\"\n");
+        let hit = &menu.items[1];
+        assert_eq!(hit.item_type, GopherItem::TextFile);
+        assert_eq!(hit.label, "gopher://a.org:70/0/holes.txt/");
+        let url = hit.url.as_ref().unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("a.org", 70));
+        assert_eq!(url.selector, "/holes.txt/");
+        assert!(menu.items[2].label.contains("*gopher*"));
+
+        assert!(Menu::from_url(search, Some(String::from("gopher AND"))).is_err());
+        let bad = error_menu(&format!("Bad query: {}", plain("AND\tx\r\n1fake")));
+        assert_eq!(
+            bad.to_string(),
+            "3Bad query: AND x  1fake\tfake\t(NULL)\t0\r\n.\r\n"
+        );
+
+        // idle client is dropped, others are still served
+        let mut idle = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        idle.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(std::io::Read::read(&mut idle, &mut [0; 16]).unwrap(), 0);
+        assert!(Menu::from_url(&root, None).is_ok());
+    }
+}
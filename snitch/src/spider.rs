@@ -0,0 +1,797 @@
+use crate::charset;
+use crate::client::{Client, ClientOptions};
+use crate::dedup::{self, Dedup};
+use crate::export;
+use crate::extract::{sha256_hex, BinHex, Extracted, Extractors};
+use crate::frontier::{Frontier, State};
+use crate::gopher::{ExternalLink, GopherItem, GopherURL, Menu};
+use crate::gopher_plus::ItemAttributes;
+use crate::index;
+use crate::metrics::Snapshot;
+use crate::rank;
+use crate::recrawl::{Change, Schedule};
+use crate::robots::Policies;
+use crate::scope::Scope;
+use crate::tls;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use smol::channel::{unbounded, Receiver, RecvError, Sender};
+use smol::future::FutureExt;
+use smol::{Executor, Timer};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often spider looks for pages due for recrawl
+const RECRAWL_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Selectors that deep are not followed, they are usually link loops
+const MAX_SELECTOR_DEPTH: usize = 50;
+
+struct Site {
+    /// Canonical url, the one pages are stored by
+    url: GopherURL,
+    /// Url as it was listed and fetched
+    listed: GopherURL,
+    text: Option<String>,
+    links: Option<Vec<GopherURL>>,
+    /// Links out of gopherspace, stored but never followed
+    external: Vec<ExternalLink>,
+    /// gopher+ attributes of linked items
+    attributes: Vec<ItemAttributes>,
+    /// Metadata of fetched item
+    meta: Option<Extracted>,
+    /// Detected character encoding of menu or text
+    encoding: Option<&'static str>,
+    /// Whether the server spoke TLS, if client tried it
+    tls: Option<bool>,
+    /// Raw reply, kept for export
+    reply: Option<Vec<u8>>,
+    /// Reason why url was not fetched
+    skipped: Option<String>,
+    /// Error fetching url
+    error: Option<String>,
+}
+
+impl Site {
+    fn new(url: &GopherURL) -> Self {
+        Self {
+            url: url.canonical(),
+            listed: url.clone(),
+            text: None,
+            links: None,
+            external: Vec::new(),
+            attributes: Vec::new(),
+            meta: None,
+            encoding: None,
+            tls: None,
+            reply: None,
+            skipped: None,
+            error: None,
+        }
+    }
+}
+
+/// Crawler settings
+#[derive(Debug, Clone)]
+pub struct Spider {
+    pub client: ClientOptions,
+    pub frontier: Frontier,
+    pub schedule: Schedule,
+    /// Number of threads running fetch workers
+    pub threads: usize,
+    /// Don't fetch or honour robots.txt and caps.txt
+    pub ignore_robots: bool,
+    /// Keep running and revisit pages when they are due, instead of
+    /// returning once frontier is empty
+    pub recrawl: bool,
+    /// Store raw replies along with extracted text, for [`crate::export`]
+    pub keep_replies: bool,
+    /// Address to serve crawl metrics on, see [`crate::metrics::Metrics::serve`]
+    pub metrics_bind: Option<String>,
+    /// Near-duplicate detection, duplicates are neither indexed nor followed
+    pub dedup: Option<Dedup>,
+    /// Which links are followed, seeds are always crawled
+    pub scope: Scope,
+}
+
+impl Default for Spider {
+    fn default() -> Self {
+        Self {
+            client: ClientOptions::default(),
+            frontier: Frontier::default(),
+            schedule: Schedule::default(),
+            threads: 2,
+            ignore_robots: false,
+            recrawl: false,
+            keep_replies: false,
+            metrics_bind: None,
+            dedup: Some(Dedup::default()),
+            scope: Scope::default(),
+        }
+    }
+}
+
+impl Spider {
+    /// Crawls from seeds and whatever is left in the frontier of DB.
+    /// Returns when frontier is empty, or after `stop` is set and urls in flight are stored,
+    /// with final crawl statistics.
+    pub fn run(
+        &self,
+        mut conn: Connection,
+        seeds: &[GopherURL],
+        stop: Arc<AtomicBool>,
+    ) -> Result<Snapshot> {
+        let (urls_tx, urls_rx) = unbounded();
+        let (sites_tx, sites_rx) = unbounded();
+        let frontier = &self.frontier;
+        let concurrency = self.client.max_in_flight;
+        let client = Arc::new(Client::new(self.client.clone()));
+        for (host, port, tls) in tls::known_hosts(&conn)? {
+            client.set_tls_support(&host, port, tls);
+        }
+        let mut extractors = Extractors::default();
+        let max_size = self.client.max_reply_size;
+        extractors.register(GopherItem::BinHex, BinHex { max_size });
+        let extractors = Arc::new(extractors);
+        let policies = match self.ignore_robots {
+            true => None,
+            false => Some(Arc::new(Policies::new(client.clone()))),
+        };
+        let metrics = client.metrics();
+        let ex = Arc::new(Executor::new());
+        if let Some(bind) = &self.metrics_bind {
+            let listener = std::net::TcpListener::bind(bind)
+                .context(format!("binding metrics endpoint to {bind}"))?;
+            let listener = smol::net::TcpListener::try_from(listener)?;
+            ex.spawn(metrics.clone().serve(listener)).detach();
+        }
+
+        for i in 0..concurrency {
+            ex.spawn(worker(
+                i,
+                client.clone(),
+                policies.clone(),
+                extractors.clone(),
+                urls_rx.clone(),
+                sites_tx.clone(),
+            ))
+            .detach();
+        }
+        // executor threads exit once spider returns and drops the sender
+        let (_shutdown, shutdown_rx) = unbounded::<()>();
+        for _ in 0..self.threads {
+            let ex = ex.clone();
+            let shutdown = shutdown_rx.clone();
+            thread::spawn(move || smol::block_on(ex.run(shutdown.recv())));
+        }
+
+        let removed = index::gc(&conn)?;
+        if removed > 0 {
+            log::info!("[spider] removed {removed} superseded content rows");
+        }
+        let resumed = frontier.resume(&conn)?;
+        log::info!(
+            "[spider] resuming crawl, {} urls queued, {resumed} were in flight",
+            frontier.count(&conn, State::Queued)?
+        );
+
+        for url in seeds {
+            if frontier.push(&conn, url, 0)? {
+                store_url(&conn, &url.canonical())?;
+            }
+        }
+
+        // keep some urls queued in channel, so workers don't wait for spider
+        let max_in_flight = 2 * concurrency;
+        let mut in_flight = 0;
+        let mut next_recrawl_check = Instant::now();
+        loop {
+            if self.recrawl && Instant::now() >= next_recrawl_check {
+                let due = self.schedule.requeue_due(&conn)?;
+                if due > 0 {
+                    log::info!("[spider] {due} pages due for recrawl");
+                }
+                next_recrawl_check = Instant::now() + RECRAWL_CHECK_INTERVAL;
+            }
+            if stop.load(Ordering::SeqCst) {
+                // return urls not picked up by workers yet back to frontier
+                while let Ok(url) = urls_rx.try_recv() {
+                    frontier.requeue(&conn, &url)?;
+                    in_flight -= 1;
+                }
+                if in_flight == 0 {
+                    log::info!("[spider] stopped");
+                    return Ok(metrics.snapshot());
+                }
+            } else if in_flight < max_in_flight {
+                for url in frontier.take(&conn, max_in_flight - in_flight)? {
+                    urls_tx
+                        .send_blocking(url)
+                        .context("sending url to worker")?;
+                    in_flight += 1;
+                }
+                if in_flight == 0 {
+                    match frontier.next_retry(&conn)? {
+                        Some(wait) => {
+                            log::info!("[spider] waiting {wait:?} to retry failed urls");
+                            thread::sleep(wait.min(Duration::from_secs(1)));
+                            continue;
+                        }
+                        None if self.recrawl => {
+                            if let Some(wait) = self.schedule.next_due(&conn)? {
+                                log::debug!("[spider] next page is due for recrawl in {wait:?}");
+                            }
+                            thread::sleep(Duration::from_secs(1));
+                            continue;
+                        }
+                        None => {
+                            log::info!("[spider] frontier is empty, crawl finished");
+                            return Ok(metrics.snapshot());
+                        }
+                    }
+                }
+            }
+
+            let received = smol::block_on(sites_rx.recv().or(async {
+                // wake up periodically to notice SIGINT
+                Timer::after(Duration::from_secs(1)).await;
+                Err(RecvError)
+            }));
+            let Ok(mut site) = received else {
+                continue;
+            };
+            in_flight -= 1;
+            if !self.keep_replies {
+                site.reply = None;
+            }
+            process_site(&mut conn, self, site)
+                .unwrap_or_else(|e| log::error!("[spider] storing site data: {e:#}"));
+            let queued = frontier.count(&conn, State::Queued)?;
+            let visited = frontier.count(&conn, State::Done)?;
+            metrics.set_queue(queued as u64, in_flight as u64, visited as u64);
+            log::info!(
+                "[spider] {in_flight} urls in flight, {queued} urls queued, {visited} urls visited"
+            );
+        }
+    }
+}
+
+/// Stores fetched site and its links, marking it as done or failed in frontier
+fn process_site(conn: &mut Connection, spider: &Spider, site: Site) -> Result<()> {
+    let (frontier, scope) = (&spider.frontier, &spider.scope);
+    let dedup = spider.dedup.as_ref();
+    let tx = conn.transaction()?;
+    if let Some(tls) = site.tls {
+        tls::remember(&tx, &site.url.host, site.url.port, tls)?;
+    }
+    if let Some(error) = &site.error {
+        frontier.failed(&tx, &site.url, error)?;
+        return Ok(tx.commit()?);
+    }
+    store_site(&tx, &spider.schedule, dedup, &site)?;
+    if let Some(links) = &site.links {
+        let links: Vec<_> = links.iter().map(GopherURL::canonical).collect();
+        rank::store_links(&tx, &site.url, &links, &site.external)?;
+    }
+    // links of mirrored menus lead to more copies
+    let duplicate = dedup.is_some() && dedup::duplicate_of(&tx, &site.url.to_string())?.is_some();
+    let depth = frontier.depth(&tx, &site.url)? + 1;
+    // links are queued as listed, servers may tell spellings apart
+    for link in site.links.iter().flatten().filter(|_| !duplicate) {
+        let url = &link.canonical();
+        if url.selector.chars().filter(|c| *c == '/').count() >= MAX_SELECTOR_DEPTH {
+            continue;
+        }
+        // patterns are written for selectors as servers list them
+        if let Err(reason) = scope.check(link, depth) {
+            log::debug!("[spider] not following {url}: {reason}");
+            continue;
+        }
+        if let Some(max) = scope.max_pages_per_host {
+            if frontier.host_count(&tx, &url.host)? >= max {
+                log::debug!("[spider] not following {url}: {max} pages of the host queued");
+                continue;
+            }
+        }
+        if frontier.push(&tx, link, depth)? {
+            store_url(&tx, url)?;
+        }
+    }
+    frontier.done(&tx, &site.url)?;
+    Ok(tx.commit()?)
+}
+
+fn store_site(
+    tx: &Connection,
+    schedule: &Schedule,
+    dedup: Option<&Dedup>,
+    site: &Site,
+) -> Result<()> {
+    if let Some(reason) = &site.skipped {
+        tx.execute(
+            "INSERT INTO pages (url, type, skip_reason) VALUES (?1, ?2, ?3)
+             ON CONFLICT(url) DO UPDATE SET skip_reason=excluded.skip_reason",
+            params![
+                site.url.to_string(),
+                site.url.gopher_type.to_string(),
+                reason
+            ],
+        )?;
+    }
+    if let Some(meta) = &site.meta {
+        tx.execute(
+            "INSERT INTO pages (url, type, size, sha256, mime) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(url) DO UPDATE
+             SET size=excluded.size, sha256=excluded.sha256, mime=excluded.mime",
+            params![
+                site.url.to_string(),
+                site.url.gopher_type.to_string(),
+                meta.size,
+                meta.sha256,
+                meta.mime
+            ],
+        )?;
+    }
+    let hash = match (&site.meta, &site.text) {
+        (Some(meta), _) => Some(meta.sha256.clone()),
+        (None, Some(text)) => Some(sha256_hex(text.as_bytes())),
+        (None, None) => None,
+    };
+    if let Some(hash) = hash {
+        store_url(tx, &site.url)?;
+        let change = schedule.record(tx, &site.url, &hash)?;
+        if let Some(encoding) = site.encoding {
+            tx.execute(
+                "UPDATE pages SET encoding = ?2 WHERE url = ?1",
+                params![site.url.to_string(), encoding],
+            )?;
+        }
+        log::debug!("[spider] {} {change:?}", site.url);
+        if let Some(reply) = &site.reply {
+            export::store_reply(tx, &site.listed, reply, change != Change::Unchanged)?;
+        }
+        if let (Some(content), Change::New | Change::Changed) = (&site.text, change) {
+            let old: Option<i64> = tx.query_row(
+                "SELECT content_id FROM pages WHERE url = ?1",
+                [site.url.to_string()],
+                |row| row.get(0),
+            )?;
+            if let Some(old) = old {
+                tx.execute("DELETE FROM page_content WHERE rowid = ?1", [old])?;
+            }
+            let original = match dedup {
+                Some(dedup) => dedup.check(tx, &site.url.to_string(), content)?,
+                None => None,
+            };
+            let content_id = match original {
+                Some(original) => {
+                    log::info!("[spider] {} duplicates {original}, not indexed", site.url);
+                    None
+                }
+                None => {
+                    tx.execute("INSERT INTO page_content(content) VALUES(?1)", [content])?;
+                    Some(tx.last_insert_rowid())
+                }
+            };
+            tx.execute(
+                "UPDATE pages SET content_id = ?2, skip_reason = NULL WHERE url = ?1",
+                params![site.url.to_string(), content_id],
+            )?;
+        }
+    }
+    for attrs in &site.attributes {
+        let Some(url) = attrs.url() else {
+            continue;
+        };
+        let admin = attrs.admin.as_ref();
+        let views = attrs
+            .views
+            .iter()
+            .map(|v| {
+                let mut view = v.mime.clone();
+                for x in v.language.iter().chain(v.size.iter()) {
+                    view.push(' ');
+                    view.push_str(x);
+                }
+                view
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        tx.execute(
+            "INSERT OR REPLACE INTO attributes (url, admin, mod_date, abstract, views)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                // keyed like pages
+                url.canonical().to_string(),
+                admin.and_then(|a| a.admin.as_ref()),
+                admin.and_then(|a| a.mod_date.as_ref()),
+                attrs.abstract_text,
+                views,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn store_url(conn: &Connection, url: &GopherURL) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO pages (url, type) VALUES (?1, ?2)",
+        params![url.to_string(), url.gopher_type.to_string()],
+    )?;
+    Ok(())
+}
+
+async fn worker(
+    id: usize,
+    client: Arc<Client>,
+    policies: Option<Arc<Policies>>,
+    extractors: Arc<Extractors>,
+    urls: Receiver<GopherURL>,
+    sites: Sender<Site>,
+) {
+    log::info!("worker {id} started");
+    while let Ok(url) = urls.recv().await {
+        // queued by versions that didn't tell external links apart
+        if let Some(link) = url.external() {
+            let site = Site {
+                skipped: Some(format!("external link {link}")),
+                ..Site::new(&url)
+            };
+            sites
+                .send(site)
+                .await
+                .unwrap_or_else(|e| log::error!("failed to sending {url}: {e:#}"));
+            continue;
+        }
+        if let Some(Err(reason)) = match &policies {
+            Some(p) => Some(p.check(&url).await),
+            None => None,
+        } {
+            log::info!("[worker {id}] skipping {url}: {reason}");
+            let site = Site {
+                skipped: Some(reason),
+                ..Site::new(&url)
+            };
+            sites
+                .send(site)
+                .await
+                .unwrap_or_else(|e| log::error!("failed to sending {url}: {e:#}"));
+            continue;
+        }
+        let tls = |site: Site| Site {
+            tls: client.tls_support(&url.host, url.port),
+            ..site
+        };
+        match get_url(&client, &extractors, &url).await.map(tls) {
+            Ok(site) => sites
+                .send(site)
+                .await
+                .unwrap_or_else(|e| log::error!("failed to sending {url}: {e:#}")),
+            Err(e) => {
+                log::error!("failed to fetch {url}: {e:#}");
+                let site = tls(Site {
+                    error: Some(format!("{e:#}")),
+                    ..Site::new(&url)
+                });
+                sites
+                    .send(site)
+                    .await
+                    .unwrap_or_else(|e| log::error!("failed to sending {url}: {e:#}"));
+            }
+        }
+        log::info!("[worker {id}] fetched {url}");
+    }
+}
+
+async fn get_url(client: &Client, extractors: &Extractors, url: &GopherURL) -> Result<Site> {
+    match url.gopher_type {
+        GopherItem::Submenu => {
+            let data = client.fetch(url, None).await.context("fetching menu")?;
+            let decoded = charset::decode(&data);
+            let site = Menu::parse(&decoded.text);
+            let mut attributes = Vec::new();
+            if site.items.iter().any(|x| x.gopher_plus) {
+                attributes = client.dir_attributes(url).await.unwrap_or_else(|e| {
+                    log::warn!("failed to fetch gopher+ attributes of {url}: {e:#}");
+                    Vec::new()
+                });
+            }
+            Ok(Site {
+                attributes,
+                text: Some(
+                    site.items
+                        .iter()
+                        .map(|x| x.label.clone())
+                        .collect::<Vec<String>>()
+                        .join("\n"),
+                ),
+                links: Some(
+                    site.items
+                        .iter()
+                        .filter(|x| x.external.is_none())
+                        .filter_map(|x| x.url.clone())
+                        .collect(),
+                ),
+                external: site
+                    .items
+                    .iter()
+                    .filter_map(|x| x.external.clone())
+                    .collect(),
+                encoding: Some(decoded.encoding),
+                reply: Some(data),
+                ..Site::new(url)
+            })
+        }
+        t if extractors.handles(t) => {
+            let data = client.fetch(url, None).await.context("fetching item")?;
+            let mut meta = extractors.extract(t, &data).context("extracting content")?;
+            Ok(Site {
+                text: meta.text.take(),
+                encoding: meta.encoding,
+                meta: Some(meta),
+                reply: Some(data),
+                ..Site::new(url)
+            })
+        }
+        _ => Ok(Site::new(url)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::init_db;
+    use crate::testing::{MockGopherServer, Response};
+    use encoding::all::KOI8_R;
+
+    fn query(conn: &Connection, sql: &str, url: &GopherURL) -> Option<String> {
+        conn.query_row(sql, [url.to_string()], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn crawling() {
+        let server = MockGopherServer::new().unwrap();
+        let dead = MockGopherServer::new().unwrap();
+        let dead_url = dead.url(GopherItem::TextFile, "/nothing");
+        drop(dead);
+
+        server.route(
+            "",
+            server.menu(&[
+                (GopherItem::Info, "Welcome to the hole", ""),
+                (GopherItem::Submenu, "Phlog", "/phlog"),
+                (GopherItem::TextFile, "Gone", "/gone"),
+                (GopherItem::TextFile, "Private", "/private/diary.txt"),
+                (GopherItem::HtmlFile, "Web", "URL:https://example.org/"),
+                (GopherItem::Telnet, "Chat", "guest"),
+            ]),
+        );
+        let mut phlog = match server.menu(&[
+            (GopherItem::TextFile, "First post", "/phlog/1.txt"),
+            (GopherItem::TextFile, "Russian post", "/phlog/ru.txt"),
+            (GopherItem::TextFile, "First post, again", "phlog/1.txt/"),
+            (GopherItem::TextFile, "Mirrored post", "/mirror/1.txt"),
+            (GopherItem::Submenu, "Home", "/"),
+        ]) {
+            Response::Raw(data) => data,
+            _ => unreachable!(),
+        };
+        // link to a host that is down, spliced before the final "."
+        phlog.truncate(phlog.len() - 3);
+        phlog.extend(format!("0Dead\t/nothing\t127.0.0.1\t{}\r\n.\r\n", dead_url.port).bytes());
+        server.route("/phlog", Response::Raw(phlog));
+        let post = "My first gopher post! I finally set up a hole on an old laptop \
+            that was gathering dust in the closet. It runs a tiny server written in \
+            a weekend, serving a few menus and text files like this one. I will write \
+            here about retro computers, ham radio, the garden and whatever else comes \
+            to mind, without ads, trackers or comment sections. If you read this, \
+            drop me a line, I would love to know who else is out there in gopherspace.";
+        server.route("/phlog/1.txt", Response::text(post));
+        server.route(
+            "/mirror/1.txt",
+            Response::text(&format!("{post}\r\nMirrored from the phlog")),
+        );
+        server.route(
+            "/phlog/ru.txt",
+            Response::Slow(
+                Duration::from_millis(100),
+                Box::new(Response::Encoded(KOI8_R, String::from("привет, суслик"))),
+            ),
+        );
+        server.route("/gone", Response::Error(String::from("Gone fishing")));
+        server.route("/private/diary.txt", Response::text("secrets"));
+        server.route(
+            "robots.txt",
+            Response::text("User-agent: *\nDisallow: /private\n"),
+        );
+
+        let spider = Spider {
+            client: ClientOptions {
+                max_in_flight: 4,
+                host_delay: Duration::ZERO,
+                connect_timeout: Duration::from_secs(1),
+                read_timeout: Duration::from_secs(1),
+                ..ClientOptions::default()
+            },
+            frontier: Frontier {
+                max_attempts: 1,
+                backoff: Duration::ZERO,
+            },
+            threads: 1,
+            ..Spider::default()
+        };
+        let db = std::env::temp_dir().join(format!("snitch-spider-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&db);
+        let db = db.to_str().unwrap();
+        let root = server.url(GopherItem::Submenu, "");
+        let summary = spider
+            .run(
+                init_db(db).unwrap(),
+                std::slice::from_ref(&root),
+                Arc::default(),
+            )
+            .unwrap();
+        assert_eq!((summary.visited, summary.queued), (6, 0));
+        let errors = |kind| summary.errors[&(String::from("127.0.0.1"), kind)];
+        // dead host, its robots.txt and caps.txt
+        assert_eq!(errors("connect"), 3);
+        // gone item and missing caps.txt
+        assert_eq!(errors("error_item"), 2);
+
+        let conn = init_db(db).unwrap();
+        let frontier = Frontier::default();
+        assert_eq!(frontier.count(&conn, State::Done).unwrap(), 6);
+        assert_eq!(frontier.count(&conn, State::Failed).unwrap(), 2);
+        let error = "SELECT last_error FROM frontier WHERE url = ?1";
+        assert_eq!(
+            query(&conn, error, &server.url(GopherItem::TextFile, "/gone")).as_deref(),
+            Some("fetching item: Gone fishing")
+        );
+        assert!(query(&conn, error, &dead_url).is_some());
+
+        let private = server.url(GopherItem::TextFile, "/private/diary.txt");
+        let skipped = "SELECT skip_reason FROM pages WHERE url = ?1";
+        assert_eq!(
+            query(&conn, skipped, &private).as_deref(),
+            Some("disallowed by robots.txt: /private")
+        );
+        assert!(!server
+            .requests()
+            .contains(&String::from("/private/diary.txt")));
+
+        let content = "SELECT content FROM page_content
+                       JOIN pages ON pages.content_id = page_content.rowid WHERE url = ?1";
+        let ru = server.url(GopherItem::TextFile, "/phlog/ru.txt");
+        assert_eq!(
+            query(&conn, content, &ru).as_deref(),
+            Some("привет, суслик")
+        );
+        let encoding = "SELECT encoding FROM pages WHERE url = ?1";
+        assert_eq!(query(&conn, encoding, &ru).as_deref(), Some("koi8-r"));
+        assert!(query(&conn, content, &root).unwrap().contains("Welcome"));
+
+        let links: usize = conn
+            .query_row("SELECT count(*) FROM links", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(links, 10);
+        let external = "SELECT dst FROM links WHERE src = ?1 AND external ORDER BY dst";
+        assert_eq!(
+            query(&conn, external, &root).as_deref(),
+            Some("https://example.org/")
+        );
+        // url variants are fetched once, copies are not indexed
+        let requests = server.requests();
+        assert!(!requests.contains(&String::from("phlog/1.txt/")));
+        assert!(!requests
+            .iter()
+            .any(|r| r.starts_with("URL:") || r == "guest"));
+        let results = index::search(&conn, "gopher", 10).unwrap();
+        assert_eq!(results.len(), 1);
+        let first = server.url(GopherItem::TextFile, "/phlog/1.txt").to_string();
+        assert_eq!(results[0].url, first);
+        let copy = server.url(GopherItem::TextFile, "/mirror/1.txt");
+        let duplicate = "SELECT duplicate_of FROM pages WHERE url = ?1";
+        assert_eq!(query(&conn, duplicate, &copy), Some(first));
+
+        // nothing left to crawl, and pages are not refetched
+        let fetched = server.requests().len();
+        spider.run(conn, &[root], Arc::default()).unwrap();
+        assert_eq!(server.requests().len(), fetched);
+        std::fs::remove_file(db).unwrap();
+    }
+
+    #[test]
+    fn storing_attributes() {
+        let conn = init_db(":memory:").unwrap();
+        let menu = GopherURL::try_from("gopher://example.org/1/").unwrap();
+        let reply = "+INFO: 0About\t0/about/\tExample.org.\t70\t+\r\n\
+            +ADMIN:\r\n Admin: Joe <joe@example.org>\r\n.\r\n";
+        let site = Site {
+            text: Some(String::from("About")),
+            attributes: ItemAttributes::parse_all(reply.as_bytes()),
+            ..Site::new(&menu)
+        };
+        let listed = site.attributes[0].url().unwrap().clone();
+        assert_ne!(listed, listed.canonical());
+        store_site(&conn, &Schedule::default(), None, &site).unwrap();
+        let site = Site {
+            text: Some(String::from("all about gopher")),
+            ..Site::new(&listed)
+        };
+        store_site(&conn, &Schedule::default(), None, &site).unwrap();
+
+        let results = crate::index::search(&conn, "gopher", 10).unwrap();
+        assert_eq!(results[0].url, listed.canonical().to_string());
+        assert_eq!(results[0].admin.as_deref(), Some("Joe <joe@example.org>"));
+    }
+
+    #[test]
+    fn scoping() {
+        let server = MockGopherServer::new().unwrap();
+        let mut root = match server.menu(&[
+            (GopherItem::Submenu, "A", "/a/"),
+            (GopherItem::BinaryFile, "Archive", "/all.zip"),
+        ]) {
+            Response::Raw(data) => data,
+            _ => unreachable!(),
+        };
+        root.truncate(root.len() - 3);
+        root.extend(format!("1Other\t/\tlocalhost\t{}\r\n.\r\n", server.port()).bytes());
+        server.route("", Response::Raw(root));
+        server.route("/a/", server.menu(&[(GopherItem::Submenu, "B", "/a/b")]));
+        server.route(
+            "/a/b",
+            server.menu(&[(GopherItem::TextFile, "C", "/a/b/c.txt")]),
+        );
+        server.route("/a/b/c.txt", Response::text("too deep"));
+
+        let crawl = |scope| {
+            let spider = Spider {
+                client: ClientOptions {
+                    host_delay: Duration::ZERO,
+                    ..ClientOptions::default()
+                },
+                ignore_robots: true,
+                scope,
+                ..Spider::default()
+            };
+            let seed = server.url(GopherItem::Submenu, "");
+            let summary = spider.run(init_db(":memory:").unwrap(), &[seed], Arc::default());
+            summary.unwrap().visited
+        };
+        let scope =
+            Scope::parse("Deny-host: localhost\nExclude-selector: \\.zip$\nMax-depth: 2").unwrap();
+        assert_eq!(crawl(scope), 3);
+        let mut requests = server.requests();
+        requests.sort();
+        assert_eq!(requests, vec!["", "/a/", "/a/b"]);
+        let scope = Scope {
+            deny_hosts: vec![String::from("localhost")],
+            max_pages_per_host: Some(2),
+            ..Scope::default()
+        };
+        assert_eq!(crawl(scope), 2);
+        // matched against selector as listed, with its trailing slash
+        let scope = Scope::parse("Deny-host: localhost\nInclude-selector: ^/a/").unwrap();
+        assert_eq!(crawl(scope), 4);
+    }
+
+    #[test]
+    fn stopping() {
+        let server = MockGopherServer::new().unwrap();
+        server.route("", server.menu(&[(GopherItem::TextFile, "Text", "/t")]));
+        let spider = Spider {
+            ignore_robots: true,
+            ..Spider::default()
+        };
+        let conn = init_db(":memory:").unwrap();
+        let root = server.url(GopherItem::Submenu, "");
+        spider
+            .run(conn, &[root], Arc::new(AtomicBool::new(true)))
+            .unwrap();
+        assert!(server.requests().is_empty());
+    }
+}
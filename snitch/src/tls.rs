@@ -0,0 +1,277 @@
+//! TLS transport for `gophers://` URLs and gopher servers speaking TLS.
+//!
+//! Most gopher servers with TLS use self-signed certificates, so
+//! certificates are checked against web PKI roots only in strict mode.
+
+use anyhow::{anyhow, Context, Result};
+use futures_rustls::TlsConnector;
+use rusqlite::{params, Connection};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, StreamOwned};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+
+/// When to use TLS for `gopher://` URLs, `gophers://` ones always use it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TlsMode {
+    /// Plain text, unless host is known to support TLS
+    #[default]
+    Off,
+    /// Try TLS first and fall back to plain text if handshake fails
+    Opportunistic,
+    /// TLS only, with certificates verified
+    Strict,
+}
+
+impl FromStr for TlsMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "opportunistic" => Ok(Self::Opportunistic),
+            "strict" => Ok(Self::Strict),
+            _ => Err(anyhow!(
+                "unknown TLS mode {s}, expected off, opportunistic or strict"
+            )),
+        }
+    }
+}
+
+/// Creates table of hosts with known TLS support
+pub fn init(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tls_hosts(
+            host TEXT NOT NULL, port INTEGER NOT NULL, tls INTEGER NOT NULL,
+            PRIMARY KEY(host, port))",
+        (),
+    )?;
+    Ok(())
+}
+
+/// Records whether TLS handshake with the server succeeded
+pub fn remember(conn: &Connection, host: &str, port: u16, tls: bool) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO tls_hosts (host, port, tls) VALUES (?1, ?2, ?3)",
+        params![host.to_lowercase(), port, tls],
+    )?;
+    Ok(())
+}
+
+/// Hosts with known TLS support, as (host, port, tls)
+pub fn known_hosts(conn: &Connection) -> Result<Vec<(String, u16, bool)>> {
+    let mut stmt = conn.prepare("SELECT host, port, tls FROM tls_hosts")?;
+    let rows = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// Client config, shared by all connections
+pub(crate) fn config(verify: bool) -> Arc<ClientConfig> {
+    static VERIFIED: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    static UNVERIFIED: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    let provider = || Arc::new(rustls::crypto::ring::default_provider());
+    match verify {
+        true => VERIFIED.get_or_init(|| {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            let config = ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .expect("ring supports default protocol versions")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            Arc::new(config)
+        }),
+        false => UNVERIFIED.get_or_init(|| {
+            let config = ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .expect("ring supports default protocol versions")
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider())))
+                .with_no_client_auth();
+            Arc::new(config)
+        }),
+    }
+    .clone()
+}
+
+pub(crate) fn server_name(host: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(String::from(host)).context(format!("invalid server name {host}"))
+}
+
+pub(crate) fn connector(verify: bool) -> TlsConnector {
+    TlsConnector::from(config(verify))
+}
+
+/// Wraps connected socket into TLS stream, doing handshake right away,
+/// so that servers without TLS are detected before request is sent
+pub(crate) fn connect(mut sock: TcpStream, host: &str, verify: bool) -> Result<TlsStream> {
+    let mut conn = ClientConnection::new(config(verify), server_name(host)?)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock).context("TLS handshake")?;
+    }
+    Ok(TlsStream(StreamOwned::new(conn, sock)))
+}
+
+/// Blocking TLS stream. Many servers close connection without TLS
+/// close_notify, which ends the reply just as it does in plain gopher.
+pub(crate) struct TlsStream(StreamOwned<ClientConnection, TcpStream>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            x => x,
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Accepts any certificate, but still checks that handshake is signed by it
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientOptions};
+    use crate::gopher::{fetch_url, fetch_url_with, GopherItem, GopherURL};
+    use crate::index::init_db;
+    use crate::testing::{MockGopherServer, Response};
+    use std::time::Duration;
+
+    #[test]
+    fn connecting() {
+        let secure = MockGopherServer::with_tls().unwrap();
+        secure.route("/hello.txt", Response::text("hello over TLS"));
+        let plain = MockGopherServer::new().unwrap();
+        plain.route("/hello.txt", Response::text("hello in plain text"));
+
+        let read = |url, mode| -> Result<String> {
+            let mut s = String::new();
+            fetch_url_with(url, None, mode)?.read_to_string(&mut s)?;
+            Ok(s)
+        };
+        let url = secure.url(GopherItem::TextFile, "/hello.txt");
+        assert!(url.tls);
+        assert_eq!(read(&url, TlsMode::Off).unwrap(), "hello over TLS");
+        // self-signed certificate
+        assert!(read(&url, TlsMode::Strict).is_err());
+        let url = plain.url(GopherItem::TextFile, "/hello.txt");
+        assert_eq!(
+            read(&url, TlsMode::Opportunistic).unwrap(),
+            "hello in plain text"
+        );
+        assert!(read(&url, TlsMode::Strict).is_err());
+        assert!(fetch_url(&url, None).is_ok());
+
+        let client = |tls| {
+            Client::new(ClientOptions {
+                host_delay: Duration::ZERO,
+                connect_timeout: Duration::from_secs(1),
+                read_timeout: Duration::from_secs(1),
+                tls,
+                ..ClientOptions::default()
+            })
+        };
+        let opportunistic = client(TlsMode::Opportunistic);
+        // gopher:// URL, TLS is negotiated anyway
+        let mut url = secure.url(GopherItem::TextFile, "/hello.txt");
+        url.tls = false;
+        let reply = smol::block_on(opportunistic.fetch(&url, None)).unwrap();
+        assert_eq!(reply, b"hello over TLS");
+        assert_eq!(
+            opportunistic.tls_support(secure.host(), secure.port()),
+            Some(true)
+        );
+        let reply = smol::block_on(
+            opportunistic.fetch(&plain.url(GopherItem::TextFile, "/hello.txt"), None),
+        );
+        assert_eq!(reply.unwrap(), b"hello in plain text");
+        assert_eq!(
+            opportunistic.tls_support(plain.host(), plain.port()),
+            Some(false)
+        );
+
+        // silent server may just be slow, its TLS support stays unknown
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = silent.local_addr().unwrap().port();
+        let url =
+            GopherURL::try_from(format!("gopher://127.0.0.1:{port}/0/hello.txt").as_str()).unwrap();
+        assert!(smol::block_on(opportunistic.fetch(&url, None)).is_err());
+        assert_eq!(opportunistic.tls_support("127.0.0.1", port), None);
+
+        // servers known to speak TLS get it even when it is off
+        let mut url = secure.url(GopherItem::TextFile, "/hello.txt");
+        url.tls = false;
+        let off = client(TlsMode::Off);
+        off.set_tls_support(secure.host(), secure.port(), true);
+        let reply = smol::block_on(off.fetch(&url, None)).unwrap();
+        assert_eq!(reply, b"hello over TLS");
+
+        let conn = init_db(":memory:").unwrap();
+        remember(&conn, "Example.org", 70, true).unwrap();
+        remember(&conn, "example.org", 70, false).unwrap();
+        assert_eq!(
+            known_hosts(&conn).unwrap(),
+            vec![(String::from("example.org"), 70, false)]
+        );
+    }
+}